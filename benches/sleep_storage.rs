@@ -0,0 +1,149 @@
+
+// Compares SLEEP storage back-ends. Run with `cargo bench`.
+
+#[macro_use]
+extern crate bencher;
+extern crate geniza;
+extern crate tempdir;
+
+use bencher::Bencher;
+use geniza::*;
+use std::fs::{create_dir, read, File};
+use std::io::Write;
+use std::path::Path;
+use tempdir::TempDir;
+
+const ENTRY_COUNT: u64 = 10000;
+const REGISTER_COUNT: u64 = 200;
+const IMPORT_FILES: u64 = 4;
+const IMPORT_FILE_SIZE: usize = 512 * 1024;
+
+fn make_tree_file(dir: &Path) -> SleepFile {
+    let mut sf = SleepFile::create(&dir.join("bench.tree"), 0x05025702, 40, Some("BLAKE2b".into())).unwrap();
+    for i in 0..ENTRY_COUNT {
+        sf.append(&[(i % 256) as u8; 40]).unwrap();
+    }
+    sf
+}
+
+fn make_register(dir: &Path) {
+    let mut sdr = SleepDirRegister::create(dir, "bench").unwrap();
+    for i in 0..REGISTER_COUNT {
+        sdr.append(&[(i % 256) as u8; 100]).unwrap();
+    }
+}
+
+fn make_import_dir(dir: &Path) {
+    create_dir(dir).unwrap();
+    for i in 0..IMPORT_FILES {
+        let mut f = File::create(dir.join(format!("file{}", i))).unwrap();
+        f.write_all(&vec![(i % 256) as u8; IMPORT_FILE_SIZE]).unwrap();
+    }
+}
+
+fn read_sleep_file(b: &mut Bencher) {
+    let tmp_dir = TempDir::new("geniza-bench").unwrap();
+    let mut sf = make_tree_file(tmp_dir.path());
+    b.iter(|| {
+        for i in 0..ENTRY_COUNT {
+            sf.read(i).unwrap();
+        }
+    });
+}
+
+fn read_mmap_file(b: &mut Bencher) {
+    let tmp_dir = TempDir::new("geniza-bench").unwrap();
+    let mut sf = MmapSleepFile::from_sleep_file(make_tree_file(tmp_dir.path())).unwrap();
+    b.iter(|| {
+        for i in 0..ENTRY_COUNT {
+            sf.read(i).unwrap();
+        }
+    });
+}
+
+fn read_mmap_slice(b: &mut Bencher) {
+    let tmp_dir = TempDir::new("geniza-bench").unwrap();
+    let mut sf = MmapSleepFile::from_sleep_file(make_tree_file(tmp_dir.path())).unwrap();
+    b.iter(|| {
+        let mut sum: u64 = 0;
+        for i in 0..ENTRY_COUNT {
+            sum += sf.read_slice(i).unwrap()[0] as u64;
+        }
+        sum
+    });
+}
+
+fn verify_register(b: &mut Bencher) {
+    let tmp_dir = TempDir::new("geniza-bench").unwrap();
+    make_register(tmp_dir.path());
+    let mut sdr = SleepDirRegister::open(tmp_dir.path(), "bench", false).unwrap();
+    b.iter(|| sdr.verify().unwrap());
+}
+
+fn verify_register_threaded(b: &mut Bencher) {
+    let tmp_dir = TempDir::new("geniza-bench").unwrap();
+    make_register(tmp_dir.path());
+    let mut sdr = SleepDirRegister::open(tmp_dir.path(), "bench", false).unwrap();
+    b.iter(|| assert!(sdr.verify_entries(VerifyMode::Full, 4, None).unwrap().is_ok()));
+}
+
+fn verify_mmap_register(b: &mut Bencher) {
+    let tmp_dir = TempDir::new("geniza-bench").unwrap();
+    make_register(tmp_dir.path());
+    let mut sdr = MmapRegister::open_mmap(tmp_dir.path(), "bench", false).unwrap();
+    b.iter(|| sdr.verify().unwrap());
+}
+
+fn verify_cached_register(b: &mut Bencher) {
+    let tmp_dir = TempDir::new("geniza-bench").unwrap();
+    make_register(tmp_dir.path());
+    let mut sdr = CachedRegister::open_cached(tmp_dir.path(), "bench", false, 1024).unwrap();
+    b.iter(|| sdr.verify().unwrap());
+}
+
+fn import_dir_all(b: &mut Bencher) {
+    let tmp_dir = TempDir::new("geniza-bench").unwrap();
+    let source = tmp_dir.path().join("source");
+    make_import_dir(&source);
+    let mut run: u64 = 0;
+    b.iter(|| {
+        let drive_dir = tmp_dir.path().join(format!("drive{}", run));
+        run += 1;
+        create_dir(&drive_dir).unwrap();
+        let mut dd = DatDrive::create(&drive_dir).unwrap();
+        dd.import_dir_all(&source, "/").unwrap();
+    });
+}
+
+// Baseline for import_dir_all: the same content, appended (and signed) one 64 KByte chunk at a
+// time. Leaves out the few metadata entries.
+fn import_dir_unbatched(b: &mut Bencher) {
+    let tmp_dir = TempDir::new("geniza-bench").unwrap();
+    let source = tmp_dir.path().join("source");
+    make_import_dir(&source);
+    let mut run: u64 = 0;
+    b.iter(|| {
+        let drive_dir = tmp_dir.path().join(format!("drive{}", run));
+        run += 1;
+        create_dir(&drive_dir).unwrap();
+        let mut dd = DatDrive::create(&drive_dir).unwrap();
+        for i in 0..IMPORT_FILES {
+            let data = read(source.join(format!("file{}", i))).unwrap();
+            for chunk in data.chunks(65536) {
+                dd.content.append(chunk).unwrap();
+            }
+        }
+    });
+}
+
+benchmark_group!(benches,
+    read_sleep_file,
+    read_mmap_file,
+    read_mmap_slice,
+    verify_register,
+    verify_register_threaded,
+    verify_mmap_register,
+    verify_cached_register,
+    import_dir_all,
+    import_dir_unbatched);
+benchmark_main!(benches);
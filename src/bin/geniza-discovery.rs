@@ -0,0 +1,44 @@
+// Free Software under GPL-3.0, see LICENSE
+// Copyright 2017 Bryan Newbold
+
+#[macro_use]
+extern crate clap;
+extern crate env_logger;
+#[macro_use]
+extern crate error_chain;
+extern crate geniza;
+
+// TODO: more careful import
+use geniza::*;
+use std::time::Duration;
+use clap::{App, Arg};
+
+fn run() -> Result<()> {
+    env_logger::init().unwrap();
+
+    let matches = App::new("geniza-discovery")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Runs a (dns-discovery compatible) peer discovery server")
+        .arg(Arg::with_name("bind")
+            .short("b")
+            .long("bind")
+            .value_name("HOST:PORT")
+            .help("UDP address to listen on")
+            .default_value("0.0.0.0:53")
+            .takes_value(true))
+        .arg(Arg::with_name("ttl")
+            .long("ttl")
+            .value_name("SECONDS")
+            .help("how long to remember announced peers")
+            .default_value("1200")
+            .takes_value(true))
+        .get_matches();
+
+    let ttl = value_t_or_exit!(matches, "ttl", u64);
+    let mut server = DiscoveryServer::bind(matches.value_of("bind").unwrap())?;
+    server.set_peer_ttl(Duration::from_secs(ttl));
+    println!("Listening on {}", server.local_addr()?);
+    server.run()
+}
+
+quick_main!(run);
@@ -1,6 +1,7 @@
 // Free Software under GPL-3.0, see LICENSE
 // Copyright 2017 Bryan Newbold
 
+#[macro_use]
 extern crate clap;
 extern crate env_logger;
 #[macro_use]
@@ -11,8 +12,11 @@ extern crate geniza;
 extern crate assert_cli;
 
 use geniza::*;
+use std::io;
+use std::thread;
 use std::path::Path;
-use clap::{App, Arg, SubCommand};
+use std::fs::create_dir_all;
+use clap::{App, Arg, ArgMatches, SubCommand};
 
 fn run() -> Result<()> {
     env_logger::init().unwrap();
@@ -26,24 +30,81 @@ fn run() -> Result<()> {
             .help("dat drive directory")
             .default_value(".dat")
             .takes_value(true))
+        .arg(Arg::with_name("address")
+            .short("a")
+            .long("address")
+            .value_name("ADDRESS")
+            .help("dat://key+readkey address, to read a private drive")
+            .takes_value(true))
         .subcommand(
             SubCommand::with_name("init")
-                .about("Creates a blank drive")
+                .about("Creates a blank drive (secret keys go in ~/.dat/secret_keys/)")
+                .arg_from_usage("--local-keys 'keep secret keys inside the drive directory instead'")
+                .arg_from_usage("--seed [HEX] 'derive keys from this 32 byte seed (64 hex characters)'")
+                .arg_from_usage("--passphrase 'derive keys from a passphrase (read from stdin)'")
+                .arg_from_usage("--encrypt 'encrypt file content on disk (key goes in ~/.dat/secret_keys/)'")
+                .arg(Arg::from_usage("--private 'only readable with the full address (includes a read key)'")
+                    .conflicts_with_all(&["local-keys", "seed", "passphrase"]))
+        )
+        .subcommand(
+            SubCommand::with_name("address")
+                .about("Prints the dat:// address of this drive (with the read key, if private)")
+        )
+        .subcommand(
+            SubCommand::with_name("encrypt")
+                .about("Encrypts file content on disk (key goes in ~/.dat/secret_keys/); the old plain data is unlinked, not wiped")
+        )
+        .subcommand(
+            SubCommand::with_name("restore-keys")
+                .about("Regenerates secret keys (into ~/.dat/secret_keys/) for a drive created with a seed")
+                .arg_from_usage("--seed [HEX] 'the 32 byte seed (64 hex characters)'")
+                .arg_from_usage("--passphrase 'the passphrase (read from stdin)'")
+        )
+        .subcommand(
+            SubCommand::with_name("fork")
+                .about("Copies this drive to a new one with fresh keys (eg, if the secret key leaked)")
+                .arg_from_usage("<TARGET> 'directory for the new drive'")
+                .arg_from_usage("--history 'replay the full history of changes, not just current files'")
+        )
+        .subcommand(
+            SubCommand::with_name("move-keys")
+                .about("Moves secret keys out of the drive directory, into ~/.dat/secret_keys/")
+        )
+        .subcommand(
+            SubCommand::with_name("create-writer")
+                .about("Creates our own writer drive for this archive (secret keys go in ~/.dat/secret_keys/)")
+        )
+        .subcommand(
+            SubCommand::with_name("authorize")
+                .about("Lets another writer change this archive (needs the primary drive's secret key)")
+                .arg_from_usage("<KEY> 'dat address of the writer drive'")
+        )
+        .subcommand(
+            SubCommand::with_name("revoke")
+                .about("Drops a writer from this archive (needs the primary drive's secret key)")
+                .arg_from_usage("<KEY> 'dat address of the writer drive'")
+        )
+        .subcommand(
+            SubCommand::with_name("writers")
+                .about("Lists the authorized writers of this archive")
         )
         .subcommand(
             SubCommand::with_name("ls")
                 .about("Lists current files in this dat")
+                .arg_from_usage("--all-writers 'use the combined tree of all authorized writers'")
         )
         .subcommand(
             SubCommand::with_name("cat")
                 .about("Prints a file (as a string) to stdout")
                 .arg_from_usage("<FILE> 'file to add'")
+                .arg_from_usage("--all-writers 'use the combined tree of all authorized writers'")
         )
         .subcommand(
             SubCommand::with_name("import-file")
                 .about("Adds an indivudal file to the dat")
                 .arg_from_usage("<FILE> 'file to add'")
                 .arg_from_usage("--target <path> 'path to import the file to (if not top level)'")
+                .arg_from_usage("--all-writers 'use the combined tree of all authorized writers'")
         )
         .subcommand(
             SubCommand::with_name("export-file")
@@ -70,6 +131,13 @@ fn run() -> Result<()> {
         .subcommand(
             SubCommand::with_name("verify")
                 .about("Checks signatures et al")
+                .arg_from_usage("--signatures-only 'only check signatures (no data hashing)'")
+                .arg_from_usage("--incremental 'only check entries added since the last --incremental verify'")
+                .arg_from_usage("--last [N] 'only check the last N entries of each register'")
+                .arg_from_usage("--start [START] 'first entry (of each register) to check'")
+                .arg_from_usage("--end [END] 'entry (of each register) to check up to (exclusive)'")
+                .arg_from_usage("--threads [N] 'worker threads for hashing (default: CPU count)'")
+                .arg_from_usage("--progress 'print progress to stderr'")
         )
         .subcommand(
             SubCommand::with_name("dump-entries")
@@ -85,6 +153,7 @@ fn run() -> Result<()> {
             SubCommand::with_name("remove")
                 .about("Deletes a file path from dat archive")
                 .arg_from_usage("<FILE> 'file to delete'")
+                .arg_from_usage("--all-writers 'use the combined tree of all authorized writers'")
         )
         .subcommand(
             SubCommand::with_name("remove-dir-all")
@@ -94,40 +163,146 @@ fn run() -> Result<()> {
         .get_matches();
 
     let dir = Path::new(matches.value_of("dat-dir").unwrap());
+    let address = matches.value_of("address");
     match matches.subcommand() {
-        ("init", Some(_subm)) => {
-            let _drive = DatDrive::create(dir)?;
-            // TODO: print public key in hex
+        ("init", Some(subm)) => {
+            let mut drive = match (seed_from_args(subm)?, subm.is_present("local-keys")) {
+                (None, false) if subm.is_present("private") => {
+                    let mut keystore = DirKeyStore::open_default()?;
+                    DatDrive::create_private(dir, &mut keystore)?
+                },
+                (None, true) => DatDrive::create(dir)?,
+                (Some(seed), true) => DatDrive::create_from_seed(dir, &seed)?,
+                (None, false) => {
+                    let mut keystore = DirKeyStore::open_default()?;
+                    DatDrive::create_with_keystore(dir, &mut keystore)?
+                },
+                (Some(seed), false) => {
+                    let mut keystore = DirKeyStore::open_default()?;
+                    DatDrive::create_from_seed_with_keystore(dir, &seed, &mut keystore)?
+                },
+            };
+            if subm.is_present("encrypt") {
+                drive.content.encrypt_data(&mut DirKeyStore::open_default()?)?;
+            }
+            println!("Address: {}", drive.address());
+            println!("Done!");
+        }
+        ("address", Some(_subm)) => {
+            let drive = open_drive(dir, false, address)?;
+            println!("{}", drive.address());
+            if drive.is_private() && drive.read_key().is_none() {
+                println!("(private drive, but we don't have the read key)");
+            }
+        }
+        ("encrypt", Some(_subm)) => {
+            let mut drive = open_drive(dir, false, address)?;
+            drive.content.encrypt_data(&mut DirKeyStore::open_default()?)?;
+            println!("Done!");
+        }
+        ("restore-keys", Some(subm)) => {
+            let seed = match seed_from_args(subm)? {
+                Some(seed) => seed,
+                None => bail!("Need either --seed or --passphrase"),
+            };
+            let mut keystore = DirKeyStore::open_default()?;
+            DatDrive::restore_secret_keys(dir, &seed, &mut keystore)?;
+            println!("Restored secret keys to {}", keystore.path().display());
+        }
+        ("fork", Some(subm)) => {
+            let target = Path::new(subm.value_of("TARGET").unwrap());
+            let mode = if subm.is_present("history") { ForkMode::History } else { ForkMode::Snapshot };
+            // The old drive only gets a pointer to the new one if we can still write to it
+            let mut drive = match open_drive(dir, true, address) {
+                Ok(drive) => drive,
+                Err(_) => open_drive(dir, false, address)?,
+            };
+            let mut keystore = DirKeyStore::open_default()?;
+            create_dir_all(target)?;
+            let forked = drive.fork(target, mode, &mut keystore)?;
+            println!("Forked to: {}", forked.address());
+            if !drive.metadata.writable() {
+                println!("(old drive isn't writable, so has no pointer to the new one)");
+            }
+        }
+        ("move-keys", Some(_subm)) => {
+            let mut keystore = DirKeyStore::open_default()?;
+            let drive = open_drive(dir, true, address)?;
+            drive.move_secret_keys(&mut keystore)?;
+            println!("Moved secret keys to {}", keystore.path().display());
+        }
+        ("create-writer", Some(_subm)) => {
+            let mut keystore = DirKeyStore::open_default()?;
+            let key = MultiDrive::create_writer(dir, &mut keystore)?;
+            println!("Writer drive: {}", format_dat_address(&key, None));
+            println!("(needs to be authorized by the archive's owner before it's used)");
+        }
+        ("authorize", Some(subm)) => {
+            let key = parse_dat_address(subm.value_of("KEY").unwrap())?;
+            MultiDrive::open(dir, true)?.authorize_writer(&key)?;
             println!("Done!");
         }
+        ("revoke", Some(subm)) => {
+            let key = parse_dat_address(subm.value_of("KEY").unwrap())?;
+            MultiDrive::open(dir, true)?.revoke_writer(&key)?;
+            println!("Done!");
+        }
+        ("writers", Some(_subm)) => {
+            let mut md = MultiDrive::open(dir, false)?;
+            for key in md.authorized_writers()? {
+                let status = if key == md.primary.metadata.pub_key() {
+                    "primary"
+                } else if md.writers.contains_key(&key) {
+                    "writer"
+                } else {
+                    "writer (drive missing)"
+                };
+                println!("{}\t{}", format_dat_address(&key, None), status);
+            }
+        }
+        ("ls", Some(subm)) if subm.is_present("all-writers") => {
+            for entry in MultiDrive::open(dir, false)?.files()? {
+                println!("{}", entry.path.display());
+            }
+        }
         ("ls", Some(_subm)) => {
-            let mut drive = DatDrive::open(dir, false)?;
+            let mut drive = open_drive(dir, false, address)?;
+            if drive.is_private() && drive.read_key().is_none() {
+                bail!("Private drive; need the read key (eg, --address dat://key+readkey)");
+            }
             for entry in drive.read_dir_recursive("/") {
                 let entry = entry?;
                 println!("{}", entry.path.display());
             }
         }
+        ("cat", Some(subm)) if subm.is_present("all-writers") => {
+            let path = Path::new(subm.value_of("FILE").unwrap());
+            let mut md = MultiDrive::open(dir, false)?;
+            let stdout = io::stdout();
+            io::copy(&mut md.file_reader(path)?, &mut stdout.lock())?;
+        }
         ("cat", Some(subm)) => {
             let path = Path::new(subm.value_of("FILE").unwrap());
-            let mut drive = DatDrive::open(dir, true)?;
-            let data = drive.read_file_bytes(&path)?;
-            // TODO: just write to stdout
-            let s = String::from_utf8(data).unwrap();
-            println!("{}", s);
+            let mut drive = open_drive(dir, false, address)?;
+            let stdout = io::stdout();
+            io::copy(&mut drive.file_reader(&path)?, &mut stdout.lock())?;
         }
         ("import-file", Some(subm)) => {
             let path = Path::new(subm.value_of("FILE").unwrap());
-            let mut drive = DatDrive::open(dir, true)?;
             let fpath = match subm.value_of("target") {
                 None => Path::new("/").join(path.file_name().unwrap()),
                 Some(p) => Path::new("/").join(p)
             };
-            drive.import_file(&path, &fpath)?;
-
+            if subm.is_present("all-writers") {
+                MultiDrive::open(dir, true)?.import_file(path, &fpath)?;
+            } else {
+                let mut drive = open_drive(dir, true, address)?;
+                drive.import_file(path, &fpath)?;
+            }
         }
         ("export-file", Some(subm)) => {
             let path = Path::new(subm.value_of("FILE").unwrap());
-            let mut drive = DatDrive::open(dir, true)?;
+            let mut drive = open_drive(dir, false, address)?;
             let fpath = match subm.value_of("target") {
                 None => Path::new("/").join(path.file_name().unwrap()),
                 Some(p) => Path::new("/").join(p)
@@ -136,7 +311,7 @@ fn run() -> Result<()> {
         }
         ("import-dir", Some(subm)) => {
             let path = Path::new(subm.value_of("DIR").unwrap());
-            let mut drive = DatDrive::open(dir, true)?;
+            let mut drive = open_drive(dir, true, address)?;
             let fpath = match subm.value_of("target") {
                 None => Path::new("/").join(path.file_name().unwrap()),
                 Some(p) => Path::new("/").join(p)
@@ -146,7 +321,7 @@ fn run() -> Result<()> {
         }
         ("export-dir", Some(subm)) => {
             let path = Path::new(subm.value_of("DIR").unwrap());
-            let mut drive = DatDrive::open(dir, true)?;
+            let mut drive = open_drive(dir, false, address)?;
             let fpath = match subm.value_of("target") {
                 None => Path::new("/").join(path.file_name().unwrap()),
                 Some(p) => Path::new("/").join(p)
@@ -154,7 +329,7 @@ fn run() -> Result<()> {
             drive.export_dir(&path, &fpath)?;
         }
         ("log", Some(_subm)) => {
-            let mut drive = DatDrive::open(dir, false)?;
+            let mut drive = open_drive(dir, false, address)?;
             for entry in drive.history(0) {
                 let entry = entry?;
                 if let Some(stat) = entry.stat {
@@ -171,12 +346,50 @@ fn run() -> Result<()> {
                 }
             }
         }
-        ("verify", Some(_subm)) => {
-            let mut drive = DatDrive::open(dir, false)?;
-            println!("{:?}", drive.verify());
+        ("verify", Some(subm)) => {
+            let mut drive = open_drive(dir, false, address)?;
+            let threads = if subm.is_present("threads") {
+                value_t_or_exit!(subm, "threads", usize)
+            } else {
+                thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+            };
+            let show_progress = subm.is_present("progress");
+            let mut all_ok = true;
+            for (name, reg) in [("metadata", &mut drive.metadata), ("content", &mut drive.content)] {
+                let len = reg.len()?;
+                let mode = if subm.is_present("incremental") {
+                    reg.set_save_checkpoints(true);
+                    VerifyMode::SinceCheckpoint
+                } else if subm.is_present("signatures-only") {
+                    VerifyMode::SignaturesOnly
+                } else if subm.is_present("last") {
+                    VerifyMode::LastEntries(value_t_or_exit!(subm, "last", u64))
+                } else if subm.is_present("start") || subm.is_present("end") {
+                    let start = if subm.is_present("start") { value_t_or_exit!(subm, "start", u64) } else { 0 };
+                    let end = if subm.is_present("end") { value_t_or_exit!(subm, "end", u64) } else { len };
+                    // (registers differ in length; clamp to each)
+                    let end = end.min(len);
+                    VerifyMode::Range(start.min(end), end)
+                } else {
+                    VerifyMode::Full
+                };
+                let mut progress = |done, total| eprint!("\r{}: {}/{}", name, done, total);
+                let report = if show_progress {
+                    let report = reg.verify_entries(mode, threads, Some(&mut progress))?;
+                    eprintln!();
+                    report
+                } else {
+                    reg.verify_entries(mode, threads, None)?
+                };
+                println!("{}: {}", name, report);
+                all_ok = all_ok && report.is_ok();
+            }
+            if !all_ok {
+                bail!("Verification failed");
+            }
         }
         ("dump-entries", Some(_subm)) => {
-            let mut drive = DatDrive::open(dir, false)?;
+            let mut drive = open_drive(dir, false, address)?;
             for entry in drive.history(0) {
                 let entry = entry?;
                 println!("{}\tpath: {}",
@@ -193,17 +406,21 @@ fn run() -> Result<()> {
         ("copy", Some(subm)) => {
             let from_path= Path::new(subm.value_of("FROM").unwrap());
             let to_path= Path::new(subm.value_of("FROM").unwrap());
-            let mut drive = DatDrive::open(dir, true)?;
+            let mut drive = open_drive(dir, true, address)?;
             drive.copy_file(&from_path, &to_path)?;
         }
         ("remove", Some(subm)) => {
             let path = Path::new(subm.value_of("FILE").unwrap());
-            let mut drive = DatDrive::open(dir, true)?;
-            drive.remove_file(&path)?;
+            if subm.is_present("all-writers") {
+                MultiDrive::open(dir, true)?.remove_file(path)?;
+            } else {
+                let mut drive = open_drive(dir, true, address)?;
+                drive.remove_file(path)?;
+            }
         }
         ("remove-dir-all", Some(subm)) => {
             let path = Path::new(subm.value_of("FILE").unwrap());
-            let mut drive = DatDrive::open(dir, true)?;
+            let mut drive = open_drive(dir, true, address)?;
             drive.remove_dir_all(&path)?;
         }
         _ => {
@@ -215,6 +432,40 @@ fn run() -> Result<()> {
     Ok(())
 }
 
+/// Opens the drive, using the read key from `--address` (if given) for private drives
+fn open_drive(dir: &Path, writable: bool, address: Option<&str>) -> Result<DatDrive> {
+    let (key, read_key) = match address {
+        None => return DatDrive::open(dir, writable),
+        Some(address) => parse_private_dat_address(address)?,
+    };
+    let drive = match read_key {
+        Some(read_key) => DatDrive::open_with_read_key(dir, writable, &read_key)?,
+        None => DatDrive::open(dir, writable)?,
+    };
+    if drive.metadata.pub_key() != &key[..] {
+        bail!("Address doesn't match the drive in {}", dir.display());
+    }
+    Ok(drive)
+}
+
+/// Key seed from `--seed` (hex) or `--passphrase` (a line from stdin), if either was given
+fn seed_from_args(subm: &ArgMatches) -> Result<Option<Vec<u8>>> {
+    if let Some(hex) = subm.value_of("seed") {
+        // (same format as a dat key)
+        return Ok(Some(parse_dat_address(hex)?));
+    }
+    if subm.is_present("passphrase") {
+        let mut passphrase = String::new();
+        io::stdin().read_line(&mut passphrase)?;
+        let passphrase = passphrase.trim_end_matches(&['\n', '\r'][..]);
+        if passphrase.is_empty() {
+            bail!("Empty passphrase");
+        }
+        return Ok(Some(seed_from_passphrase(passphrase)?));
+    }
+    Ok(None)
+}
+
 quick_main!(run);
 
 #[test]
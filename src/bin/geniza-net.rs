@@ -1,6 +1,7 @@
 // Free Software under GPL-3.0, see LICENSE
 // Copyright 2017 Bryan Newbold
 
+#[macro_use]
 extern crate clap;
 extern crate env_logger;
 #[macro_use]
@@ -11,9 +12,24 @@ extern crate sodiumoxide;
 // TODO: more careful import
 use geniza::*;
 use std::path::Path;
-use clap::{App, SubCommand, Arg};
+use std::net::SocketAddr;
+use clap::{App, SubCommand, Arg, ArgMatches};
 use sodiumoxide::crypto::stream::Key;
 
+// Helper to parse any '--server' arguments
+fn dns_servers(subm: &ArgMatches) -> Result<Option<Vec<SocketAddr>>> {
+    match subm.values_of("server") {
+        None => Ok(None),
+        Some(vals) => {
+            let mut servers = vec![];
+            for v in vals {
+                servers.push(v.parse()?);
+            }
+            Ok(Some(servers))
+        }
+    }
+}
+
 fn run() -> Result<()> {
     env_logger::init().unwrap();
 
@@ -38,7 +54,38 @@ fn run() -> Result<()> {
         .subcommand(
             SubCommand::with_name("discover-dns")
                 .about("Does a centralized DNS lookup for peers with the given key")
-                .arg_from_usage("<dat_key> 'dat key (public key) to lookup"),
+                .arg_from_usage("<dat_key> 'dat key (public key) to lookup")
+                .arg_from_usage("--server [host_port]... 'discovery server to use instead of public ones'"),
+        )
+        .subcommand(
+            SubCommand::with_name("announce-dns")
+                .about("Announces to centralized DNS discovery that we have the given key")
+                .arg_from_usage("<dat_key> 'dat key (public key) to announce")
+                .arg_from_usage("<port> 'TCP port we accept connections on'")
+                .arg_from_usage("--server [host_port]... 'discovery server to use instead of public ones'"),
+        )
+        .subcommand(
+            SubCommand::with_name("relay")
+                .about("Runs a relay, forwarding connections between peers that can't connect directly")
+                .arg(Arg::with_name("bind")
+                    .short("b")
+                    .long("bind")
+                    .value_name("HOST:PORT")
+                    .help("TCP address to listen on")
+                    .default_value("0.0.0.0:3283")
+                    .takes_value(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("relay-connect")
+                .about("Connects to a peer through a relay and exchanges handshake")
+                .arg_from_usage("<relay> 'relay host:port'")
+                .arg_from_usage("<dat_key> 'dat key (public key) of the archive to find a peer for'"),
+        )
+        .subcommand(
+            SubCommand::with_name("relay-listen")
+                .about("Waits at a relay for peers, and exchanges handshake with each one paired with us")
+                .arg_from_usage("<relay> 'relay host:port'")
+                .arg_from_usage("<dat_key> 'dat key (public key) to register with'"),
         )
         .subcommand(
             SubCommand::with_name("naive-clone")
@@ -86,7 +133,10 @@ fn run() -> Result<()> {
         ("discover-dns", Some(subm)) => {
             let dat_key = subm.value_of("dat_key").unwrap();
             let key_bytes = parse_dat_address(&dat_key)?;
-            let peers = discover_peers_dns(&key_bytes)?;
+            let peers = match dns_servers(subm)? {
+                Some(servers) => discover_peers_dns_servers(&key_bytes, &servers)?,
+                None => discover_peers_dns(&key_bytes)?,
+            };
             if peers.len() == 0 {
                 println!("No peers found!");
             } else {
@@ -95,6 +145,45 @@ fn run() -> Result<()> {
                 }
             }
         }
+        ("announce-dns", Some(subm)) => {
+            let dat_key = subm.value_of("dat_key").unwrap();
+            let key_bytes = parse_dat_address(dat_key)?;
+            let port = value_t_or_exit!(subm, "port", u16);
+            let servers = match dns_servers(subm)? {
+                Some(servers) => servers,
+                None => default_dns_servers()?,
+            };
+            announce_dns(&key_bytes, port, &servers)?;
+            println!("Done!");
+        }
+        ("relay", Some(subm)) => {
+            let mut server = RelayServer::bind(subm.value_of("bind").unwrap())?;
+            println!("Listening on {}", server.local_addr()?);
+            server.run()?;
+        }
+        ("relay-connect", Some(subm)) => {
+            let relay = subm.value_of("relay").unwrap().parse()?;
+            let dat_key = subm.value_of("dat_key").unwrap();
+            let key_bytes = parse_dat_address(dat_key)?;
+            let key = Key::from_slice(&key_bytes).unwrap();
+            connect_via_relay(relay, &key, false, None)?;
+            println!("Done!");
+        }
+        ("relay-listen", Some(subm)) => {
+            let relay = subm.value_of("relay").unwrap().parse()?;
+            let dat_key = subm.value_of("dat_key").unwrap();
+            let key_bytes = parse_dat_address(dat_key)?;
+            let key = Key::from_slice(&key_bytes).unwrap();
+            let discovery_key = make_discovery_key(&key_bytes);
+            loop {
+                let tcp = relay_listen(relay, &discovery_key)?;
+                let peer = tcp.peer_addr()?;
+                match DatConnection::from_tcp(tcp, &key, false, None) {
+                    Ok(_) => println!("Handshake with a peer (via {}): OK", peer),
+                    Err(e) => println!("Handshake with a peer (via {}) failed: {}", peer, e),
+                }
+            }
+        }
         ("naive-clone", Some(subm)) => {
             let host_port = subm.value_of("host_port").unwrap();
             let dat_key = subm.value_of("dat_key").unwrap();
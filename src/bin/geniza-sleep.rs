@@ -10,6 +10,8 @@ extern crate geniza;
 
 // TODO: more careful import
 use geniza::*;
+use std::fs::File;
+use std::io::{Read, Write};
 use std::path::Path;
 use clap::{App, SubCommand};
 
@@ -28,7 +30,8 @@ fn run() -> Result<()> {
             SubCommand::with_name("create")
                 .about("Creates an SLEEP directory register (with header)")
                 .arg_from_usage("<DIR> 'directory containing files'")
-                .arg_from_usage("<prefix> 'prefix for each data file'"),
+                .arg_from_usage("<prefix> 'prefix for each data file'")
+                .arg_from_usage("--hash [ALGO] 'tree hash algorithm (BLAKE2b or SHA256; only BLAKE2b registers can be synced)'"),
         )
         .subcommand(
             SubCommand::with_name("chunk")
@@ -43,6 +46,34 @@ fn run() -> Result<()> {
                 .arg_from_usage("<DIR> 'directory containing files'")
                 .arg_from_usage("<prefix> 'prefix for each data file'")
         )
+        .subcommand(
+            SubCommand::with_name("prove")
+                .about("Writes a self-contained proof (with data) for a single entry to a file")
+                .arg_from_usage("<DIR> 'directory containing files'")
+                .arg_from_usage("<prefix> 'prefix for each data file'")
+                .arg_from_usage("<index> 'index of the entry to prove'")
+                .arg_from_usage("<FILE> 'proof file to write'")
+                .arg_from_usage("--no-data 'leave the entry data out of the proof'"),
+        )
+        .subcommand(
+            SubCommand::with_name("verify-proof")
+                .about("Checks a proof file (written by 'prove'); doesn't need the register")
+                .arg_from_usage("<FILE> 'proof file to read'"),
+        )
+        .subcommand(
+            SubCommand::with_name("recover")
+                .about("Repairs a register left inconsistent by a crash (rolls back partial appends; doesn't need the secret key)")
+                .arg_from_usage("<DIR> 'directory containing files'")
+                .arg_from_usage("<prefix> 'prefix for each data file'"),
+        )
+        .subcommand(
+            SubCommand::with_name("clear")
+                .about("Drops local data for a range of entries (keeping tree and signatures)")
+                .arg_from_usage("<DIR> 'directory containing files'")
+                .arg_from_usage("<prefix> 'prefix for each data file'")
+                .arg_from_usage("<start> 'first entry index to clear'")
+                .arg_from_usage("<end> 'entry index to clear up to (exclusive)'"),
+        )
         .subcommand(
             SubCommand::with_name("file-info")
                 .about("Reads a single SLEEP file and shows some basic metadata")
@@ -83,11 +114,13 @@ fn run() -> Result<()> {
             let mut sdr = SleepDirRegister::open(dir, prefix, false)?;
             println!("Entry count: {}", sdr.len()?);
             println!("Total size (bytes): {}", sdr.len_bytes()?);
+            println!("Tree hash: {}", sdr.hash_algorithm().name());
         }
         ("create", Some(subm)) => {
             let dir = Path::new(subm.value_of("DIR").unwrap());
             let prefix = subm.value_of("prefix").unwrap();
-            SleepDirRegister::create(dir, prefix)?;
+            let algorithm = HashAlgorithm::from_name(Some(subm.value_of("hash").unwrap_or("BLAKE2b")))?;
+            SleepDirRegister::create_with_algorithm(dir, prefix, algorithm)?;
             println!("Done!");
         }
         ("chunk", Some(subm)) => {
@@ -103,6 +136,48 @@ fn run() -> Result<()> {
             let mut sdr = SleepDirRegister::open(dir, prefix, false)?;
             println!("{:?}", sdr.verify());
         }
+        ("clear", Some(subm)) => {
+            let dir = Path::new(subm.value_of("DIR").unwrap());
+            let prefix = subm.value_of("prefix").unwrap();
+            let start = value_t_or_exit!(subm, "start", u64);
+            let end = value_t_or_exit!(subm, "end", u64);
+            let mut sdr = SleepDirRegister::open(dir, prefix, true)?;
+            sdr.clear(start, end)?;
+            println!("Done!");
+        }
+        ("prove", Some(subm)) => {
+            let dir = Path::new(subm.value_of("DIR").unwrap());
+            let prefix = subm.value_of("prefix").unwrap();
+            let index = value_t_or_exit!(subm, "index", u64);
+            let path = Path::new(subm.value_of("FILE").unwrap());
+            let mut sdr = SleepDirRegister::open(dir, prefix, false)?;
+            let proof = sdr.prove(index, !subm.is_present("no-data"))?;
+            File::create(path)?.write_all(&proof.to_bytes())?;
+            println!("Done!");
+        }
+        ("verify-proof", Some(subm)) => {
+            let path = Path::new(subm.value_of("FILE").unwrap());
+            let mut raw = vec![];
+            File::open(path)?.read_to_end(&mut raw)?;
+            let proof = RegisterProof::from_bytes(&raw)?;
+            verify_proof(&proof)?;
+            print!("Public key: ");
+            for b in &proof.pub_key {
+                print!("{:02x}", b);
+            }
+            println!();
+            println!("Entry {} of {}: OK", proof.index, proof.length);
+            match proof.data {
+                Some(ref data) => println!("Data (bytes): {}", data.len()),
+                None => println!("Data: not included"),
+            }
+        }
+        ("recover", Some(subm)) => {
+            let dir = Path::new(subm.value_of("DIR").unwrap());
+            let prefix = subm.value_of("prefix").unwrap();
+            let (_, report) = SleepDirRegister::open_recover(dir, prefix)?;
+            println!("{}", report);
+        }
         ("file-info", Some(subm)) => {
             let path = Path::new(subm.value_of("FILE").unwrap());
             let sf = SleepFile::open(path, false)?;
@@ -133,8 +208,8 @@ fn run() -> Result<()> {
         ("file-read-all", Some(subm)) => {
             let path = Path::new(subm.value_of("FILE").unwrap());
             let mut sf = SleepFile::open(path, false)?;
-            for i in 0..sf.len()? {
-                println!("{}: {:?}", i, sf.read(i));
+            for (i, entry) in sf.entries().enumerate() {
+                println!("{}: {:?}", i, entry);
             }
         }
         ("file-chunk", Some(subm)) => {
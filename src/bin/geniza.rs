@@ -13,6 +13,8 @@ use geniza::*;
 use std::path::{Path, PathBuf};
 use clap::{App, SubCommand};
 use std::env::current_dir;
+use std::net::SocketAddr;
+use std::time::Duration;
 use sodiumoxide::crypto::stream::Key;
 
 
@@ -42,7 +44,13 @@ fn run() -> Result<()> {
                 .about("Finds and downloads a dat archive from the network into a given folder")
                 .arg_from_usage("<address> 'dat address (public key) to fetch'")
                 .arg_from_usage("[dir] 'directory to clone into'")
-                .arg_from_usage("--full 'pull and save complete history (not just latest version)'"),
+                .arg_from_usage("--full 'pull and save complete history (not just latest version)'")
+                .arg_from_usage("--peer [host_port]... 'peer to try, in addition to discovered peers'")
+                .arg_from_usage("--peers-file [path] 'file listing peers to try (one host:port per line)'")
+                .arg_from_usage("--relay [host_port] 'relay to fall back to if direct connections fail'")
+                .arg_from_usage("--listen [host_port] 'accept connections (and be a hole punching rendezvous) here'")
+                .arg_from_usage("--holepunch 'reach peers behind NAT by UDP hole punching through connected peers'")
+                .arg_from_usage("--relay-listen [host_port] 'wait at this relay for peers that can't connect directly'"),
         )
         .subcommand(
             SubCommand::with_name("init")
@@ -99,6 +107,27 @@ fn run() -> Result<()> {
             let mut sync = Synchronizer::new_downloader(key,
                                                         SyncMode::RxMax,
                                                         dir)?;
+            if let Some(peers) = subm.values_of("peer") {
+                let peers: Vec<SocketAddr> = peers.map(|p| p.parse()).collect::<::std::result::Result<_, _>>()?;
+                sync.add_discovery(Box::new(StaticDiscovery::new(peers)));
+            }
+            if let Some(path) = subm.value_of("peers-file") {
+                sync.add_discovery(Box::new(PeersFileDiscovery::new(path, Duration::from_secs(30))));
+            }
+            if let Some(relay) = subm.value_of("relay") {
+                sync.set_relay(Some(relay.parse()?));
+            }
+            if let Some(bind) = subm.value_of("listen") {
+                let addr = sync.listen(bind.parse()?)?;
+                sync.enable_rendezvous(addr)?;
+                println!("Listening on {}", addr);
+            }
+            if subm.is_present("holepunch") {
+                sync.enable_holepunch();
+            }
+            if let Some(relay) = subm.value_of("relay-listen") {
+                sync.listen_via_relay(relay.parse()?)?;
+            }
             let peer_count = sync.discover()?;
             println!("Found {} potential peers", peer_count);
             sync.run()?;
@@ -4,6 +4,9 @@ use integer_encoding::VarInt;
 use bit_field::BitArray;
 use network_msgs::Have;
 
+/// Largest bitfield (in bytes, once decoded) accepted from a peer: 16 million entries
+pub const MAX_BITFIELD_BYTES: usize = 1 << 21;
+
 pub struct Bitfield {
     inner: Vec<u64>,
 }
@@ -26,9 +29,17 @@ pub fn decode_bitfield(raw_bf: &[u8]) -> Result<Vec<u8>> {
     }
     let mut bit_array: Vec<u8> = vec![];
     while offset < raw_bf.len() {
+        // decode_var() panics on overlong varints, instead of failing
+        if !raw_bf[offset..].iter().take(10).any(|b| b & 0x80 == 0) {
+            bail!("Bad varint in bitfield");
+        }
         let (header, inc): (u64, usize) = VarInt::decode_var(&raw_bf[offset..]);
         offset += inc;
 
+        let len = if (header & 0x01) == 0x01 { header >> 2 } else { header >> 1 };
+        if len > (MAX_BITFIELD_BYTES - bit_array.len()) as u64 {
+            bail!("Bitfield is too large (more than {} bytes)", MAX_BITFIELD_BYTES);
+        }
         if (header & 0x01) == 0x01 {
             // compressed
             let bit = (header & 0x02) == 0x02;
@@ -41,6 +52,9 @@ pub fn decode_bitfield(raw_bf: &[u8]) -> Result<Vec<u8>> {
         } else {
             // uncompressed
             let byte_count = header >> 1;
+            if byte_count > (raw_bf.len() - offset) as u64 {
+                bail!("Truncated bitfield");
+            }
             let mut data = raw_bf[offset..(offset + byte_count as usize)].to_vec();
             bit_array.append(&mut data);
             offset += byte_count as usize;
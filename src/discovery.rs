@@ -1,35 +1,64 @@
 
 use errors::*;
-use std::net::{IpAddr, SocketAddr};
+use std::io::{BufRead, BufReader};
+use std::fs::File;
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
 use make_discovery_key;
 use data_encoding::HEXLOWER;
 use resolve::{DnsConfig, DnsResolver, resolve_host};
-use resolve::record::Srv;
-
-pub fn discover_peers_dns(dat_key: &[u8]) -> Result<Vec<SocketAddr>> {
+use resolve::{Message, Question, Resource, Class, RecordType, MESSAGE_LIMIT};
+use resolve::record::{Srv, Txt};
+use chan;
 
+/// Returns the DNS name to query for peers of a given dat key (public key, not discovery key):
+/// the first 40 hex characters of the discovery key, under the "dat.local" domain.
+pub fn dns_discovery_name(dat_key: &[u8]) -> String {
     let dk = make_discovery_key(dat_key);
     let dk_hex = HEXLOWER.encode(&dk);
-    let dk_name = format!("{}.dat.local", &dk_hex[0..40]);
-    info!("discovering peers using DNS: {}", dk_name);
+    format!("{}.dat.local", &dk_hex[0..40])
+}
 
+/// Looks up the default public (centralized) discovery servers.
+pub fn default_dns_servers() -> Result<Vec<SocketAddr>> {
     let dns1: Vec<IpAddr> = resolve_host("discovery1.publicbits.org")?.collect();
     let dns2: Vec<IpAddr> = resolve_host("discovery2.publicbits.org")?.collect();
+    Ok(vec![
+        SocketAddr::from((dns1[0], 53)),
+        SocketAddr::from((dns2[0], 53))])
+}
 
+fn dns_config(servers: &[SocketAddr]) -> Result<DnsConfig> {
+    if servers.is_empty() {
+        bail!("Need at least one DNS discovery server");
+    }
     let default_config = DnsConfig::load_default()?;
-    let config = DnsConfig {
-        name_servers: vec![
-            SocketAddr::from((dns1[0], 53)),
-            SocketAddr::from((dns2[0], 53))],
+    Ok(DnsConfig {
+        name_servers: servers.to_vec(),
         search: vec!["dat.local".to_string()],
         n_dots: default_config.n_dots,
         timeout: default_config.timeout,
         attempts: default_config.attempts,
         rotate: true,
         use_inet6: false,
-    };
+    })
+}
+
+pub fn discover_peers_dns(dat_key: &[u8]) -> Result<Vec<SocketAddr>> {
+    discover_peers_dns_servers(dat_key, &default_dns_servers()?)
+}
+
+/// Like `discover_peers_dns()`, but queries the given discovery servers (eg, a self-hosted
+/// `geniza-discovery` instance) instead of the public ones.
+pub fn discover_peers_dns_servers(dat_key: &[u8], servers: &[SocketAddr]) -> Result<Vec<SocketAddr>> {
 
-    let resolver = DnsResolver::new(config)?;
+    let dk_name = dns_discovery_name(dat_key);
+    info!("discovering peers using DNS: {}", dk_name);
+
+    let resolver = DnsResolver::new(dns_config(servers)?)?;
 
     let peers: Vec<Srv> = resolver.resolve_record(&dk_name)?;
     // target (IP addresses) are returned with a trailing period that must be stripped
@@ -37,3 +66,297 @@ pub fn discover_peers_dns(dat_key: &[u8]) -> Result<Vec<SocketAddr>> {
     info!("found peers: {:?}", peers);
     Ok(peers)
 }
+
+/// Sends a dns-discovery query, returning the response.
+fn dns_discovery_query(resolver: &DnsResolver, name: &str, additional: &[String]) -> Result<Vec<String>> {
+    let mut msg = Message::new();
+    msg.question.push(Question::new(name.to_string(), RecordType::Txt, Class::Internet));
+    for txt in additional {
+        let mut rr = Resource::new(name.to_string(), RecordType::Txt, Class::Internet, 0);
+        rr.write_rdata(&Txt { data: txt.as_bytes().to_vec() })
+            .map_err(|e| format!("DNS encode error: {}", e))?;
+        msg.additional.push(rr);
+    }
+    let mut buf = vec![0; MESSAGE_LIMIT];
+    let reply = resolver.send_message(&msg, &mut buf)
+        .map_err(|e| format!("DNS discovery query failed: {}", e))?;
+    let mut txts = vec![];
+    for rr in reply.records() {
+        if rr.r_type == RecordType::Txt {
+            let txt: Txt = rr.read_rdata().map_err(|e| format!("DNS decode error: {}", e))?;
+            txts.push(String::from_utf8_lossy(&txt.data).into_owned());
+        }
+    }
+    Ok(txts)
+}
+
+/// Announces to the given dns-discovery servers that we have the given dat key, and are accepting
+/// connections on `port` (at whatever IP address the server sees us sending from).
+///
+/// This is a two step exchange: first fetch a token (tied to our IP address), then send the
+/// announce along with the token.
+pub fn announce_dns(dat_key: &[u8], port: u16, servers: &[SocketAddr]) -> Result<()> {
+
+    let dk_name = dns_discovery_name(dat_key);
+    info!("announcing port {} using DNS: {}", port, dk_name);
+    let resolver = DnsResolver::new(dns_config(servers)?)?;
+
+    let txts = dns_discovery_query(&resolver, &dk_name, &[])?;
+    let token = match txts.iter().find(|t| t.starts_with("token=")) {
+        Some(t) => t[6..].to_string(),
+        None => bail!("DNS discovery server didn't return a token"),
+    };
+    dns_discovery_query(&resolver, &dk_name, &[
+        format!("token={}", token),
+        format!("announce={}", port)])?;
+    Ok(())
+}
+
+/// Abstract source of candidate peer addresses
+///
+/// Back-ends could be centralized DNS, static lists, files on disk, mDNS, a DHT, etc.
+pub trait Discovery {
+    /// Short human-readable name for this source (used in log messages).
+    fn name(&self) -> &str;
+
+    /// Returns candidate peer addresses for the given dat key (the public key, not the discovery
+    /// key). Addresses may have been returned by previous calls as well.
+    fn discover(&mut self, dat_key: &[u8]) -> Result<Vec<SocketAddr>>;
+
+    /// How long to wait before querying this source again. If None, the source is only queried
+    /// once.
+    fn refresh_interval(&self) -> Option<Duration>;
+}
+
+/// Centralized DNS discovery (by default, the discovery1.publicbits.org servers)
+pub struct DnsDiscovery {
+    refresh: Duration,
+    servers: Option<Vec<SocketAddr>>,
+}
+
+impl DnsDiscovery {
+    pub fn new() -> DnsDiscovery {
+        DnsDiscovery {
+            refresh: Duration::from_secs(60),
+            servers: None,
+        }
+    }
+
+    pub fn with_refresh(refresh: Duration) -> DnsDiscovery {
+        DnsDiscovery { refresh, servers: None }
+    }
+
+    /// Queries the given servers instead of the public ones.
+    pub fn with_servers(servers: Vec<SocketAddr>) -> DnsDiscovery {
+        DnsDiscovery {
+            refresh: Duration::from_secs(60),
+            servers: Some(servers),
+        }
+    }
+}
+
+impl Default for DnsDiscovery {
+    fn default() -> DnsDiscovery {
+        DnsDiscovery::new()
+    }
+}
+
+impl Discovery for DnsDiscovery {
+    fn name(&self) -> &str {
+        "dns"
+    }
+
+    fn discover(&mut self, dat_key: &[u8]) -> Result<Vec<SocketAddr>> {
+        match self.servers {
+            Some(ref servers) => discover_peers_dns_servers(dat_key, servers),
+            None => discover_peers_dns(dat_key),
+        }
+    }
+
+    fn refresh_interval(&self) -> Option<Duration> {
+        Some(self.refresh)
+    }
+}
+
+/// Fixed list of peers (eg, passed on the command line)
+pub struct StaticDiscovery {
+    peers: Vec<SocketAddr>,
+}
+
+impl StaticDiscovery {
+    pub fn new(peers: Vec<SocketAddr>) -> StaticDiscovery {
+        StaticDiscovery { peers }
+    }
+}
+
+impl Discovery for StaticDiscovery {
+    fn name(&self) -> &str {
+        "static"
+    }
+
+    fn discover(&mut self, _dat_key: &[u8]) -> Result<Vec<SocketAddr>> {
+        Ok(self.peers.clone())
+    }
+
+    fn refresh_interval(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Reads peers from a plain text file, one "host:port" per line. Blank lines and lines starting
+/// with '#' are skipped. The file is re-read on every query, so it can be edited while running.
+pub struct PeersFileDiscovery {
+    path: PathBuf,
+    refresh: Duration,
+}
+
+impl PeersFileDiscovery {
+    pub fn new<P: AsRef<Path>>(path: P, refresh: Duration) -> PeersFileDiscovery {
+        PeersFileDiscovery {
+            path: path.as_ref().to_path_buf(),
+            refresh,
+        }
+    }
+}
+
+impl Discovery for PeersFileDiscovery {
+    fn name(&self) -> &str {
+        "peers-file"
+    }
+
+    fn discover(&mut self, _dat_key: &[u8]) -> Result<Vec<SocketAddr>> {
+        let f = BufReader::new(File::open(&self.path)?);
+        let mut peers = vec![];
+        for line in f.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.to_socket_addrs() {
+                Ok(addrs) => peers.extend(addrs),
+                Err(e) => warn!("skipping bad peer line in {}: {} ({})", self.path.display(), line, e),
+            }
+        }
+        Ok(peers)
+    }
+
+    fn refresh_interval(&self) -> Option<Duration> {
+        Some(self.refresh)
+    }
+}
+
+/// Handle to a background thread which repeatedly queries a set of discovery sources (each on
+/// it's own refresh interval) and passes any newly found peer addresses into a channel. The
+/// thread exits when this handle is dropped, or when no sources are left to refresh.
+pub struct DiscoveryWorker {
+    // Never sent on; dropping it wakes up and stops the worker thread
+    _stop_tx: chan::Sender<()>,
+}
+
+impl DiscoveryWorker {
+
+    /// If `query_now` is false, the first query of each source waits for a full refresh interval
+    /// (useful if the caller already did a synchronous discovery pass).
+    pub fn spawn(dat_key: &[u8], sources: Vec<Box<dyn Discovery + Send>>, peer_tx: chan::Sender<SocketAddr>, query_now: bool) -> DiscoveryWorker {
+
+        let (stop_tx, stop_rx) = chan::sync(0);
+        let dat_key = dat_key.to_vec();
+        thread::spawn(move || {
+            discovery_loop(dat_key, sources, peer_tx, stop_rx, query_now);
+        });
+        DiscoveryWorker { _stop_tx: stop_tx }
+    }
+}
+
+fn discovery_loop(dat_key: Vec<u8>, mut sources: Vec<Box<dyn Discovery + Send>>, peer_tx: chan::Sender<SocketAddr>, stop_rx: chan::Receiver<()>, query_now: bool) {
+
+    let mut seen: HashSet<SocketAddr> = HashSet::new();
+    let start = Instant::now();
+    // When each source should next be queried (None if never again)
+    let mut next_due: Vec<Option<Instant>> = sources.iter().map(|s| {
+        if query_now { Some(start) } else { s.refresh_interval().map(|d| start + d) }
+    }).collect();
+
+    loop {
+        let now = Instant::now();
+        for (i, source) in sources.iter_mut().enumerate() {
+            match next_due[i] {
+                Some(due) if due <= now => {},
+                _ => continue,
+            }
+            match source.discover(&dat_key) {
+                Ok(peers) => {
+                    debug!("discovery source '{}' returned {} peers", source.name(), peers.len());
+                    for p in peers {
+                        if seen.insert(p) {
+                            peer_tx.send(p);
+                        }
+                    }
+                },
+                Err(e) => warn!("discovery source '{}' failed: {}", source.name(), e),
+            }
+            next_due[i] = source.refresh_interval().map(|d| now + d);
+        }
+
+        // Sleep until the next source is due, or we are told to stop
+        let wait = match next_due.iter().filter_map(|d| *d).min() {
+            None => {
+                debug!("no discovery sources left to refresh; worker exiting");
+                return;
+            },
+            Some(due) => {
+                let now = Instant::now();
+                if due > now { due - now } else { Duration::new(0, 0) }
+            },
+        };
+        let timeout = chan::after(wait);
+        chan_select! {
+            timeout.recv() => {},
+            stop_rx.recv() => {
+                return;
+            },
+        }
+    }
+}
+
+#[test]
+fn test_static_discovery() {
+    let peers: Vec<SocketAddr> = vec!["127.0.0.1:3282".parse().unwrap(), "[::1]:1234".parse().unwrap()];
+    let mut sd = StaticDiscovery::new(peers.clone());
+    assert_eq!(sd.discover(&[0; 32]).unwrap(), peers);
+    assert_eq!(sd.refresh_interval(), None);
+}
+
+#[test]
+fn test_peers_file_discovery() {
+    use tempdir::TempDir;
+    use std::io::Write;
+    let tmp_dir = TempDir::new("geniza-test").unwrap();
+    let path = tmp_dir.path().join("peers.txt");
+    let mut f = File::create(&path).unwrap();
+    f.write_all(b"# some peers\n127.0.0.1:3282\n\n  10.0.0.1:9000  \nnot-a-peer\n").unwrap();
+
+    let mut pfd = PeersFileDiscovery::new(&path, Duration::from_secs(10));
+    let peers = pfd.discover(&[0; 32]).unwrap();
+    assert_eq!(peers, vec!["127.0.0.1:3282".parse().unwrap(), "10.0.0.1:9000".parse().unwrap()]);
+
+    let mut pfd = PeersFileDiscovery::new(tmp_dir.path().join("non-existant"), Duration::from_secs(10));
+    assert!(pfd.discover(&[0; 32]).is_err());
+}
+
+#[test]
+fn test_discovery_worker() {
+    let a: SocketAddr = "127.0.0.1:1001".parse().unwrap();
+    let b: SocketAddr = "127.0.0.1:1002".parse().unwrap();
+    let sources: Vec<Box<dyn Discovery + Send>> = vec![
+        Box::new(StaticDiscovery::new(vec![a, b])),
+        Box::new(StaticDiscovery::new(vec![b])),
+    ];
+    let (peer_tx, peer_rx) = chan::async();
+    let _worker = DiscoveryWorker::spawn(&[0; 32], sources, peer_tx, true);
+
+    // Duplicates are filtered, and channel closes once all (one-shot) sources are done
+    let found: Vec<SocketAddr> = peer_rx.iter().collect();
+    assert_eq!(found, vec![a, b]);
+}
@@ -0,0 +1,315 @@
+
+use errors::*;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+use crypto::blake2b::Blake2b;
+use crypto::digest::Digest;
+use data_encoding::HEXLOWER;
+use rand::{OsRng, Rng};
+use resolve::{Message, Question, Resource, Class, RecordType, MESSAGE_LIMIT};
+use resolve::message::Qr;
+use resolve::record::{Srv, Txt};
+
+/// Max number of SRV records (peers) returned in a single reply
+const MAX_REPLY_PEERS: usize = 32;
+
+/// Minimal server side of the dns-discovery protocol (as used by the public discovery1/discovery2
+/// servers and `discover_peers_dns()`).
+///
+/// Queries are for names like `<first 40 hex chars of discovery key>.dat.local`. SRV queries are
+/// answered with the peers that have announced that name; TXT queries are answered with a
+/// `token=` (derived from the querying IP address) and `host=` (the querying IP address).
+/// Announces are regular queries with TXT records in the "additional" section: `token=<token>`
+/// and `announce=<port>` (or `unannounce=<port>`).
+pub struct DiscoveryServer {
+    socket: UdpSocket,
+    domain: String,
+    secret: [u8; 32],
+    peer_ttl: Duration,
+    // Keyed by the discovery name prefix (without domain)
+    peers: HashMap<String, Vec<(SocketAddr, Instant)>>,
+}
+
+impl DiscoveryServer {
+
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<DiscoveryServer> {
+        let socket = UdpSocket::bind(addr)?;
+        let mut secret = [0; 32];
+        let mut rng = OsRng::new()?;
+        rng.fill_bytes(&mut secret);
+        Ok(DiscoveryServer {
+            socket,
+            domain: "dat.local".to_string(),
+            secret,
+            peer_ttl: Duration::from_secs(20 * 60),
+            peers: HashMap::new(),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// How long an announced peer is remembered (if it doesn't re-announce).
+    pub fn set_peer_ttl(&mut self, ttl: Duration) {
+        self.peer_ttl = ttl;
+    }
+
+    /// Serves requests forever (or until a socket error).
+    pub fn run(&mut self) -> Result<()> {
+        info!("discovery server listening on {}", self.local_addr()?);
+        loop {
+            self.serve_one()?;
+        }
+    }
+
+    /// Blocks until a single request is received, and replies to it. Malformed requests are
+    /// logged and dropped.
+    pub fn serve_one(&mut self) -> Result<()> {
+        let mut buf = vec![0; MESSAGE_LIMIT];
+        let (len, from) = self.socket.recv_from(&mut buf)?;
+        let reply = match Message::decode(&buf[0..len]) {
+            Ok(msg) => self.handle_query(&msg, from),
+            Err(e) => {
+                debug!("dropping malformed DNS request from {}: {}", from, e);
+                return Ok(());
+            },
+        };
+        if let Some(reply) = reply {
+            let mut out = vec![0; MESSAGE_LIMIT];
+            match reply.encode(&mut out) {
+                Ok(bytes) => { self.socket.send_to(bytes, from)?; },
+                Err(e) => warn!("failed to encode DNS reply to {}: {}", from, e),
+            }
+        }
+        Ok(())
+    }
+
+    fn token_for(&self, ip: &IpAddr) -> String {
+        let mut token = [0; 16];
+        let mut hash = Blake2b::new_keyed(16, &self.secret);
+        hash.input(ip.to_string().as_bytes());
+        hash.result(&mut token);
+        HEXLOWER.encode(&token)
+    }
+
+    /// Returns the discovery name prefix if the question name is under our domain.
+    fn parse_name(&self, name: &str) -> Option<String> {
+        let name = name.trim_end_matches('.').to_lowercase();
+        let suffix = format!(".{}", self.domain);
+        if name.ends_with(&suffix) && name.len() > suffix.len() {
+            Some(name[0..(name.len() - suffix.len())].to_string())
+        } else {
+            None
+        }
+    }
+
+    /// Current (unexpired) peers for a name
+    fn lookup(&mut self, id: &str) -> Vec<SocketAddr> {
+        let now = Instant::now();
+        let ttl = self.peer_ttl;
+        match self.peers.get_mut(id) {
+            None => vec![],
+            Some(list) => {
+                list.retain(|&(_, seen)| now.duration_since(seen) < ttl);
+                list.iter().map(|&(sa, _)| sa).collect()
+            }
+        }
+    }
+
+    fn announce(&mut self, id: &str, peer: SocketAddr) {
+        info!("announce: {} at {}", id, peer);
+        let list = self.peers.entry(id.to_string()).or_default();
+        list.retain(|&(sa, _)| sa != peer);
+        list.push((peer, Instant::now()));
+    }
+
+    fn unannounce(&mut self, id: &str, peer: SocketAddr) {
+        info!("unannounce: {} at {}", id, peer);
+        if let Some(list) = self.peers.get_mut(id) {
+            list.retain(|&(sa, _)| sa != peer);
+        }
+    }
+
+    /// Processes a single request (including any announces), and builds the reply. Returns None
+    /// if the message shouldn't be replied to at all (eg, it was itself a reply).
+    fn handle_query(&mut self, msg: &Message, from: SocketAddr) -> Option<Message<'static>> {
+        if msg.header.qr != Qr::Query {
+            return None;
+        }
+
+        // Announces ride along with queries as TXT records
+        let mut token = None;
+        let mut announce = None;
+        let mut unannounce = None;
+        for rr in msg.additional.iter() {
+            if rr.r_type != RecordType::Txt {
+                continue;
+            }
+            let txt = match rr.read_rdata::<Txt>() {
+                Ok(t) => String::from_utf8_lossy(&t.data).into_owned(),
+                Err(_) => continue,
+            };
+            if let Some(val) = txt.strip_prefix("token=") {
+                token = Some(val.to_string());
+            } else if let Some(val) = txt.strip_prefix("announce=") {
+                announce = val.parse::<u16>().ok();
+            } else if let Some(val) = txt.strip_prefix("unannounce=") {
+                unannounce = val.parse::<u16>().ok();
+            }
+        }
+        let token_ok = token == Some(self.token_for(&from.ip()));
+
+        let mut reply = Message::with_id(msg.header.id);
+        reply.header.qr = Qr::Response;
+        reply.header.op = msg.header.op;
+        reply.header.authoritative = true;
+        reply.header.recursion_desired = msg.header.recursion_desired;
+
+        for q in msg.question.iter() {
+            reply.question.push(Question::new(q.name.clone(), q.q_type, q.q_class));
+            let id = match self.parse_name(&q.name) {
+                Some(id) => id,
+                None => continue,
+            };
+
+            if announce.is_some() || unannounce.is_some() {
+                if !token_ok {
+                    warn!("ignoring announce from {} with bad or missing token", from);
+                } else {
+                    // port zero means "whatever port you see me sending from"
+                    if let Some(port) = announce {
+                        let port = if port == 0 { from.port() } else { port };
+                        self.announce(&id, SocketAddr::new(from.ip(), port));
+                    }
+                    if let Some(port) = unannounce {
+                        let port = if port == 0 { from.port() } else { port };
+                        self.unannounce(&id, SocketAddr::new(from.ip(), port));
+                    }
+                }
+            }
+
+            match q.q_type {
+                RecordType::Txt => {
+                    for txt in &[format!("token={}", self.token_for(&from.ip())),
+                                 format!("host={}", from.ip())] {
+                        let mut rr = Resource::new(q.name.clone(), RecordType::Txt, Class::Internet, 0);
+                        if rr.write_rdata(&Txt { data: txt.as_bytes().to_vec() }).is_ok() {
+                            reply.answer.push(rr);
+                        }
+                    }
+                },
+                RecordType::Srv | RecordType::Other(255) => {
+                    for peer in self.lookup(&id).into_iter().take(MAX_REPLY_PEERS) {
+                        // SRV targets are names, so only IPv4 addresses survive the trip
+                        if !peer.is_ipv4() {
+                            continue;
+                        }
+                        let srv = Srv {
+                            priority: 0,
+                            weight: 0,
+                            port: peer.port(),
+                            target: peer.ip().to_string(),
+                        };
+                        let mut rr = Resource::new(q.name.clone(), RecordType::Srv, Class::Internet, 0);
+                        if rr.write_rdata(&srv).is_ok() {
+                            reply.answer.push(rr);
+                        }
+                    }
+                },
+                _ => {},
+            }
+        }
+        Some(reply)
+    }
+}
+
+#[test]
+fn test_discovery_server_query() {
+    let mut ds = DiscoveryServer::bind("127.0.0.1:0").unwrap();
+    let from: SocketAddr = "10.1.2.3:5555".parse().unwrap();
+    let name = "c7638882870abd4044d6467b0738f15e3a36f57c.dat.local.";
+
+    // Token request
+    let mut query = Message::new();
+    query.question.push(Question::new(name.to_string(), RecordType::Txt, Class::Internet));
+    let reply = ds.handle_query(&query, from).unwrap();
+    assert_eq!(reply.header.id, query.header.id);
+    let txts: Vec<String> = reply.answer.iter()
+        .map(|rr| String::from_utf8(rr.read_rdata::<Txt>().unwrap().data).unwrap()).collect();
+    assert!(txts.contains(&"host=10.1.2.3".to_string()));
+    let token = txts.iter().find(|t| t.starts_with("token=")).unwrap().clone();
+
+    // Announce with a bad token is ignored
+    let mut announce = query.clone();
+    for txt in &["token=deadbeef", "announce=3282"] {
+        let mut rr = Resource::new(name.to_string(), RecordType::Txt, Class::Internet, 0);
+        rr.write_rdata(&Txt { data: txt.as_bytes().to_vec() }).unwrap();
+        announce.additional.push(rr);
+    }
+    ds.handle_query(&announce, from).unwrap();
+    assert_eq!(ds.lookup("c7638882870abd4044d6467b0738f15e3a36f57c").len(), 0);
+
+    // ... and with a good token is recorded
+    let mut announce = query.clone();
+    for txt in &[&token[..], "announce=3282"] {
+        let mut rr = Resource::new(name.to_string(), RecordType::Txt, Class::Internet, 0);
+        rr.write_rdata(&Txt { data: txt.as_bytes().to_vec() }).unwrap();
+        announce.additional.push(rr);
+    }
+    ds.handle_query(&announce, from).unwrap();
+
+    let mut query = Message::new();
+    query.question.push(Question::new(name.to_string(), RecordType::Srv, Class::Internet));
+    let reply = ds.handle_query(&query, from).unwrap();
+    assert_eq!(reply.answer.len(), 1);
+    let srv: Srv = reply.answer[0].read_rdata().unwrap();
+    assert_eq!(srv.port, 3282);
+    assert_eq!(srv.target, "10.1.2.3.");
+
+    // Other domains get empty answers
+    let mut query = Message::new();
+    query.question.push(Question::new("example.com".to_string(), RecordType::Srv, Class::Internet));
+    assert_eq!(ds.handle_query(&query, from).unwrap().answer.len(), 0);
+
+    // Expired peers are dropped
+    ds.set_peer_ttl(Duration::new(0, 0));
+    assert_eq!(ds.lookup("c7638882870abd4044d6467b0738f15e3a36f57c").len(), 0);
+}
+
+#[test]
+fn test_discovery_server_announce_discover_connect() {
+    use std::thread;
+    use std::net::TcpListener;
+    use sodiumoxide::crypto::stream::Key;
+    use discovery::{announce_dns, discover_peers_dns_servers};
+    use protocol::DatConnection;
+
+    let mut ds = DiscoveryServer::bind("127.0.0.1:0").unwrap();
+    let server_addr = ds.local_addr().unwrap();
+    thread::spawn(move || {
+        ds.run().unwrap();
+    });
+
+    let key_bytes = [7; 32];
+    let key = Key::from_slice(&key_bytes).unwrap();
+
+    // A "seeding" peer, which announces itself
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let peer_port = listener.local_addr().unwrap().port();
+    announce_dns(&key_bytes, peer_port, &[server_addr]).unwrap();
+    let seed_key = key.clone();
+    let seeder = thread::spawn(move || {
+        let (tcp, _) = listener.accept().unwrap();
+        DatConnection::from_tcp(tcp, &seed_key, false, None).unwrap();
+    });
+
+    // A downloading peer discovers and connects to it
+    let peers = discover_peers_dns_servers(&key_bytes, &[server_addr]).unwrap();
+    assert_eq!(peers, vec![SocketAddr::from(([127, 0, 0, 1], peer_port))]);
+    DatConnection::connect(peers[0], &key, false, None).unwrap();
+    seeder.join().unwrap();
+
+    assert_eq!(discover_peers_dns_servers(&[8; 32], &[server_addr]).unwrap().len(), 0);
+}
@@ -1,58 +1,297 @@
 
-use std::io::{Read, Write, BufReader};
+use std::io::{self, Read, BufReader};
 use std::path::{Path, PathBuf};
 use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
-use std::fs::{File, OpenOptions, read_dir, create_dir_all};
+use std::fs::{File, Metadata, OpenOptions, read_dir, create_dir_all};
 use std::cmp::min;
 use std::ffi::OsStr;
+use std::collections::{BTreeMap, HashMap};
+use sodiumoxide::crypto::secretbox;
 use protobuf::Message;
 use protobuf::parse_from_bytes;
 use integer_encoding::VarInt;
 
 use errors::*;
 use sleep_register::*;
+use register_reader::*;
+use keystore::*;
+use helpers::*;
 use metadata_msgs::{Index, Stat, Node};
 
+/// Count of 64 KByte content chunks written (and signed) at a time by `add_file()`
+const ADD_FILE_BATCH: usize = 16;
+
+/// File written by `mark_forked()`, pointing at the new key of a forked drive
+pub const FORK_POINTER_PATH: &str = "/.dat-forked";
+
+/// `Index` type (first metadata entry) of private drives (see `DatDrive::create_private()`);
+/// regular drives are just "hyperdrive"
+pub const PRIVATE_INDEX_TYPE: &str = "hyperdrive-private";
+
+/// How much of a drive `fork_into()` copies
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ForkMode {
+    /// Just the current files
+    Snapshot,
+    /// Every addition and removal, in order
+    History,
+}
+
 /// "Sort of" follows rust std::fs API for file system access.
-pub struct DatDrive {
-    pub metadata: SleepDirRegister,
-    pub content: SleepDirRegister,
+///
+/// Generic over the register back-end; the default is on-disk SLEEP directories, while
+/// `DatDrive<MemoryRegister>` (see `create_memory()`) lives entirely in RAM.
+pub struct DatDrive<H: HyperRegister = SleepDirRegister> {
+    pub metadata: H,
+    pub content: H,
+    // Private drives have every metadata entry (after the Index) and content chunk sealed with
+    // the read key. Without it (`None`) the registers can still be verified and replicated, but
+    // files can't be read. `None` if we don't have the Index entry (eg, sparse clones), so can't
+    // tell; reading files then fails too.
+    private: Option<bool>,
+    read_key: Option<secretbox::Key>,
 }
 
 impl DatDrive {
 
     /// Instantiates a drive in the given directory. Path should be the complete path (eg, ending
     /// in '/.dat/'), not an enclosing directory containing files.
+    ///
+    /// Secret keys are written inside the directory; see `create_with_keystore()`.
     pub fn create<P: AsRef<Path>>(path: P) -> Result<DatDrive> {
-        let mut metadata = SleepDirRegister::create(path.as_ref(), "metadata")?;
+        let metadata = SleepDirRegister::create(path.as_ref(), "metadata")?;
         let content = SleepDirRegister::create(path.as_ref(), "content")?;
+        DatDrive::init(metadata, content, None)
+    }
+
+    /// Like `create()`, but both secret keys go in the given key store, not the drive directory.
+    pub fn create_with_keystore<P: AsRef<Path>>(path: P, keystore: &mut dyn KeyStore) -> Result<DatDrive> {
+        let metadata = SleepDirRegister::create_with_keystore(path.as_ref(), "metadata", keystore)?;
+        let content = SleepDirRegister::create_with_keystore(path.as_ref(), "content", keystore)?;
+        DatDrive::init(metadata, content, None)
+    }
+
+    /// Like `create_with_keystore()`, but makes a private drive: file metadata and content are
+    /// encrypted with a random read key (which also goes in the key store), so only those given
+    /// the full `address()` can read it. Peers with just the public key can still replicate and
+    /// verify the (encrypted) registers.
+    pub fn create_private<P: AsRef<Path>>(path: P, keystore: &mut dyn KeyStore) -> Result<DatDrive> {
+        let metadata = SleepDirRegister::create_with_keystore(path.as_ref(), "metadata", keystore)?;
+        let content = SleepDirRegister::create_with_keystore(path.as_ref(), "content", keystore)?;
+        let read_key = secretbox::gen_key();
+        keystore.put_read_key(metadata.pub_key(), &read_key.0)?;
+        DatDrive::init(metadata, content, Some(read_key))
+    }
+
+    /// Like `create()`, but the metadata key-pair comes from the given 32 byte seed, and the
+    /// content key-pair is derived from the metadata secret key (see `derive_content_seed()`), so
+    /// the seed is all that needs to be backed up (see `restore_secret_keys()`).
+    pub fn create_from_seed<P: AsRef<Path>>(path: P, seed: &[u8]) -> Result<DatDrive> {
+        let content_seed = drive_content_seed(seed)?;
+        let metadata = SleepDirRegister::create_from_seed(path.as_ref(), "metadata", seed)?;
+        let content = SleepDirRegister::create_from_seed(path.as_ref(), "content", &content_seed)?;
+        DatDrive::init(metadata, content, None)
+    }
+
+    /// Combination of `create_from_seed()` and `create_with_keystore()`.
+    pub fn create_from_seed_with_keystore<P: AsRef<Path>>(path: P, seed: &[u8], keystore: &mut dyn KeyStore) -> Result<DatDrive> {
+        let content_seed = drive_content_seed(seed)?;
+        let metadata = SleepDirRegister::create_from_seed_with_keystore(path.as_ref(), "metadata", seed, keystore)?;
+        let content = SleepDirRegister::create_from_seed_with_keystore(path.as_ref(), "content", &content_seed, keystore)?;
+        DatDrive::init(metadata, content, None)
+    }
+
+    /// Regenerates the secret keys of a drive made by `create_from_seed()` (eg, after the drive
+    /// directory was copied to a new machine), and puts them in the key store. Fails if the seed
+    /// doesn't match the drive.
+    pub fn restore_secret_keys<P: AsRef<Path>>(path: P, seed: &[u8], keystore: &mut dyn KeyStore) -> Result<()> {
+        let drive = DatDrive::open(path, false)?;
+        let (metadata_secret, metadata_pub) = keypair_from_seed(seed)?;
+        let (content_secret, content_pub) = keypair_from_seed(&derive_content_seed(&metadata_secret))?;
+        if drive.metadata.discovery_key() != make_discovery_key(&metadata_pub) ||
+                drive.content.discovery_key() != make_discovery_key(&content_pub) {
+            bail!("Seed doesn't match the keys of this drive");
+        }
+        keystore.put_secret_key(&metadata_pub, &metadata_secret)?;
+        keystore.put_secret_key(&content_pub, &content_secret)
+    }
+
+    fn init(mut metadata: SleepDirRegister, content: SleepDirRegister, read_key: Option<secretbox::Key>) -> Result<DatDrive> {
         // Calculate content discovery key and write as Index entry in metadata register
         let dk = metadata.discovery_key();
-        let mut index = Index::new();
-        index.set_field_type("hyperdrive".into());
-        index.set_content(dk);
-        metadata.append(&index.write_to_bytes()?)?;
+        metadata.append(&index_entry(dk, read_key.is_some())?)?;
         Ok(DatDrive {
             metadata,
             content,
+            private: Some(read_key.is_some()),
+            read_key,
         })
     }
 
     /// Path should be the complete path (eg, ending in '/.dat/'), not an enclosing directory
     /// containing files.
+    ///
+    /// The read key of a private drive comes from the default key store, if it's there; without
+    /// it, private drives can only be opened read-only (eg, to replicate them).
     pub fn open<P: AsRef<Path>>(path: P, writable: bool) -> Result<DatDrive> {
-        let metadata = SleepDirRegister::open(path.as_ref(), "metadata", writable)?;
+        let default_ks = DirKeyStore::open_default().ok();
+        DatDrive::open_keyed(path.as_ref(), writable,
+                             default_ks.as_ref().map(|ks| ks as &dyn KeyStore), None)
+    }
+
+    /// Like `open()`, but looks up secret keys (if writable) and any read key in the given key
+    /// store.
+    pub fn open_with_keystore<P: AsRef<Path>>(path: P, writable: bool, keystore: &dyn KeyStore) -> Result<DatDrive> {
+        DatDrive::open_keyed(path.as_ref(), writable, Some(keystore), None)
+    }
+
+    /// Like `open()`, with the read key of a private drive given directly (eg, from an address
+    /// parsed by `parse_private_dat_address()`). The read key is ignored for regular drives.
+    pub fn open_with_read_key<P: AsRef<Path>>(path: P, writable: bool, read_key: &[u8]) -> Result<DatDrive> {
+        let default_ks = DirKeyStore::open_default().ok();
+        DatDrive::open_keyed(path.as_ref(), writable,
+                             default_ks.as_ref().map(|ks| ks as &dyn KeyStore), Some(read_key))
+    }
+
+    fn open_keyed(path: &Path, writable: bool, keystore: Option<&dyn KeyStore>,
+                  read_key: Option<&[u8]>) -> Result<DatDrive> {
+        let mut metadata = match keystore {
+            Some(ks) => SleepDirRegister::open_with_keystore(path, "metadata", writable, ks)?,
+            None => SleepDirRegister::open(path, "metadata", writable)?,
+        };
         if metadata.len()? == 0 {
             bail!("Expected at least one entry (Index) in metadata register");
         }
-        let content = SleepDirRegister::open(path.as_ref(), "content", writable)?;
+        // Without the Index, the drive can still be verified and replicated, just not read or
+        // written
+        let private = match metadata.get_data_entry(0) {
+            Ok(data) => Some(parse_from_bytes::<Index>(&data)?.get_field_type() == PRIVATE_INDEX_TYPE),
+            Err(e) => {
+                if writable {
+                    bail!("Can't write to a drive without its Index entry: {}", e);
+                }
+                warn!("Drive Index entry not available ({}); can't tell if it's private (path={})", e, path.display());
+                None
+            },
+        };
+        let read_key = match (private, read_key, keystore) {
+            (Some(false), _, _) => None,
+            (_, Some(key), _) => Some(key.to_vec()),
+            (_, None, Some(ks)) => ks.get_read_key(metadata.pub_key())?,
+            (_, None, None) => None,
+        };
+        let read_key = match read_key {
+            None => None,
+            Some(key) => match secretbox::Key::from_slice(&key) {
+                Some(key) => Some(key),
+                None => bail!("Read key must be 32 bytes (got {})", key.len()),
+            },
+        };
+        if private == Some(true) && writable && read_key.is_none() {
+            bail!("Can't write to a private drive without its read key");
+        }
+        let content = match keystore {
+            Some(ks) => SleepDirRegister::open_with_keystore(path, "content", writable, ks)?,
+            None => SleepDirRegister::open(path, "content", writable)?,
+        };
+        Ok(DatDrive {
+            metadata,
+            content,
+            private,
+            read_key,
+        })
+    }
+
+    /// 'dat://' address of this drive. For private drives this includes the read key (if we have
+    /// it), so it's all somebody needs to read the drive: share with care.
+    pub fn address(&self) -> String {
+        format_dat_address(self.metadata.pub_key(), self.read_key())
+    }
+
+    /// Moves an archive to new keys (eg, if the old secret keys leaked): creates a new drive at
+    /// `path` (with fresh keys, kept in the key store), copies files into it (see `fork_into()`),
+    /// and, if this drive is still writable, points at the new one with `mark_forked()`.
+    ///
+    /// Forks of private drives are private too, with a new read key.
+    pub fn fork<P: AsRef<Path>>(&mut self, path: P, mode: ForkMode, keystore: &mut dyn KeyStore) -> Result<DatDrive> {
+        let mut forked = if self.is_private() {
+            DatDrive::create_private(path, keystore)?
+        } else {
+            DatDrive::create_with_keystore(path, keystore)?
+        };
+        self.fork_into(&mut forked, mode)?;
+        if self.metadata.writable() {
+            self.mark_forked(forked.metadata.pub_key(), forked.read_key())?;
+        }
+        Ok(forked)
+    }
+
+    /// Moves both secret keys out of the drive directory into the key store (drive must have been
+    /// opened writable).
+    pub fn move_secret_keys(&self, keystore: &mut dyn KeyStore) -> Result<()> {
+        self.metadata.move_secret_key(keystore)?;
+        self.content.move_secret_key(keystore)
+    }
+}
+
+impl DatDrive<MemoryRegister> {
+
+    /// Instantiates a new drive in memory; nothing is persisted.
+    pub fn create_memory() -> Result<DatDrive<MemoryRegister>> {
+        DatDrive::init_memory(MemoryRegister::new()?, MemoryRegister::new()?, None)
+    }
+
+    /// In-memory version of `create_private()`; the read key is only kept in the drive (see
+    /// `read_key()`).
+    pub fn create_memory_private() -> Result<DatDrive<MemoryRegister>> {
+        DatDrive::init_memory(MemoryRegister::new()?, MemoryRegister::new()?, Some(secretbox::gen_key()))
+    }
+
+    /// In-memory version of `create_from_seed()` (eg, for reproducible tests).
+    pub fn create_memory_from_seed(seed: &[u8]) -> Result<DatDrive<MemoryRegister>> {
+        let content_seed = drive_content_seed(seed)?;
+        DatDrive::init_memory(MemoryRegister::from_seed(seed)?, MemoryRegister::from_seed(&content_seed)?, None)
+    }
+
+    fn init_memory(mut metadata: MemoryRegister, content: MemoryRegister, read_key: Option<secretbox::Key>) -> Result<DatDrive<MemoryRegister>> {
+        let dk = metadata.discovery_key();
+        metadata.append(&index_entry(dk, read_key.is_some())?)?;
         Ok(DatDrive {
             metadata,
             content,
+            private: Some(read_key.is_some()),
+            read_key,
         })
     }
 }
 
+/// Encodes the first (`Index`) entry of a drive's metadata register
+fn index_entry(dk: Vec<u8>, private: bool) -> Result<Vec<u8>> {
+    let mut index = Index::new();
+    index.set_field_type(if private { PRIVATE_INDEX_TYPE } else { "hyperdrive" }.into());
+    index.set_content(dk);
+    Ok(index.write_to_bytes()?)
+}
+
+/// `Stat` (mode, owner, and times) for a file in the "real" filesystem, as `import_file()` records
+/// it; content fields are filled in when the file is added.
+pub fn stat_from_metadata(in_metadata: &Metadata) -> Stat {
+    let mut stat = Stat::new();
+    stat.set_mode(in_metadata.mode());
+    stat.set_uid(in_metadata.uid());
+    stat.set_gid(in_metadata.gid());
+    stat.set_size(in_metadata.size());
+    stat.set_mtime(in_metadata.mtime() as u64);
+    stat.set_ctime(in_metadata.ctime() as u64);
+    stat
+}
+
+/// Seed for the content key-pair of a drive whose metadata key-pair comes from `seed`
+fn drive_content_seed(seed: &[u8]) -> Result<Vec<u8>> {
+    let (metadata_secret, _) = keypair_from_seed(seed)?;
+    Ok(derive_content_seed(&metadata_secret))
+}
+
 /// Inflates a binary-encoded child index table. `current` is the entry index number that this
 /// child index is associated with.
 fn decode_children(raw: &[u8], current: u64) -> Result<Vec<Vec<u64>>> {
@@ -174,7 +413,7 @@ fn test_longest_common_prefix() {
         1);
 }
 
-impl<'a> DatDrive {
+impl<H: HyperRegister> DatDrive<H> {
 
     /// Returns number of drive metadata entries (not including the first entry, which is the
     /// content register public key)
@@ -182,6 +421,34 @@ impl<'a> DatDrive {
         Ok(self.metadata.len()? - 1)
     }
 
+    /// Are file metadata and content encrypted? (see `DatDrive::create_private()`) False if we
+    /// can't tell (see `privacy_known()`).
+    pub fn is_private(&self) -> bool {
+        self.private == Some(true)
+    }
+
+    /// Whether we have the Index entry, which says if the drive is private; drives opened without
+    /// it can't be read.
+    pub fn privacy_known(&self) -> bool {
+        self.private.is_some()
+    }
+
+    /// The read key of a private drive, if we have it
+    pub fn read_key(&self) -> Option<&[u8]> {
+        self.read_key.as_ref().map(|key| &key.0[..])
+    }
+
+    /// `Some(key)` for private drives, `None` for regular ones; fails if this drive is private
+    /// but we don't have the key.
+    fn sealing_key(&self) -> Result<Option<&secretbox::Key>> {
+        match (self.private, self.read_key.as_ref()) {
+            (Some(false), _) => Ok(None),
+            (Some(true), Some(key)) => Ok(Some(key)),
+            (Some(true), None) => bail!("Drive is private, and we don't have the read key"),
+            (None, _) => bail!("Don't have the drive's Index entry, so can't tell if it's private"),
+        }
+    }
+
     /// Entry index is counted by drive entries (not including the first register entry, which is
     /// the content register public key)
     fn get_dir_entry(&mut self, entry_index: u64) -> Result<DriveEntry> {
@@ -190,6 +457,10 @@ impl<'a> DatDrive {
         }
         trace!("fetching drive entry {} (of {})", entry_index, self.entry_count()?);
         let data = self.metadata.get_data_entry(entry_index)?;
+        let data = match self.sealing_key()? {
+            Some(key) => open_entry(key, entry_index, &data)?,
+            None => data,
+        };
         let node = parse_from_bytes::<Node>(&data)?;
         let stat = match node.has_value() {
             true => Some(parse_from_bytes::<Stat>(&node.get_value())?),
@@ -287,7 +558,7 @@ impl<'a> DatDrive {
     }
 
     /// 'start' is the drive metadata register entry index. Zero is skipped automatically.
-    pub fn history<'b>(&'b mut self, start: u64) -> DriveHistory<'b> {
+    pub fn history<'b>(&'b mut self, start: u64) -> DriveHistory<'b, H> {
         // skip pubkey entry
         let start = if start == 0 { 1 } else { start };
         DriveHistory {
@@ -296,12 +567,12 @@ impl<'a> DatDrive {
         }
     }
 
-    pub fn read_dir_recursive<'b, P: AsRef<Path>>(&'b mut self, path: P) -> ReadDriveDir<'b> {
+    pub fn read_dir_recursive<'b, P: AsRef<Path>>(&'b mut self, path: P) -> ReadDriveDir<'b, H> {
         // TODO: pass a single error if there is an error?
         ReadDriveDir::init(self, path, true).unwrap()
     }
 
-    pub fn read_dir<'b, P: AsRef<Path>>(&'b mut self, path: P) -> ReadDriveDir<'b> {
+    pub fn read_dir<'b, P: AsRef<Path>>(&'b mut self, path: P) -> ReadDriveDir<'b, H> {
         // TODO: pass a single error if there is an error?
         ReadDriveDir::init(self, path, false).unwrap()
     }
@@ -327,22 +598,45 @@ impl<'a> DatDrive {
         // TODO: check if file already exists
         let mut total_size: u64 = 0;
         let mut data_entries: u64 = 0;
-        let mut buf = [0; 65536];
+        let mut buf = vec![0; 65536 * ADD_FILE_BATCH];
+        let key = self.sealing_key()?.cloned();
         let data_offset = self.content.len()?;
         let data_byte_offset = self.content.len_bytes()?;
 
         loop {
-            // 1. read chunk
-            let rlen = source.read(&mut buf)?;
-            if rlen == 0 {
+            // 1. read a batch of chunks (each up to 64 KByte)
+            let mut filled = 0;
+            while filled < buf.len() {
+                let rlen = source.read(&mut buf[filled..])?;
+                if rlen == 0 {
+                    break;
+                }
+                filled += rlen;
+            }
+            if filled == 0 {
                 break;
             }
-            // 2. append chunk to data register
-            self.content.append(&buf[0..rlen])?;
+            // 2. append chunks to data register (signed once per batch)
+            {
+                let chunks: Vec<&[u8]> = buf[0..filled].chunks(65536).collect();
+                if let Some(ref key) = key {
+                    let first = self.content.len()?;
+                    let sealed: Vec<Vec<u8>> = chunks.iter().enumerate()
+                        .map(|(i, chunk)| seal_entry(key, first + i as u64, chunk))
+                        .collect();
+                    let sealed: Vec<&[u8]> = sealed.iter().map(|chunk| &chunk[..]).collect();
+                    self.content.append_batch(&sealed)?;
+                } else {
+                    self.content.append_batch(&chunks)?;
+                }
+                data_entries += chunks.len() as u64;
+            }
 
             // 3. increment metadata size
-            total_size += rlen as u64;
-            data_entries += 1;
+            total_size += filled as u64;
+            if filled < buf.len() {
+                break;
+            }
         }
 
         // 4. write metadata
@@ -379,7 +673,11 @@ impl<'a> DatDrive {
             node.set_value(val.write_to_bytes()?);
         }
         node.set_paths(children);
-        self.metadata.append(&node.write_to_bytes()?)?;
+        let data = node.write_to_bytes()?;
+        match self.sealing_key()?.cloned() {
+            Some(key) => self.metadata.append(&seal_entry(&key, index, &data))?,
+            None => self.metadata.append(&data)?,
+        };
         return Ok(index);
     }
 
@@ -442,15 +740,8 @@ impl<'a> DatDrive {
     pub fn import_file<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, source: P, dest: Q) -> Result<u64> {
         info!("importing file: '{:?}' as '{:?}'", source.as_ref(), dest.as_ref());
         let in_file = File::open(source)?;
-        let in_metadata = in_file.metadata()?;
+        let mut stat = stat_from_metadata(&in_file.metadata()?);
         let in_file = BufReader::new(in_file);
-        let mut stat = Stat::new();
-        stat.set_mode(in_metadata.mode());
-        stat.set_uid(in_metadata.uid());
-        stat.set_gid(in_metadata.gid());
-        stat.set_size(in_metadata.size());
-        stat.set_mtime(in_metadata.mtime() as u64);
-        stat.set_ctime(in_metadata.ctime() as u64);
         self.add_file(dest, &mut stat, in_file)
     }
 
@@ -474,12 +765,8 @@ impl<'a> DatDrive {
                 .write(true)
                 .mode(stat.get_mode())
                 .open(dest)?;
-            let offset = stat.get_offset();
-            let blocks = stat.get_blocks();
-            for i in offset..(offset+blocks) {
-                let chunk = self.content.get_data_entry(i)?;
-                out_file.write_all(&chunk)?;
-            }
+            let mut reader = self.content_reader(&stat)?;
+            io::copy(&mut reader, &mut out_file)?;
             // TODO: more outfile metadata (uid, guid, etc)
         } else {
             bail!("Couldn't find path: {}", source.display());
@@ -539,22 +826,31 @@ impl<'a> DatDrive {
     }
 
     pub fn read_file_bytes<P: AsRef<Path>>(&mut self, path: P) -> Result<Vec<u8>> {
+        let mut buf = vec![];
+        self.file_reader(path)?.read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Streaming (Read + Seek) access to a file's content, without loading it all into memory.
+    pub fn file_reader<P: AsRef<Path>>(&mut self, path: P) -> Result<RegisterReader<'_, H>> {
         let de = self.get_file_entry(path.as_ref())?;
         if let Some(entry) = de {
             let stat = entry.stat.unwrap();
-            let mut buf = vec![];
-            let offset = stat.get_offset();
-            let blocks = stat.get_blocks();
-            for i in offset..(offset+blocks) {
-                let mut chunk = self.content.get_data_entry(i)?;
-                buf.append(&mut chunk);
-            }
-            return Ok(buf);
+            self.content_reader(&stat)
         } else {
             bail!("Couldn't find path: {}", path.as_ref().display());
         }
     }
 
+    /// Streaming access to the content chunks a file's `Stat` points to (eg, from `history()`),
+    /// decrypting them for private drives.
+    pub fn content_reader(&mut self, stat: &Stat) -> Result<RegisterReader<'_, H>> {
+        match self.sealing_key()?.cloned() {
+            Some(key) => RegisterReader::new_sealed(&mut self.content, stat.get_offset(), stat.get_blocks(), &key),
+            None => RegisterReader::new(&mut self.content, stat.get_offset(), stat.get_blocks()),
+        }
+    }
+
     /// For now, simply verifies that both metadata and content registers are properly signed.
     pub fn verify(&mut self) -> Result<()> {
         self.metadata.verify()?;
@@ -610,6 +906,73 @@ impl<'a> DatDrive {
         return self.append_metadata_entry(&to, Some(&stat), None);
     }
 
+    /// Copies files from this drive into `target` (normally a new, empty drive): either the current
+    /// files, or the full history of changes (see `ForkMode`). Content shared between entries (eg,
+    /// from `copy_file()`) stays shared. Any `mark_forked()` pointer file is left out.
+    ///
+    /// Returns the version number of `target` after the copy.
+    pub fn fork_into<G: HyperRegister>(&mut self, target: &mut DatDrive<G>, mode: ForkMode) -> Result<u64> {
+        let entries: Vec<DriveEntry> = match mode {
+            ForkMode::History => self.history(1).collect::<Result<_>>()?,
+            ForkMode::Snapshot => self.snapshot()?,
+        };
+        // (old content entry offset, blocks) => (new offset, new byte offset). Empty files share
+        // their offset with whatever comes next, so they're never recorded.
+        let mut copied: HashMap<(u64, u64), (u64, u64)> = HashMap::new();
+        let mut version = target.entry_count()?;
+        for entry in entries {
+            if entry.path == Path::new(FORK_POINTER_PATH) {
+                continue;
+            }
+            let mut stat = match entry.stat {
+                None => {
+                    version = target.remove_file(&entry.path)?;
+                    continue;
+                },
+                Some(stat) => stat,
+            };
+            if stat.get_blocks() > 0 {
+                if let Some(&(offset, byte_offset)) = copied.get(&(stat.get_offset(), stat.get_blocks())) {
+                    stat.set_offset(offset);
+                    stat.set_byteOffset(byte_offset);
+                    version = target.append_metadata_entry(&entry.path, Some(&stat), None)?;
+                    continue;
+                }
+            }
+            let old_key = (stat.get_offset(), stat.get_blocks());
+            let reader = self.content_reader(&stat)?;
+            version = target.add_file(&entry.path, &mut stat, reader)?;
+            if old_key.1 > 0 {
+                copied.insert(old_key, (stat.get_offset(), stat.get_byteOffset()));
+            }
+        }
+        Ok(version)
+    }
+
+    /// The latest version of each file currently in the drive, in path order. Like
+    /// `read_dir_recursive("/")`, but worked out from the full history instead of the file tree.
+    pub fn snapshot(&mut self) -> Result<Vec<DriveEntry>> {
+        let mut current: BTreeMap<PathBuf, DriveEntry> = BTreeMap::new();
+        for entry in self.history(1) {
+            let entry = entry?;
+            if entry.stat.is_some() {
+                current.insert(entry.path.clone(), entry);
+            } else {
+                current.remove(&entry.path);
+            }
+        }
+        Ok(current.into_values().collect())
+    }
+
+    /// Writes a small file (`FORK_POINTER_PATH`) saying this drive has moved to the given (new
+    /// metadata public) key, and read key if the new drive is private.
+    pub fn mark_forked(&mut self, new_key: &[u8], new_read_key: Option<&[u8]>) -> Result<u64> {
+        let pointer = format!("{}\n", format_dat_address(new_key, new_read_key));
+        let mut stat = Stat::new();
+        stat.set_mode(0o100644);
+        self.add_file_bytes(FORK_POINTER_PATH, &mut stat, pointer.as_bytes())
+    }
+
     /// Returns version number containing rename action on success.
     pub fn rename<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, from: P, to: Q) -> Result<u64> {
         // Crude implementation:
@@ -726,10 +1089,186 @@ fn test_dd_readback() {
 
     assert_eq!(&dd.read_file_bytes("/here/msg.txt").unwrap()[..],
                "hello world".as_bytes());
+    use std::io::{Seek, SeekFrom};
+    let mut reader = dd.file_reader("/here/msg.txt").unwrap();
+    reader.seek(SeekFrom::Start(6)).unwrap();
+    let mut buf = String::new();
+    reader.read_to_string(&mut buf).unwrap();
+    assert_eq!(buf, "world");
     assert_eq!(&dd.read_file_bytes("/sub/other.txt").unwrap()[..],
                "goodbye".as_bytes());
 }
 
+#[test]
+fn test_dd_memory() {
+    let mut dd = DatDrive::create_memory().unwrap();
+
+    let mut stat = make_test_stat();
+    dd.add_file_bytes("/here/msg.txt", &mut stat, "hello world".as_bytes()).unwrap();
+    let mut stat = make_test_stat();
+    dd.add_file_bytes("/sub/other.txt", &mut stat, "goodbye".as_bytes()).unwrap();
+    dd.remove_file("/sub/other.txt").unwrap();
+
+    assert_eq!(&dd.read_file_bytes("/here/msg.txt").unwrap()[..],
+               "hello world".as_bytes());
+    assert!(dd.read_file_bytes("/sub/other.txt").is_err());
+    assert_eq!(dd.read_dir_recursive("/").count(), 1);
+    assert_eq!(dd.history(1).count(), 3);
+    assert!(dd.verify().is_ok());
+}
+
+#[test]
+fn test_dd_create_from_seed() {
+    use tempdir::TempDir;
+    let tmp_dir = TempDir::new("geniza-test").unwrap();
+    let (dir_a, dir_b) = (tmp_dir.path().join("a"), tmp_dir.path().join("b"));
+    create_dir_all(&dir_a).unwrap();
+    create_dir_all(&dir_b).unwrap();
+    let mut ks = DirKeyStore::new(tmp_dir.path().join("keys"));
+    let dd_a = DatDrive::create_from_seed_with_keystore(&dir_a, &[5; 32], &mut ks).unwrap();
+    let dd_b = DatDrive::create_from_seed(&dir_b, &[5; 32]).unwrap();
+    let dd_mem = DatDrive::create_memory_from_seed(&[5; 32]).unwrap();
+    assert_eq!(dd_a.metadata.discovery_key(), dd_b.metadata.discovery_key());
+    assert_eq!(dd_a.content.discovery_key(), dd_b.content.discovery_key());
+    assert_eq!(dd_a.content.discovery_key(), dd_mem.content.discovery_key());
+    assert_ne!(dd_a.metadata.discovery_key(), dd_a.content.discovery_key());
+    drop(dd_a);
+
+    // A fresh key store can be filled in from the seed alone
+    let mut new_ks = DirKeyStore::new(tmp_dir.path().join("new_keys"));
+    assert!(DatDrive::open_with_keystore(&dir_a, true, &new_ks).is_err());
+    assert!(DatDrive::restore_secret_keys(&dir_a, &[6; 32], &mut new_ks).is_err());
+    DatDrive::restore_secret_keys(&dir_a, &[5; 32], &mut new_ks).unwrap();
+    let mut dd = DatDrive::open_with_keystore(&dir_a, true, &new_ks).unwrap();
+    let mut stat = make_test_stat();
+    dd.add_file_bytes("/msg.txt", &mut stat, "hello world".as_bytes()).unwrap();
+    assert!(dd.verify().is_ok());
+}
+
+#[test]
+fn test_dd_fork() {
+    use data_encoding::HEXLOWER;
+    let mut dd = DatDrive::create_memory().unwrap();
+    let mut stat = make_test_stat();
+    dd.add_file_bytes("/a.txt", &mut stat, "hello world".as_bytes()).unwrap();
+    let mut stat = make_test_stat();
+    dd.add_file_bytes("/b.txt", &mut stat, "goodbye".as_bytes()).unwrap();
+    dd.copy_file("/a.txt", "/sub/c.txt").unwrap();
+    let mut stat = make_test_stat();
+    dd.add_file_bytes("/empty", &mut stat, &[]).unwrap();
+    dd.remove_file("/b.txt").unwrap();
+    // An empty file has the same offset as the file after it
+    let mut stat = make_test_stat();
+    dd.add_file_bytes("/d.txt", &mut stat, "later".as_bytes()).unwrap();
+
+    let mut history = DatDrive::create_memory().unwrap();
+    assert_eq!(dd.fork_into(&mut history, ForkMode::History).unwrap(), 6);
+    let mut snapshot = DatDrive::create_memory().unwrap();
+    assert_eq!(dd.fork_into(&mut snapshot, ForkMode::Snapshot).unwrap(), 4);
+    for forked in [&mut history, &mut snapshot] {
+        assert_eq!(forked.read_file_bytes("/a.txt").unwrap(), "hello world".as_bytes());
+        assert_eq!(forked.read_file_bytes("/sub/c.txt").unwrap(), "hello world".as_bytes());
+        assert!(forked.read_file_bytes("/b.txt").is_err());
+        assert_eq!(forked.read_dir_recursive("/").count(), 4);
+        assert_eq!(forked.read_file_bytes("/empty").unwrap().len(), 0);
+        assert_eq!(forked.read_file_bytes("/d.txt").unwrap(), "later".as_bytes());
+        // Copied content isn't duplicated
+        assert_eq!(forked.content.len_bytes().unwrap(), 16 + if forked.entry_count().unwrap() == 6 { 7 } else { 0 });
+        assert!(forked.verify().is_ok());
+    }
+
+    // Old drive points to the new one, but that doesn't get copied on a second fork
+    let new_key = history.metadata.pub_key().to_vec();
+    dd.mark_forked(&new_key, None).unwrap();
+    let pointer = String::from_utf8(dd.read_file_bytes(FORK_POINTER_PATH).unwrap()).unwrap();
+    assert_eq!(pointer.trim_end(), format!("dat://{}", HEXLOWER.encode(&new_key)));
+    let mut again = DatDrive::create_memory().unwrap();
+    dd.fork_into(&mut again, ForkMode::Snapshot).unwrap();
+    assert!(again.read_file_bytes(FORK_POINTER_PATH).is_err());
+}
+
+#[test]
+fn test_dd_private() {
+    use tempdir::TempDir;
+    let mut dd = DatDrive::create_memory_private().unwrap();
+    let mut stat = make_test_stat();
+    let big: Vec<u8> = (0..200000u32).map(|i| (i % 251) as u8).collect();
+    dd.add_file_bytes("/secret/msg.txt", &mut stat, "hello world".as_bytes()).unwrap();
+    let mut stat = make_test_stat();
+    dd.add_file_bytes("/big", &mut stat, &big).unwrap();
+    assert!(dd.is_private());
+    assert_eq!(dd.read_file_bytes("/secret/msg.txt").unwrap(), "hello world".as_bytes());
+    assert_eq!(dd.read_file_bytes("/big").unwrap(), big);
+    assert_eq!(dd.read_dir_recursive("/").count(), 2);
+    // Nothing readable in the registers themselves
+    assert!(!dd.content.get_data_entry(0).unwrap().windows(5).any(|w| w == b"hello"));
+    assert!(!dd.metadata.get_data_entry(1).unwrap().windows(6).any(|w| w == b"secret"));
+    assert!(dd.verify().is_ok());
+
+    // On disk: the read key goes in the key store (and the address); without it, the drive can
+    // only be opened read-only, and verified but not read
+    let tmp_dir = TempDir::new("geniza-test").unwrap();
+    let dir = tmp_dir.path().join("drive");
+    create_dir_all(&dir).unwrap();
+    let mut ks = DirKeyStore::new(tmp_dir.path().join("keys"));
+    let mut dd = DatDrive::create_private(&dir, &mut ks).unwrap();
+    let mut stat = make_test_stat();
+    dd.add_file_bytes("/msg.txt", &mut stat, "hello world".as_bytes()).unwrap();
+    let address = dd.address();
+    drop(dd);
+    let (key, read_key) = parse_private_dat_address(&address).unwrap();
+    let read_key = read_key.unwrap();
+    assert_eq!(ks.get_read_key(&key).unwrap(), Some(read_key.clone()));
+
+    let mut dd = DatDrive::open_with_keystore(&dir, true, &ks).unwrap();
+    assert_eq!(dd.read_file_bytes("/msg.txt").unwrap(), "hello world".as_bytes());
+    drop(dd);
+
+    let empty_ks = DirKeyStore::new(tmp_dir.path().join("no_keys"));
+    assert!(DatDrive::open_with_keystore(&dir, true, &empty_ks).is_err());
+    let mut seed = DatDrive::open_with_keystore(&dir, false, &empty_ks).unwrap();
+    assert!(seed.is_private());
+    assert_eq!(seed.read_key(), None);
+    assert!(seed.verify().is_ok());
+    assert!(seed.read_file_bytes("/msg.txt").is_err());
+    assert!(seed.history(1).next().unwrap().is_err());
+    drop(seed);
+
+    let mut dd = DatDrive::open_with_read_key(&dir, false, &read_key).unwrap();
+    assert_eq!(dd.read_file_bytes("/msg.txt").unwrap(), "hello world".as_bytes());
+    let mut dd = DatDrive::open_with_read_key(&dir, false, &[3; 32]).unwrap();
+    assert!(dd.read_file_bytes("/msg.txt").is_err());
+}
+
+#[test]
+fn test_dd_open_without_index() {
+    use tempdir::TempDir;
+    let tmp_dir = TempDir::new("geniza-test").unwrap();
+    let dir = tmp_dir.path().join("drive");
+    create_dir_all(&dir).unwrap();
+    let mut dd = DatDrive::create(&dir).unwrap();
+    let mut stat = make_test_stat();
+    dd.add_file_bytes("/msg.txt", &mut stat, "hello world".as_bytes()).unwrap();
+    let index = dd.metadata.get_data_entry(0).unwrap();
+    // Like a sparse clone that hasn't fetched the Index (yet)
+    dd.metadata.clear(0, 1).unwrap();
+    drop(dd);
+
+    assert!(DatDrive::open(&dir, true).is_err());
+    let mut dd = DatDrive::open(&dir, false).unwrap();
+    assert!(!dd.privacy_known());
+    assert_eq!(dd.entry_count().unwrap(), 1);
+    assert!(dd.read_file_bytes("/msg.txt").is_err());
+    drop(dd);
+
+    let (mut metadata, _) = SleepDirRegister::open_recover(&dir, "metadata").unwrap();
+    metadata.restore_data_entry(0, &index).unwrap();
+    drop(metadata);
+    let mut dd = DatDrive::open(&dir, false).unwrap();
+    assert!(dd.privacy_known());
+    assert_eq!(dd.read_file_bytes("/msg.txt").unwrap(), "hello world".as_bytes());
+}
+
 /* TODO: needs data in register, or support for reading from checkout
 #[test]
 fn test_dd_read_file_bytes() {
@@ -886,12 +1425,12 @@ pub struct DriveEntry {
 }
 
 /// Iterator over full drive history (file additions/deletions).
-pub struct DriveHistory<'a> {
-    drive: &'a mut DatDrive,
+pub struct DriveHistory<'a, H: 'a + HyperRegister = SleepDirRegister> {
+    drive: &'a mut DatDrive<H>,
     current: u64,
 }
 
-impl<'a> Iterator for DriveHistory<'a> {
+impl<'a, H: HyperRegister> Iterator for DriveHistory<'a, H> {
     type Item = Result<DriveEntry>;
     fn next(&mut self) -> Option<Result<DriveEntry>> {
         // pubkey increment-by-one logic here
@@ -906,8 +1445,8 @@ impl<'a> Iterator for DriveHistory<'a> {
 }
 
 /// Iterator over drive file entries.
-pub struct ReadDriveDir<'a> {
-    drive: &'a mut DatDrive,
+pub struct ReadDriveDir<'a, H: 'a + HyperRegister = SleepDirRegister> {
+    drive: &'a mut DatDrive<H>,
     recursive: bool,
     path: PathBuf,
 
@@ -916,8 +1455,8 @@ pub struct ReadDriveDir<'a> {
     entries: Vec<(u64, u64)>,
 }
 
-impl<'a> ReadDriveDir<'a> {
-    fn init<P: AsRef<Path>>(drive: &mut DatDrive, path: P, recursive: bool) -> Result<ReadDriveDir> {
+impl<'a, H: HyperRegister> ReadDriveDir<'a, H> {
+    fn init<P: AsRef<Path>>(drive: &mut DatDrive<H>, path: P, recursive: bool) -> Result<ReadDriveDir<H>> {
 
         let path = path.as_ref();
 
@@ -949,7 +1488,7 @@ impl<'a> ReadDriveDir<'a> {
     }
 }
 
-impl<'a> Iterator for ReadDriveDir<'a> {
+impl<'a, H: HyperRegister> Iterator for ReadDriveDir<'a, H> {
     type Item = Result<DriveEntry>;
 
     fn next(&mut self) -> Option<Result<DriveEntry>> {
@@ -2,6 +2,9 @@
 use errors::*;
 use crypto::digest::Digest;
 use crypto::blake2b::Blake2b;
+use crypto::ed25519;
+use rand::{OsRng, Rng};
+use sodiumoxide::crypto::{pwhash, secretbox};
 
 /// Helper to calculate a discovery key from a public key. 'key' should be 32 bytes; the returned
 /// array will also be 32 bytes long.
@@ -16,16 +19,87 @@ pub fn make_discovery_key(key: &[u8]) -> Vec<u8> {
     discovery_key.to_vec()
 }
 
-/// Helper to parse a dat address (aka, public key) in string format.
+/// Helper to generate a random 32 byte seed (for `keypair_from_seed()`), using OsRng.
+pub fn random_seed() -> Result<Vec<u8>> {
+    let mut seed = vec![0; 32];
+    let mut rng = OsRng::new()?;
+    rng.fill_bytes(&mut seed);
+    Ok(seed)
+}
+
+/// Helper to create an Ed25519 key-pair from a 32 byte seed. Returns (secret_key, pub_key),
+/// which are 64 and 32 bytes long; the same seed always gives the same keys.
+pub fn keypair_from_seed(seed: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+    if seed.len() != 32 {
+        bail!("Key seed must be 32 bytes (got {})", seed.len());
+    }
+    let (secret_key, pub_key) = ed25519::keypair(seed);
+    Ok((secret_key.to_vec(), pub_key.to_vec()))
+}
+
+/// Helper to derive the seed for a drive's content key-pair from its metadata secret key, so the
+/// metadata key (or the seed it came from) is all that needs to be backed up.
 ///
-/// Address can start with 'dat://'. It should contain 64 hexadecimal characters.
-pub fn parse_dat_address(input: &str) -> Result<Vec<u8>> {
+/// Calculated as a BLAKE2b keyed hash (using the secret key) of the string "geniza content",
+/// similar to discovery keys. 'secret_key' should be 64 bytes; the returned seed is 32 bytes.
+pub fn derive_content_seed(secret_key: &[u8]) -> Vec<u8> {
+    let mut seed = [0; 32];
+    let mut hash = Blake2b::new_keyed(32, secret_key);
+    hash.input(b"geniza content");
+    hash.result(&mut seed);
+    seed.to_vec()
+}
 
-    let raw_key = if input.starts_with("dat://") {
-        &input[6..]
-    } else {
-        input
+/// Helper to stretch a passphrase into a 32 byte key seed, with scrypt (libsodium's
+/// "sensitive" limits; takes a few seconds and 1 GByte of RAM).
+///
+/// The salt is fixed, so the same passphrase always gives the same keys; passphrases need to be
+/// long and unique for this to be at all safe.
+pub fn seed_from_passphrase(passphrase: &str) -> Result<Vec<u8>> {
+    let salt = pwhash::Salt::from_slice(b"geniza key seed from passphrase!").unwrap();
+    let mut seed = vec![0; 32];
+    if pwhash::derive_key(&mut seed, passphrase.as_bytes(), &salt,
+                          pwhash::OPSLIMIT_SENSITIVE, pwhash::MEMLIMIT_SENSITIVE).is_err() {
+        bail!("Failed to derive key seed from passphrase (out of memory?)");
+    }
+    Ok(seed)
+}
+
+/// Extra bytes in each entry sealed by `seal_entry()`: nonce, MAC, and the entry index
+pub const SEALED_ENTRY_OVERHEAD: u64 = (secretbox::NONCEBYTES + secretbox::MACBYTES + 8) as u64;
+
+/// Helper to encrypt a single register entry with a symmetric (secretbox) key, using a random
+/// nonce. The entry index is sealed along with the data, so entries can't be swapped around.
+pub fn seal_entry(key: &secretbox::Key, index: u64, data: &[u8]) -> Vec<u8> {
+    let nonce = secretbox::gen_nonce();
+    let mut plain = Vec::with_capacity(8 + data.len());
+    plain.extend_from_slice(&index.to_be_bytes());
+    plain.extend_from_slice(data);
+    let mut sealed = nonce.0.to_vec();
+    sealed.extend(secretbox::seal(&plain, &nonce, key));
+    sealed
+}
+
+/// Inverse of `seal_entry()`; fails if the data was tampered with, or was sealed for a different
+/// entry index (or with a different key).
+pub fn open_entry(key: &secretbox::Key, index: u64, sealed: &[u8]) -> Result<Vec<u8>> {
+    if sealed.len() < SEALED_ENTRY_OVERHEAD as usize {
+        bail!("Encrypted data for entry {} is truncated", index);
+    }
+    let nonce = secretbox::Nonce::from_slice(&sealed[0..secretbox::NONCEBYTES]).unwrap();
+    let mut plain = match secretbox::open(&sealed[secretbox::NONCEBYTES..], &nonce, key) {
+        Ok(plain) => plain,
+        Err(()) => bail!("Encrypted data for entry {} failed to authenticate", index),
     };
+    let mut sealed_index = [0; 8];
+    sealed_index.copy_from_slice(&plain[0..8]);
+    if u64::from_be_bytes(sealed_index) != index {
+        bail!("Encrypted data chunk is for a different entry (expected {})", index);
+    }
+    Ok(plain.split_off(8))
+}
+
+fn parse_hex_key(raw_key: &str) -> Result<Vec<u8>> {
     if raw_key.len() != 32 * 2 {
         bail!("dat key not correct length");
     }
@@ -40,6 +114,43 @@ pub fn parse_dat_address(input: &str) -> Result<Vec<u8>> {
     Ok(key_bytes)
 }
 
+/// Helper to parse a dat address (aka, public key) in string format.
+///
+/// Address can start with 'dat://'. It should contain 64 hexadecimal characters, optionally
+/// followed by '+' and the read key of a private drive (another 64); the read key is ignored (see
+/// `parse_private_dat_address()`), so private addresses can be used to find and replicate drives.
+pub fn parse_dat_address(input: &str) -> Result<Vec<u8>> {
+    Ok(parse_private_dat_address(input)?.0)
+}
+
+/// Like `parse_dat_address()`, but also returns the read key, if the address has one
+/// ('dat://<key>+<read key>').
+pub fn parse_private_dat_address(input: &str) -> Result<(Vec<u8>, Option<Vec<u8>>)> {
+
+    let raw_key = input.strip_prefix("dat://").unwrap_or(input);
+    match raw_key.find('+') {
+        None => Ok((parse_hex_key(raw_key)?, None)),
+        Some(split) => Ok((parse_hex_key(&raw_key[..split])?,
+                           Some(parse_hex_key(&raw_key[(split + 1)..])?))),
+    }
+}
+
+/// Inverse of `parse_private_dat_address()`: formats a public key (and optional read key) as a
+/// 'dat://' address, in lower-case hex.
+pub fn format_dat_address(key: &[u8], read_key: Option<&[u8]>) -> String {
+    let mut address = "dat://".to_string();
+    for b in key {
+        address.push_str(&format!("{:02x}", b));
+    }
+    if let Some(read_key) = read_key {
+        address.push('+');
+        for b in read_key {
+            address.push_str(&format!("{:02x}", b));
+        }
+    }
+    address
+}
+
 #[test]
 fn test_parse_dat_address() {
 
@@ -58,4 +169,30 @@ fn test_parse_dat_address() {
         "dat://c7638882870abd4044d6467b0738f15e3a36f57c3a7f7f3417fd7e4e0841d5970").is_err());
     assert!(parse_dat_address(
         "dat://c7638882870abd4044d6467b0738f15e3a36f57c3a7f7f3417fd7e4e0841d59").is_err());
+
+    // Private drive addresses (with read key)
+    let key = parse_dat_address(
+        "dat://c7638882870abd4044d6467b0738f15e3a36f57c3a7f7f3417fd7e4e0841d597").unwrap();
+    let address = "dat://c7638882870abd4044d6467b0738f15e3a36f57c3a7f7f3417fd7e4e0841d597+\
+        0101010101010101010101010101010101010101010101010101010101010101";
+    assert_eq!(parse_dat_address(address).unwrap(), key);
+    assert_eq!(parse_private_dat_address(address).unwrap(), (key.clone(), Some(vec![1; 32])));
+    assert_eq!(format_dat_address(&key, Some(&[1; 32])), address);
+    assert_eq!(parse_private_dat_address(&format_dat_address(&key, None)).unwrap(), (key, None));
+    assert!(parse_dat_address(
+        "dat://c7638882870abd4044d6467b0738f15e3a36f57c3a7f7f3417fd7e4e0841d597+0101").is_err());
+}
+
+#[test]
+fn test_keypair_from_seed() {
+    let (secret_key, pub_key) = keypair_from_seed(&[1; 32]).unwrap();
+    assert_eq!(keypair_from_seed(&[1; 32]).unwrap(), (secret_key.clone(), pub_key.clone()));
+    assert_eq!(&secret_key[32..], &pub_key[..]);
+    assert!(keypair_from_seed(&[1; 31]).is_err());
+    assert_ne!(random_seed().unwrap(), random_seed().unwrap());
+
+    let content_seed = derive_content_seed(&secret_key);
+    assert_eq!(content_seed.len(), 32);
+    assert_eq!(content_seed, derive_content_seed(&secret_key));
+    assert_ne!(keypair_from_seed(&content_seed).unwrap().1, pub_key);
 }
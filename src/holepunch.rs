@@ -0,0 +1,528 @@
+
+use errors::*;
+use std::io;
+use std::collections::HashMap;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use protocol::{DatNetMessage, Extension};
+use pex::{encode_pex, decode_pex};
+
+/// Name of the hole punching protocol extension
+pub const HOLEPUNCH_EXTENSION: &str = "holepunch";
+
+/// Prefix on all hole punching UDP packets
+const UDP_MAGIC: &[u8] = b"GZHP";
+const UDP_REGISTER: u8 = 0;
+const UDP_OBSERVED: u8 = 1;
+const UDP_PUNCH: u8 = 2;
+
+/// How often registration and punch packets are re-sent while waiting for a reply
+const RESEND_INTERVAL_MS: u64 = 100;
+
+/// How long to keep trying to register or punch before giving up
+pub const HOLEPUNCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a rendezvous peer remembers a UDP registration
+pub const REGISTRATION_TTL: Duration = Duration::from_secs(60);
+
+/// Most UDP registrations a rendezvous peer remembers at once; the oldest is forgotten first
+pub const MAX_REGISTRATIONS: usize = 4096;
+
+pub type HolePunchToken = [u8; 16];
+
+/// Messages exchanged (as protocol extension messages) with a rendezvous peer: a peer that two
+/// NAT-ed peers are both connected to.
+///
+/// The flow is:
+///
+/// 1. each NAT-ed peer sends a UDP registration packet (with a random token) to the rendezvous
+///    peer's UDP port, which records the observed (public) UDP address for that token. The UDP
+///    port is the same number as the rendezvous peer's TCP port.
+/// 2. each sends `Register` with the same token over it's existing connection
+/// 3. the rendezvous peer replies with `Peers`: the other registered peers, by the address it
+///    knows them by
+/// 4. the newly registered peer sends `Connect`, naming one of those peers
+/// 5. the rendezvous peer sends `Introduce` to both, with the other's observed UDP address
+/// 6. both peers call `udp_hole_punch()` at the same time, and then talk over the punched socket
+///    (see `UdpStream`)
+///
+/// A UDP registration is used up by an introduction; peers register again (with a new socket) to
+/// be introduced to more peers.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HolePunchMsg {
+    Register(HolePunchToken),
+    Connect(SocketAddr),
+    Introduce(SocketAddr, HolePunchToken),
+    Peers(Vec<SocketAddr>),
+}
+
+impl HolePunchMsg {
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        match *self {
+            HolePunchMsg::Register(ref token) => {
+                buf.push(0);
+                buf.extend_from_slice(token);
+            },
+            HolePunchMsg::Connect(addr) => {
+                buf.push(1);
+                buf.append(&mut encode_pex(&[addr]));
+            },
+            HolePunchMsg::Introduce(addr, ref token) => {
+                buf.push(2);
+                buf.extend_from_slice(token);
+                buf.append(&mut encode_pex(&[addr]));
+            },
+            HolePunchMsg::Peers(ref addrs) => {
+                buf.push(3);
+                buf.append(&mut encode_pex(addrs));
+            },
+        }
+        buf
+    }
+
+    pub fn decode(raw: &[u8]) -> Result<HolePunchMsg> {
+        if raw.is_empty() {
+            bail!("Empty holepunch message");
+        }
+        match raw[0] {
+            0 => Ok(HolePunchMsg::Register(parse_token(&raw[1..])?)),
+            1 => Ok(HolePunchMsg::Connect(parse_one_addr(&raw[1..])?)),
+            2 => {
+                let token = parse_token(&raw[1..])?;
+                Ok(HolePunchMsg::Introduce(parse_one_addr(&raw[17..])?, token))
+            },
+            3 => Ok(HolePunchMsg::Peers(decode_pex(&raw[1..])?)),
+            other => bail!("Unknown holepunch message type: {}", other),
+        }
+    }
+
+    /// Helper to wrap as a network message
+    pub fn to_net_msg(&self) -> DatNetMessage {
+        DatNetMessage::Extension(Extension {
+            name: HOLEPUNCH_EXTENSION.to_string(),
+            payload: self.encode(),
+        })
+    }
+}
+
+fn parse_token(raw: &[u8]) -> Result<HolePunchToken> {
+    if raw.len() < 16 {
+        bail!("Truncated holepunch token");
+    }
+    let mut token = [0; 16];
+    token.copy_from_slice(&raw[0..16]);
+    Ok(token)
+}
+
+fn parse_one_addr(raw: &[u8]) -> Result<SocketAddr> {
+    let addrs = decode_pex(raw)?;
+    if addrs.len() != 1 {
+        bail!("Expected exactly one address in holepunch message");
+    }
+    Ok(addrs[0])
+}
+
+fn udp_packet(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut buf = UDP_MAGIC.to_vec();
+    buf.push(kind);
+    buf.extend_from_slice(body);
+    buf
+}
+
+/// Returns (kind, body) if this looks like one of our UDP packets
+fn parse_udp_packet(raw: &[u8]) -> Option<(u8, &[u8])> {
+    if raw.len() < UDP_MAGIC.len() + 1 || &raw[0..UDP_MAGIC.len()] != UDP_MAGIC {
+        return None;
+    }
+    Some((raw[UDP_MAGIC.len()], &raw[(UDP_MAGIC.len() + 1)..]))
+}
+
+/// Minimal datagram socket abstraction, so hole punching can run over a plain `UdpSocket` or
+/// something that behaves like one from behind a NAT (eg, in tests).
+pub trait Datagram {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize>;
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()>;
+}
+
+impl Datagram for UdpSocket {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, buf, addr)
+    }
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buf)
+    }
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        UdpSocket::set_read_timeout(self, dur)
+    }
+}
+
+/// Makes fresh sockets for hole punching (each rendezvous registration uses up one socket)
+pub type DatagramFactory = Box<dyn Fn() -> Result<Arc<dyn Datagram + Send + Sync>> + Send>;
+
+/// Waits (until `deadline`) for a packet matching `accept`, re-sending `packet` to `dest` every
+/// `RESEND_INTERVAL_MS`.
+fn send_until<D, F, T>(sock: &D, packet: &[u8], dest: SocketAddr, deadline: Instant, mut accept: F) -> Result<T>
+        where D: Datagram + ?Sized, F: FnMut(u8, &[u8], SocketAddr) -> Option<T> {
+    let mut buf = [0; 512];
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            bail!("Timed out waiting for UDP reply from {}", dest);
+        }
+        sock.send_to(packet, dest)?;
+        let resend_at = now + Duration::from_millis(RESEND_INTERVAL_MS);
+        loop {
+            let now = Instant::now();
+            if now >= resend_at || now >= deadline {
+                break;
+            }
+            let wait = if resend_at < deadline { resend_at - now } else { deadline - now };
+            sock.set_read_timeout(Some(wait))?;
+            let (len, from) = match sock.recv_from(&mut buf) {
+                Ok(v) => v,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => break,
+                Err(e) => return Err(e.into()),
+            };
+            if let Some((kind, body)) = parse_udp_packet(&buf[0..len]) {
+                if let Some(val) = accept(kind, body, from) {
+                    return Ok(val);
+                }
+            }
+        }
+    }
+}
+
+/// Registers a UDP socket's public address with a rendezvous peer's UDP port. Returns the address
+/// the rendezvous peer observed us at.
+pub fn udp_register<D: Datagram + ?Sized>(sock: &D, rendezvous: SocketAddr, token: &HolePunchToken, timeout: Duration) -> Result<SocketAddr> {
+    let packet = udp_packet(UDP_REGISTER, token);
+    send_until(sock, &packet, rendezvous, Instant::now() + timeout, |kind, body, from| {
+        if kind == UDP_OBSERVED && from == rendezvous && body.len() > 16 && body[0..16] == token[..] {
+            parse_one_addr(&body[16..]).ok()
+        } else {
+            None
+        }
+    })
+}
+
+/// Punches a hole through NATs on both ends, by sending probes to the remote's public address
+/// until a probe (with the same token) arrives from the remote. Both ends must call this at about
+/// the same time. Returns the address the remote's packets actually arrived from, which should be
+/// used for further communication.
+pub fn udp_hole_punch<D: Datagram + ?Sized>(sock: &D, remote: SocketAddr, token: &HolePunchToken, timeout: Duration) -> Result<SocketAddr> {
+    let packet = udp_packet(UDP_PUNCH, token);
+    let from = send_until(sock, &packet, remote, Instant::now() + timeout, |kind, body, from| {
+        // NATs may re-map the port, but not the IP
+        if kind == UDP_PUNCH && body == &token[..] && from.ip() == remote.ip() {
+            Some(from)
+        } else {
+            None
+        }
+    })?;
+    // The remote may still be waiting for one of our probes to get through
+    sock.send_to(&packet, from)?;
+    Ok(from)
+}
+
+/// Shared table of UDP registrations (token to observed address, and when it was seen).
+/// Registrations expire after `REGISTRATION_TTL`, and at most `MAX_REGISTRATIONS` are kept.
+#[derive(Clone, Default)]
+pub struct RendezvousRegistry {
+    inner: Arc<Mutex<HashMap<HolePunchToken, (SocketAddr, Instant)>>>,
+}
+
+impl RendezvousRegistry {
+    pub fn lookup(&self, token: &HolePunchToken) -> Option<SocketAddr> {
+        match self.inner.lock().unwrap().get(token) {
+            Some(&(addr, seen)) if seen.elapsed() < REGISTRATION_TTL => Some(addr),
+            _ => None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn insert(&self, token: HolePunchToken, addr: SocketAddr, seen: Instant) {
+        let mut table = self.inner.lock().unwrap();
+        if !table.contains_key(&token) && table.len() >= MAX_REGISTRATIONS {
+            table.retain(|_, &mut (_, t)| t.elapsed() < REGISTRATION_TTL);
+            if table.len() >= MAX_REGISTRATIONS {
+                let oldest = table.iter().min_by_key(|&(_, &(_, t))| t).map(|(k, _)| *k);
+                if let Some(oldest) = oldest {
+                    table.remove(&oldest);
+                }
+            }
+        }
+        table.insert(token, (addr, seen));
+    }
+}
+
+/// UDP side of a rendezvous peer: records the public address of every registration packet, and
+/// tells the sender what address it was seen from.
+pub struct UdpRendezvous {
+    socket: UdpSocket,
+    registry: RendezvousRegistry,
+}
+
+impl UdpRendezvous {
+
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<UdpRendezvous> {
+        Ok(UdpRendezvous {
+            socket: UdpSocket::bind(addr)?,
+            registry: RendezvousRegistry::default(),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    pub fn registry(&self) -> RendezvousRegistry {
+        self.registry.clone()
+    }
+
+    /// Serves registrations forever (or until receiving fails).
+    pub fn run(&mut self) -> Result<()> {
+        loop {
+            self.serve_one()?;
+        }
+    }
+
+    /// Blocks until a single packet is received and handles it. Unrelated and malformed packets
+    /// are dropped; only an error receiving is returned.
+    pub fn serve_one(&mut self) -> Result<()> {
+        let mut buf = [0; 512];
+        let (len, from) = self.socket.recv_from(&mut buf)?;
+        if let Some((UDP_REGISTER, body)) = parse_udp_packet(&buf[0..len]) {
+            let token = match parse_token(body) {
+                Ok(token) => token,
+                Err(e) => {
+                    warn!("dropping bad holepunch registration from {}: {}", from, e);
+                    return Ok(());
+                },
+            };
+            debug!("holepunch registration from {}", from);
+            self.registry.insert(token, from, Instant::now());
+            let mut reply_body = token.to_vec();
+            reply_body.append(&mut encode_pex(&[from]));
+            if let Err(e) = self.socket.send_to(&udp_packet(UDP_OBSERVED, &reply_body), from) {
+                warn!("couldn't reply to holepunch registration from {}: {}", from, e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Connection-side state of a rendezvous peer. Peers are identified by an opaque handle (eg, the
+/// synchronizer's peer handle) and the address we know them by.
+#[derive(Default)]
+pub struct HolePunchCoordinator {
+    tokens: HashMap<u64, HolePunchToken>,
+}
+
+impl HolePunchCoordinator {
+
+    pub fn new() -> HolePunchCoordinator {
+        HolePunchCoordinator::default()
+    }
+
+    pub fn remove_peer(&mut self, handle: u64) {
+        self.tokens.remove(&handle);
+    }
+
+    /// Handles a message received from peer `from`. `peers` lists the handle and address of every
+    /// connected peer. Returns messages to send, as (handle, message) pairs. `fresh_token` is used
+    /// for new introductions (it should be random).
+    ///
+    /// Only newly registered peers are told about the others (so two peers don't both ask to be
+    /// introduced to each other).
+    pub fn handle(&mut self, from: u64, msg: &HolePunchMsg, peers: &[(u64, SocketAddr)], registry: &RendezvousRegistry, fresh_token: HolePunchToken) -> Result<Vec<(u64, HolePunchMsg)>> {
+        match *msg {
+            HolePunchMsg::Register(token) => {
+                let is_new = self.tokens.insert(from, token).is_none();
+                let others: Vec<SocketAddr> = peers.iter()
+                    .filter(|&&(handle, _)| handle != from && self.tokens.contains_key(&handle))
+                    .map(|&(_, sa)| sa)
+                    .collect();
+                if is_new && !others.is_empty() {
+                    Ok(vec![(from, HolePunchMsg::Peers(others))])
+                } else {
+                    Ok(vec![])
+                }
+            },
+            HolePunchMsg::Connect(target) => {
+                let target = match peers.iter().find(|&&(_, sa)| sa == target) {
+                    Some(&(handle, _)) => handle,
+                    None => bail!("holepunch connect to unknown peer: {}", target),
+                };
+                let from_udp = self.udp_addr(from, registry)?;
+                let target_udp = self.udp_addr(target, registry)?;
+                Ok(vec![
+                    (from, HolePunchMsg::Introduce(target_udp, fresh_token)),
+                    (target, HolePunchMsg::Introduce(from_udp, fresh_token)),
+                ])
+            },
+            HolePunchMsg::Introduce(_, _) | HolePunchMsg::Peers(_) => {
+                bail!("unexpected holepunch message (we are the rendezvous)")
+            },
+        }
+    }
+
+    fn udp_addr(&self, handle: u64, registry: &RendezvousRegistry) -> Result<SocketAddr> {
+        let token = match self.tokens.get(&handle) {
+            Some(t) => t,
+            None => bail!("peer hasn't registered for holepunching"),
+        };
+        match registry.lookup(token) {
+            Some(addr) => Ok(addr),
+            None => bail!("no UDP registration seen for peer"),
+        }
+    }
+}
+
+/// Simulated NAT for tests: an address-restricted filter that drops inbound packets from any
+/// address the socket hasn't previously sent to.
+#[cfg(test)]
+pub struct NatSocket {
+    inner: UdpSocket,
+    allowed: Mutex<Vec<::std::net::IpAddr>>,
+}
+
+#[cfg(test)]
+impl NatSocket {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<NatSocket> {
+        Ok(NatSocket { inner: UdpSocket::bind(addr)?, allowed: Mutex::new(vec![]) })
+    }
+}
+
+#[cfg(test)]
+impl Datagram for NatSocket {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.allowed.lock().unwrap().push(addr.ip());
+        self.inner.send_to(buf, addr)
+    }
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        loop {
+            let (len, from) = self.inner.recv_from(buf)?;
+            if self.allowed.lock().unwrap().contains(&from.ip()) {
+                return Ok((len, from));
+            }
+        }
+    }
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(dur)
+    }
+}
+
+#[test]
+fn test_holepunch_msg_roundtrip() {
+    let addr: SocketAddr = "10.0.0.1:3282".parse().unwrap();
+    for msg in &[HolePunchMsg::Register([7; 16]),
+                 HolePunchMsg::Connect(addr),
+                 HolePunchMsg::Introduce(addr, [9; 16]),
+                 HolePunchMsg::Peers(vec![addr, addr])] {
+        assert_eq!(&HolePunchMsg::decode(&msg.encode()).unwrap(), msg);
+    }
+    assert!(HolePunchMsg::decode(&[]).is_err());
+    assert!(HolePunchMsg::decode(&[0, 1, 2]).is_err());
+    assert!(HolePunchMsg::decode(&[7]).is_err());
+}
+
+#[test]
+fn test_holepunch_simulated_nat() {
+    use std::thread;
+
+    // Two peers "behind NAT" on separate loopback addresses, and a rendezvous peer
+    let nat_a = NatSocket::bind("127.0.0.2:0").unwrap();
+    let nat_b = NatSocket::bind("127.0.0.3:0").unwrap();
+    let mut rendezvous = UdpRendezvous::bind("127.0.0.1:0").unwrap();
+    let rendezvous_addr = rendezvous.local_addr().unwrap();
+    let registry = rendezvous.registry();
+    thread::spawn(move || {
+        rendezvous.run().unwrap();
+    });
+    let timeout = Duration::from_secs(5);
+
+    // Unsolicited packets are filtered by the "NAT"
+    nat_b.send_to(b"hello", nat_a.inner.local_addr().unwrap()).unwrap();
+    nat_a.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+    assert!(nat_a.recv_from(&mut [0; 64]).is_err());
+
+    // 1. UDP registration
+    let observed_a = udp_register(&nat_a, rendezvous_addr, &[1; 16], timeout).unwrap();
+    let observed_b = udp_register(&nat_b, rendezvous_addr, &[2; 16], timeout).unwrap();
+    assert_eq!(observed_a, nat_a.inner.local_addr().unwrap());
+
+    // 2-4. Register and Connect messages (which would normally arrive over dat connections)
+    let tcp_a: SocketAddr = "127.0.0.2:3282".parse().unwrap();
+    let tcp_b: SocketAddr = "127.0.0.3:3282".parse().unwrap();
+    let peers = vec![(100, tcp_a), (200, tcp_b)];
+    let mut coord = HolePunchCoordinator::new();
+    assert!(coord.handle(100, &HolePunchMsg::Connect(tcp_b), &peers, &registry, [3; 16]).is_err());
+    let out = coord.handle(200, &HolePunchMsg::Register([2; 16]), &peers, &registry, [3; 16]).unwrap();
+    assert!(out.is_empty());
+    let out = coord.handle(100, &HolePunchMsg::Register([1; 16]), &peers, &registry, [3; 16]).unwrap();
+    assert_eq!(out, vec![(100, HolePunchMsg::Peers(vec![tcp_b]))]);
+    let out = coord.handle(100, &HolePunchMsg::Connect(tcp_b), &peers, &registry, [3; 16]).unwrap();
+    assert_eq!(out, vec![
+        (100, HolePunchMsg::Introduce(observed_b, [3; 16])),
+        (200, HolePunchMsg::Introduce(observed_a, [3; 16]))]);
+
+    // 5. Simultaneous punching
+    let punch_b = thread::spawn(move || {
+        udp_hole_punch(&nat_b, observed_a, &[3; 16], timeout).unwrap()
+    });
+    assert_eq!(udp_hole_punch(&nat_a, observed_b, &[3; 16], timeout).unwrap(), observed_b);
+    assert_eq!(punch_b.join().unwrap(), observed_a);
+}
+
+#[test]
+fn test_rendezvous_limits() {
+    let addr: SocketAddr = "10.0.0.1:3282".parse().unwrap();
+    let registry = RendezvousRegistry::default();
+    let now = Instant::now();
+    for i in 0..(MAX_REGISTRATIONS as u64 + 10) {
+        let mut token = [0; 16];
+        token[0..8].copy_from_slice(&i.to_le_bytes());
+        registry.insert(token, addr, now + Duration::from_millis(i));
+    }
+    assert_eq!(registry.len(), MAX_REGISTRATIONS);
+    // The oldest were forgotten
+    assert_eq!(registry.lookup(&[0; 16]), None);
+    let mut newest = [0; 16];
+    newest[0..8].copy_from_slice(&(MAX_REGISTRATIONS as u64 + 9).to_le_bytes());
+    assert_eq!(registry.lookup(&newest), Some(addr));
+
+    // Expired registrations aren't returned
+    if let Some(long_ago) = now.checked_sub(REGISTRATION_TTL * 2) {
+        registry.insert([0xff; 16], addr, long_ago);
+        assert_eq!(registry.lookup(&[0xff; 16]), None);
+    }
+}
+
+#[test]
+fn test_rendezvous_bad_packets() {
+    use std::thread;
+
+    let mut rendezvous = UdpRendezvous::bind("127.0.0.1:0").unwrap();
+    let rendezvous_addr = rendezvous.local_addr().unwrap();
+    let registry = rendezvous.registry();
+    thread::spawn(move || {
+        rendezvous.run().unwrap();
+    });
+    // A truncated registration doesn't stop the rendezvous from serving the next one
+    let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+    sock.send_to(&udp_packet(UDP_REGISTER, &[1, 2, 3]), rendezvous_addr).unwrap();
+    let observed = udp_register(&sock, rendezvous_addr, &[4; 16], Duration::from_secs(5)).unwrap();
+    assert_eq!(observed, sock.local_addr().unwrap());
+    assert_eq!(registry.len(), 1);
+}
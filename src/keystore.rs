@@ -0,0 +1,223 @@
+
+use std::env;
+use std::io::prelude::*;
+use std::fs::{self, OpenOptions, DirBuilder};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use data_encoding::HEXLOWER;
+
+use errors::*;
+use make_discovery_key;
+
+/// Somewhere to keep register secret keys apart from the register files themselves, so an
+/// archive directory can be copied or published without handing out write access.
+///
+/// Keys are looked up by (32 byte) public key; secret keys are 64 bytes, in the usual Ed25519
+/// (libsodium) format, with the public key as the second half.
+pub trait KeyStore {
+    /// Returns `None` if there is no key for this register (not an error).
+    fn get_secret_key(&self, pub_key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Fails if a *different* key is already stored for this register.
+    fn put_secret_key(&mut self, pub_key: &[u8], secret_key: &[u8]) -> Result<()>;
+
+    fn remove_secret_key(&mut self, pub_key: &[u8]) -> Result<()>;
+
+    /// Symmetric key for encrypting the register's data at rest (see
+    /// `SleepDirRegister::encrypt_data()`); `None` if there isn't one.
+    fn get_data_key(&self, pub_key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Fails if a *different* data key is already stored for this register.
+    fn put_data_key(&mut self, pub_key: &[u8], data_key: &[u8]) -> Result<()>;
+
+    /// Symmetric key for reading a private drive (see `DatDrive::create_private()`), looked up by
+    /// the drive's metadata public key; `None` if there isn't one.
+    fn get_read_key(&self, pub_key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Fails if a *different* read key is already stored for this drive.
+    fn put_read_key(&mut self, pub_key: &[u8], read_key: &[u8]) -> Result<()>;
+}
+
+fn check_key_pair(pub_key: &[u8], secret_key: &[u8]) -> Result<()> {
+    if pub_key.len() != 32 || secret_key.len() != 64 {
+        bail!("Bad key lengths (pub {} != 32 or secret {} != 64)", pub_key.len(), secret_key.len());
+    }
+    if secret_key[32..] != pub_key[..] {
+        bail!("Secret key doesn't match public key");
+    }
+    Ok(())
+}
+
+/// Stores each secret key as a raw file, named by the hex discovery key of the register (so
+/// directory listings don't reveal public keys either), split after the first byte like git
+/// objects: `<dir>/bf/feaefe7c...`. This matches the layout of the reference implementation's
+/// default location, `~/.dat/secret_keys/`.
+///
+/// Files are created with mode 0600 (and directories 0700); keys in files readable by anybody
+/// else are refused, like ssh does.
+pub struct DirKeyStore {
+    dir: PathBuf,
+}
+
+impl DirKeyStore {
+
+    /// The directory doesn't need to exist yet; it's created on the first `put_secret_key()`.
+    pub fn new<P: AsRef<Path>>(dir: P) -> DirKeyStore {
+        DirKeyStore { dir: dir.as_ref().to_path_buf() }
+    }
+
+    /// `~/.dat/secret_keys/` (using `$HOME`).
+    pub fn open_default() -> Result<DirKeyStore> {
+        match env::var_os("HOME") {
+            Some(ref home) if !home.is_empty() => {
+                Ok(DirKeyStore::new(Path::new(home).join(".dat").join("secret_keys")))
+            },
+            _ => bail!("Can't find default key store: HOME not set"),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.dir
+    }
+
+    fn key_path(&self, pub_key: &[u8]) -> PathBuf {
+        let dk_hex = HEXLOWER.encode(&make_discovery_key(pub_key));
+        self.dir.join(&dk_hex[0..2]).join(&dk_hex[2..])
+    }
+
+    fn data_key_path(&self, pub_key: &[u8]) -> PathBuf {
+        self.key_path(pub_key).with_extension("data_key")
+    }
+
+    fn read_key_path(&self, pub_key: &[u8]) -> PathBuf {
+        self.key_path(pub_key).with_extension("read_key")
+    }
+}
+
+fn read_private_file(path: &Path) -> Result<Option<Vec<u8>>> {
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let mode = fs::metadata(path)?.permissions().mode();
+    if mode & 0o077 != 0 {
+        bail!("Permissions {:o} for key file are too open (should be 600): {}",
+            mode & 0o777, path.display());
+    }
+    let mut contents = vec![];
+    OpenOptions::new().read(true).open(path)?.read_to_end(&mut contents)?;
+    Ok(Some(contents))
+}
+
+fn write_private_file(path: &Path, contents: &[u8]) -> Result<()> {
+    DirBuilder::new().recursive(true).mode(0o700).create(path.parent().unwrap())?;
+    let mut key_file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    key_file.write_all(contents)?;
+    key_file.sync_all()?;
+    Ok(())
+}
+
+impl KeyStore for DirKeyStore {
+
+    fn get_secret_key(&self, pub_key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let path = self.key_path(pub_key);
+        let secret_key = match read_private_file(&path)? {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+        if let Err(e) = check_key_pair(pub_key, &secret_key) {
+            bail!("Bad secret key file ({}): {}", e, path.display());
+        }
+        Ok(Some(secret_key))
+    }
+
+    fn put_secret_key(&mut self, pub_key: &[u8], secret_key: &[u8]) -> Result<()> {
+        check_key_pair(pub_key, secret_key)?;
+        if let Some(existing) = self.get_secret_key(pub_key)? {
+            if existing == secret_key {
+                return Ok(());
+            }
+            bail!("A different secret key is already stored for this register");
+        }
+        write_private_file(&self.key_path(pub_key), secret_key)
+    }
+
+    fn remove_secret_key(&mut self, pub_key: &[u8]) -> Result<()> {
+        let path = self.key_path(pub_key);
+        if path.is_file() {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    fn get_data_key(&self, pub_key: &[u8]) -> Result<Option<Vec<u8>>> {
+        read_private_file(&self.data_key_path(pub_key))
+    }
+
+    fn put_data_key(&mut self, pub_key: &[u8], data_key: &[u8]) -> Result<()> {
+        if let Some(existing) = self.get_data_key(pub_key)? {
+            if existing == data_key {
+                return Ok(());
+            }
+            bail!("A different data key is already stored for this register");
+        }
+        write_private_file(&self.data_key_path(pub_key), data_key)
+    }
+
+    fn get_read_key(&self, pub_key: &[u8]) -> Result<Option<Vec<u8>>> {
+        read_private_file(&self.read_key_path(pub_key))
+    }
+
+    fn put_read_key(&mut self, pub_key: &[u8], read_key: &[u8]) -> Result<()> {
+        if let Some(existing) = self.get_read_key(pub_key)? {
+            if existing == read_key {
+                return Ok(());
+            }
+            bail!("A different read key is already stored for this drive");
+        }
+        write_private_file(&self.read_key_path(pub_key), read_key)
+    }
+}
+
+#[test]
+fn test_dir_keystore() {
+    use tempdir::TempDir;
+    use crypto::ed25519;
+    let tmp_dir = TempDir::new("geniza-test").unwrap();
+    let mut ks = DirKeyStore::new(tmp_dir.path().join("secret_keys"));
+    let (secret_key, pub_key) = ed25519::keypair(&[7; 32]);
+    let (other_secret, other_pub) = ed25519::keypair(&[8; 32]);
+    assert_eq!(ks.get_secret_key(&pub_key).unwrap(), None);
+    ks.put_secret_key(&pub_key, &secret_key).unwrap();
+    assert_eq!(ks.get_secret_key(&pub_key).unwrap(), Some(secret_key.to_vec()));
+    assert_eq!(ks.get_secret_key(&other_pub).unwrap(), None);
+
+    // Idempotent, but won't overwrite or accept mismatched pairs
+    ks.put_secret_key(&pub_key, &secret_key).unwrap();
+    assert!(ks.put_secret_key(&pub_key, &other_secret).is_err());
+    assert!(ks.put_secret_key(&other_pub, &secret_key).is_err());
+
+    let path = ks.key_path(&pub_key);
+    assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+    assert_eq!(fs::metadata(path.parent().unwrap()).unwrap().permissions().mode() & 0o777, 0o700);
+    assert_eq!(path.parent().unwrap().parent().unwrap(), ks.path());
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+    assert!(ks.get_secret_key(&pub_key).is_err());
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+
+    // Data keys are kept separately
+    assert_eq!(ks.get_data_key(&pub_key).unwrap(), None);
+    ks.put_data_key(&pub_key, &[9; 32]).unwrap();
+    assert!(ks.put_data_key(&pub_key, &[10; 32]).is_err());
+    assert_eq!(ks.get_data_key(&pub_key).unwrap(), Some(vec![9; 32]));
+    assert_eq!(ks.get_read_key(&pub_key).unwrap(), None);
+    ks.put_read_key(&pub_key, &[11; 32]).unwrap();
+    assert_eq!(ks.get_read_key(&pub_key).unwrap(), Some(vec![11; 32]));
+
+    ks.remove_secret_key(&pub_key).unwrap();
+    assert_eq!(ks.get_secret_key(&pub_key).unwrap(), None);
+    assert_eq!(ks.get_data_key(&pub_key).unwrap(), Some(vec![9; 32]));
+}
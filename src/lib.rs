@@ -29,6 +29,8 @@ extern crate data_encoding;
 #[macro_use]
 extern crate chan;
 extern crate bit_vec;
+extern crate memmap;
+extern crate lru;
 
 #[cfg(test)]
 extern crate tempdir;
@@ -55,18 +57,36 @@ mod bitfield;
 pub use bitfield::*;
 mod sleep_file;
 pub use sleep_file::*;
+mod sleep_cache;
+pub use sleep_cache::*;
+mod keystore;
+pub use keystore::*;
 mod sleep_register;
 pub use sleep_register::*;
+mod register_reader;
+pub use register_reader::*;
 mod drive;
 pub use drive::*;
+mod multi_drive;
+pub use multi_drive::*;
 mod protocol;
 pub use protocol::*;
 pub mod network_msgs;
 pub mod metadata_msgs;
 mod discovery;
 pub use discovery::*;
+mod discovery_server;
+pub use discovery_server::*;
 mod peer;
 pub use peer::*;
+mod pex;
+pub use pex::*;
+mod holepunch;
+pub use holepunch::*;
+mod udp_stream;
+pub use udp_stream::*;
+mod relay;
+pub use relay::*;
 mod synchronizer;
 pub use synchronizer::*;
 
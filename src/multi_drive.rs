@@ -0,0 +1,497 @@
+
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::fs::{File, read_dir, create_dir_all};
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use data_encoding::HEXLOWER;
+
+use errors::*;
+use sleep_register::*;
+use register_reader::*;
+use keystore::*;
+use helpers::*;
+use drive::*;
+use metadata_msgs::Stat;
+
+/// File in the primary drive of a `MultiDrive` listing the other writers authorized to change it,
+/// one `dat://` address (metadata public key of the writer's drive) per line
+pub const WRITERS_PATH: &str = "/.dat-writers";
+
+/// `Stat` mode of the "whiteout" entries `MultiDrive::remove_file()` writes to mark a path as
+/// deleted (as in overlayfs, an empty character device)
+pub const WHITEOUT_MODE: u32 = 0o020000;
+
+/// Sub-directory of the primary drive directory holding other writers' drives, each named by
+/// hex public key
+const WRITERS_DIR: &str = "writers";
+
+/// Versions stamped with a `ctime` more than this far (in seconds) in the future are left out of
+/// the tree (until then), so nobody can claim a path for good by writing `u64::MAX`
+pub const MAX_CLOCK_SKEW: u64 = 24 * 60 * 60;
+
+/// A file in the combined file tree of a `MultiDrive`: the winning version of the path, and the
+/// writer whose drive it's in.
+#[derive(Debug, Clone)]
+pub struct MultiEntry {
+    pub path: PathBuf,
+    pub stat: Stat,
+    /// Metadata public key of the writer's drive
+    pub writer: Vec<u8>,
+}
+
+impl MultiEntry {
+
+    /// Does this version win over `other` (a version of the same path from another writer)? The
+    /// later `ctime` wins, with ties going to the greater writer key, so every peer ends up with
+    /// the same tree no matter what order they got the entries in.
+    fn wins_over(&self, other: &MultiEntry) -> bool {
+        (self.stat.get_ctime(), &self.writer) > (other.stat.get_ctime(), &other.writer)
+    }
+
+    fn is_whiteout(&self) -> bool {
+        self.stat.get_mode() == WHITEOUT_MODE
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// The combined tree, as of the given metadata register lengths (of the primary drive, then every
+// writer drive in key order)
+struct MergedTree {
+    lengths: Vec<u64>,
+    writers: Vec<Vec<u8>>,
+    // Including whiteouts
+    entries: Vec<MultiEntry>,
+    // When the earliest version left out for being in the future becomes valid
+    expires: Option<u64>,
+}
+
+/// One archive with several writers. Each writer only ever appends to their own drive; the owner
+/// of the primary drive (whose key is the archive's address) decides who else can write by
+/// listing their drives in `WRITERS_PATH`. The file tree is the combination of all the authorized
+/// writers' drives (that we have a copy of).
+///
+/// Every write through a `MultiDrive` sets `ctime` to the current time (or just after that of the
+/// version it replaces, if that's later, so clock skew can't undo it), and conflicting versions of
+/// a path are resolved by that (see `MultiEntry`); versions from too far in the future are
+/// ignored (see `MAX_CLOCK_SKEW`). Removals are written as "whiteout" entries, so a writer can
+/// delete a file that's in somebody else's drive.
+///
+/// The combined tree is kept between calls, and only rebuilt when a drive has changed.
+pub struct MultiDrive<H: HyperRegister = SleepDirRegister> {
+    pub primary: DatDrive<H>,
+    /// Other writers' drives (whether authorized or not), by metadata public key
+    pub writers: BTreeMap<Vec<u8>, DatDrive<H>>,
+    // Key of the drive our writes go to
+    local: Option<Vec<u8>>,
+    merged: Option<MergedTree>,
+}
+
+impl MultiDrive {
+
+    /// Opens the primary drive at `path` (see `DatDrive::open()`), and any writer drives in its
+    /// "writers" sub-directory. If writable, writes go to the first of those (primary first) we
+    /// have the secret key for.
+    pub fn open<P: AsRef<Path>>(path: P, writable: bool) -> Result<MultiDrive> {
+        let path = path.as_ref();
+        let mut md = MultiDrive::new(open_maybe_writable(path, writable)?);
+        let writers_dir = path.join(WRITERS_DIR);
+        if writers_dir.is_dir() {
+            for entry in read_dir(&writers_dir)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let key = match parse_dat_address(&name) {
+                    Ok(key) => key,
+                    Err(_) => {
+                        warn!("Skipping unexpected entry in writers directory: {}", name);
+                        continue;
+                    },
+                };
+                let drive = open_maybe_writable(&entry.path(), writable)?;
+                if drive.metadata.pub_key() != &key[..] {
+                    bail!("Writer drive doesn't match its directory name: {}", entry.path().display());
+                }
+                md.add_writer_drive(drive);
+            }
+        }
+        if writable && md.local.is_none() {
+            bail!("Don't have the secret key for any drive in this archive (see create_writer())");
+        }
+        Ok(md)
+    }
+
+    /// Makes a new (empty) writer drive for the archive at `path`, with secret keys in the key
+    /// store, and returns its public key. The primary drive's owner needs to `authorize_writer()`
+    /// it before anybody (including us) sees files written to it.
+    pub fn create_writer<P: AsRef<Path>>(path: P, keystore: &mut dyn KeyStore) -> Result<Vec<u8>> {
+        let path = path.as_ref();
+        let primary = DatDrive::open(path, false)?;
+        if !primary.privacy_known() {
+            bail!("Don't have the drive's Index entry, so can't tell if it's private");
+        }
+        if primary.is_private() {
+            bail!("Multiple writers aren't supported for private drives");
+        }
+        let seed = random_seed()?;
+        let (_, pub_key) = keypair_from_seed(&seed)?;
+        let dir = path.join(WRITERS_DIR).join(HEXLOWER.encode(&pub_key));
+        create_dir_all(&dir)?;
+        DatDrive::create_from_seed_with_keystore(&dir, &seed, keystore)?;
+        Ok(pub_key)
+    }
+
+    /// Copies Stat metadata and all content from a file in the "real" filesystem into our drive
+    /// (see `DatDrive::import_file()`).
+    pub fn import_file<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, source: P, dest: Q) -> Result<u64> {
+        let in_file = File::open(source)?;
+        let mut stat = stat_from_metadata(&in_file.metadata()?);
+        self.add_file(dest, &mut stat, in_file)
+    }
+}
+
+fn open_maybe_writable(path: &Path, writable: bool) -> Result<DatDrive> {
+    if writable {
+        if let Ok(drive) = DatDrive::open(path, true) {
+            return Ok(drive);
+        }
+    }
+    DatDrive::open(path, false)
+}
+
+impl<H: HyperRegister> MultiDrive<H> {
+
+    /// Starts with just the primary drive (writes go to it, if it's writable); see
+    /// `add_writer_drive()`.
+    pub fn new(primary: DatDrive<H>) -> MultiDrive<H> {
+        let local = if primary.metadata.writable() {
+            Some(primary.metadata.pub_key().to_vec())
+        } else {
+            None
+        };
+        MultiDrive {
+            primary,
+            writers: BTreeMap::new(),
+            local,
+            merged: None,
+        }
+    }
+
+    /// Adds another writer's drive. If we have nowhere to write yet and it's writable, writes will
+    /// go to it.
+    pub fn add_writer_drive(&mut self, drive: DatDrive<H>) {
+        let key = drive.metadata.pub_key().to_vec();
+        if self.local.is_none() && drive.metadata.writable() {
+            self.local = Some(key.clone());
+        }
+        self.writers.insert(key, drive);
+        self.merged = None;
+    }
+
+    /// Metadata public key of the drive writes go to, if any
+    pub fn local_writer(&self) -> Option<&[u8]> {
+        self.local.as_ref().map(|key| &key[..])
+    }
+
+    /// Public keys of all the writers whose drives make up the file tree: the primary drive first,
+    /// then those listed in its `WRITERS_PATH`.
+    pub fn authorized_writers(&mut self) -> Result<Vec<Vec<u8>>> {
+        Ok(self.merged()?.writers.clone())
+    }
+
+    fn read_authorized_writers(&mut self) -> Result<Vec<Vec<u8>>> {
+        let mut writers = vec![self.primary.metadata.pub_key().to_vec()];
+        let list = match self.primary.snapshot()?.into_iter().find(|e| e.path == Path::new(WRITERS_PATH)) {
+            None => return Ok(writers),
+            Some(entry) => {
+                let mut list = String::new();
+                self.primary.content_reader(&entry.stat.unwrap())?.read_to_string(&mut list)?;
+                list
+            },
+        };
+        for line in list.lines().map(|l| l.trim()).filter(|l| !l.is_empty()) {
+            let key = parse_dat_address(line)?;
+            if !writers.contains(&key) {
+                writers.push(key);
+            }
+        }
+        Ok(writers)
+    }
+
+    /// Adds a writer to `WRITERS_PATH` (we have to be the primary drive's owner). Returns the
+    /// primary drive's new version number.
+    pub fn authorize_writer(&mut self, key: &[u8]) -> Result<u64> {
+        let mut writers = self.authorized_writers()?;
+        if key.len() != 32 {
+            bail!("Bad writer key (len {} != 32)", key.len());
+        }
+        if writers.iter().any(|w| w == key) {
+            bail!("Already an authorized writer");
+        }
+        writers.push(key.to_vec());
+        self.write_writers(&writers[1..])
+    }
+
+    /// Takes a writer out of `WRITERS_PATH` (we have to be the primary drive's owner); their
+    /// files drop out of the tree. Returns the primary drive's new version number.
+    pub fn revoke_writer(&mut self, key: &[u8]) -> Result<u64> {
+        let mut writers = self.authorized_writers()?;
+        if key == self.primary.metadata.pub_key() {
+            bail!("Can't revoke the primary drive");
+        }
+        if !writers.iter().any(|w| w == key) {
+            bail!("Not an authorized writer");
+        }
+        writers.retain(|w| w != key);
+        self.write_writers(&writers[1..])
+    }
+
+    fn write_writers(&mut self, writers: &[Vec<u8>]) -> Result<u64> {
+        if !self.primary.metadata.writable() {
+            bail!("Only the owner of the primary drive can change the writers");
+        }
+        let mut list = String::new();
+        for key in writers {
+            list.push_str(&format_dat_address(key, None));
+            list.push('\n');
+        }
+        let mut stat = Stat::new();
+        stat.set_mode(0o100644);
+        stat.set_ctime(now_secs());
+        self.primary.add_file_bytes(WRITERS_PATH, &mut stat, list.as_bytes())
+    }
+
+    /// The combined file tree of all authorized writers, in path order.
+    ///
+    /// Authorized writers whose drives we don't have (yet) are skipped. Only the primary drive's
+    /// `WRITERS_PATH` counts.
+    pub fn files(&mut self) -> Result<Vec<MultiEntry>> {
+        Ok(self.files_with_whiteouts()?.into_iter().filter(|e| !e.is_whiteout()).collect())
+    }
+
+    /// The combined tree, rebuilt first if any drive has changed since last time (or a version
+    /// left out for being in the future is due).
+    fn merged(&mut self) -> Result<&MergedTree> {
+        let mut lengths = vec![self.primary.metadata.len()?];
+        for drive in self.writers.values() {
+            lengths.push(drive.metadata.len()?);
+        }
+        let fresh = match self.merged {
+            Some(ref merged) => merged.lengths == lengths && merged.expires.is_none_or(|t| now_secs() < t),
+            None => false,
+        };
+        if !fresh {
+            let writers = self.read_authorized_writers()?;
+            let (entries, expires) = self.merge(&writers)?;
+            self.merged = Some(MergedTree { lengths, writers, entries, expires });
+        }
+        Ok(self.merged.as_ref().unwrap())
+    }
+
+    fn merge(&mut self, writers: &[Vec<u8>]) -> Result<(Vec<MultiEntry>, Option<u64>)> {
+        let primary_key = self.primary.metadata.pub_key().to_vec();
+        let latest_ctime = now_secs().saturating_add(MAX_CLOCK_SKEW);
+        let mut expires: Option<u64> = None;
+        let mut winners: BTreeMap<PathBuf, MultiEntry> = BTreeMap::new();
+        for key in writers.iter().cloned() {
+            let drive = if key == primary_key {
+                &mut self.primary
+            } else {
+                match self.writers.get_mut(&key) {
+                    Some(drive) => drive,
+                    None => continue,
+                }
+            };
+            for entry in drive.snapshot()? {
+                if entry.path == Path::new(WRITERS_PATH) && key != primary_key {
+                    continue;
+                }
+                let candidate = MultiEntry {
+                    path: entry.path,
+                    stat: entry.stat.unwrap(),
+                    writer: key.clone(),
+                };
+                let ctime = candidate.stat.get_ctime();
+                if ctime > latest_ctime {
+                    warn!("Ignoring version of {} from the future (ctime {})", candidate.path.display(), ctime);
+                    let due = ctime - MAX_CLOCK_SKEW;
+                    expires = Some(expires.map_or(due, |t| t.min(due)));
+                    continue;
+                }
+                let wins = match winners.get(&candidate.path) {
+                    Some(current) => candidate.wins_over(current),
+                    None => true,
+                };
+                if wins {
+                    winners.insert(candidate.path.clone(), candidate);
+                }
+            }
+        }
+        Ok((winners.into_values().collect(), expires))
+    }
+
+    fn files_with_whiteouts(&mut self) -> Result<Vec<MultiEntry>> {
+        Ok(self.merged()?.entries.clone())
+    }
+
+    /// Files at or under `path` in the combined tree.
+    pub fn read_dir_recursive<P: AsRef<Path>>(&mut self, path: P) -> Result<Vec<MultiEntry>> {
+        let path = path.as_ref();
+        Ok(self.files()?.into_iter().filter(|e| e.path.starts_with(path)).collect())
+    }
+
+    /// The winning version of a single file, if it exists.
+    pub fn get_file<P: AsRef<Path>>(&mut self, path: P) -> Result<Option<MultiEntry>> {
+        let path = path.as_ref();
+        Ok(self.merged()?.entries.iter().find(|e| e.path == path && !e.is_whiteout()).cloned())
+    }
+
+    /// Streaming (Read + Seek) access to the winning version of a file.
+    pub fn file_reader<P: AsRef<Path>>(&mut self, path: P) -> Result<RegisterReader<'_, H>> {
+        let entry = match self.get_file(path.as_ref())? {
+            Some(entry) => entry,
+            None => bail!("Couldn't find path: {}", path.as_ref().display()),
+        };
+        let drive = if entry.writer == self.primary.metadata.pub_key() {
+            &mut self.primary
+        } else {
+            self.writers.get_mut(&entry.writer).unwrap()
+        };
+        drive.content_reader(&entry.stat)
+    }
+
+    pub fn read_file_bytes<P: AsRef<Path>>(&mut self, path: P) -> Result<Vec<u8>> {
+        let mut buf = vec![];
+        self.file_reader(path)?.read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Our (authorized) drive, for writing
+    fn local_drive(&mut self) -> Result<&mut DatDrive<H>> {
+        let key = match self.local {
+            Some(ref key) => key.clone(),
+            None => bail!("No writable drive for this archive (see create_writer())"),
+        };
+        if !self.merged()?.writers.contains(&key) {
+            bail!("Our writer drive hasn't been authorized by the primary drive's owner");
+        }
+        if key == self.primary.metadata.pub_key() {
+            Ok(&mut self.primary)
+        } else {
+            Ok(self.writers.get_mut(&key).unwrap())
+        }
+    }
+
+    /// Adds a file to our drive, stamping `ctime` (see `MultiDrive`). Returns our drive's version
+    /// number.
+    pub fn add_file<P: AsRef<Path>, R: Read>(&mut self, path: P, stat: &mut Stat, source: R) -> Result<u64> {
+        if path.as_ref() == Path::new(WRITERS_PATH) {
+            bail!("Use authorize_writer() or revoke_writer() to change {}", WRITERS_PATH);
+        }
+        // (including whiteouts, which files() leaves out)
+        let replaced = self.merged()?.entries.iter().find(|e| e.path == path.as_ref()).cloned();
+        let ctime = match replaced {
+            Some(entry) => now_secs().max(entry.stat.get_ctime().saturating_add(1)),
+            None => now_secs(),
+        };
+        stat.set_ctime(ctime);
+        self.local_drive()?.add_file(path, stat, source)
+    }
+
+    pub fn add_file_bytes<P: AsRef<Path>>(&mut self, path: P, stat: &mut Stat, data: &[u8]) -> Result<u64> {
+        self.add_file(path, stat, data)
+    }
+
+    /// Deletes a file from the combined tree (whichever drive it's in), by writing a whiteout
+    /// entry to our drive. Returns our drive's version number.
+    pub fn remove_file<P: AsRef<Path>>(&mut self, path: P) -> Result<u64> {
+        let path = path.as_ref();
+        if self.get_file(path)?.is_none() {
+            bail!("Tried to delete non-existant file: {}", path.display());
+        }
+        let mut stat = Stat::new();
+        stat.set_mode(WHITEOUT_MODE);
+        self.add_file_bytes(path, &mut stat, &[])
+    }
+
+    /// Checks signatures et al of every drive we have.
+    pub fn verify(&mut self) -> Result<()> {
+        self.primary.verify()?;
+        for drive in self.writers.values_mut() {
+            drive.verify()?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_multi_drive() {
+    let mut md = MultiDrive::new(DatDrive::create_memory().unwrap());
+    let mut stat = Stat::new();
+    stat.set_mode(0o100644);
+    md.add_file_bytes("/shared.txt", &mut stat, b"from primary").unwrap();
+
+    // Writers are ignored until authorized
+    let mut writer = DatDrive::create_memory().unwrap();
+    let writer_key = writer.metadata.pub_key().to_vec();
+    let mut stat = Stat::new();
+    stat.set_mode(0o100644);
+    writer.add_file_bytes("/theirs.txt", &mut stat, b"from writer").unwrap();
+    md.add_writer_drive(writer);
+    assert_eq!(md.files().unwrap().len(), 1);
+    md.authorize_writer(&writer_key).unwrap();
+    assert!(md.authorize_writer(&writer_key).is_err());
+    assert_eq!(md.authorized_writers().unwrap().len(), 2);
+    let paths: Vec<PathBuf> = md.files().unwrap().into_iter().map(|e| e.path).collect();
+    assert_eq!(paths, vec![PathBuf::from(WRITERS_PATH), PathBuf::from("/shared.txt"), PathBuf::from("/theirs.txt")]);
+    assert_eq!(md.read_file_bytes("/theirs.txt").unwrap(), b"from writer");
+
+    // Concurrent writes: later ctime wins, then greater key
+    let later = now_secs() + 100;
+    for (ctime, data) in [(5, &b"older"[..]), (later, &b"newer"[..])] {
+        let mut stat = Stat::new();
+        stat.set_mode(0o100644);
+        stat.set_ctime(ctime);
+        md.writers.get_mut(&writer_key).unwrap().add_file_bytes("/shared.txt", &mut stat, data).unwrap();
+        let expected = if ctime == 5 { &b"from primary"[..] } else { data };
+        assert_eq!(md.read_file_bytes("/shared.txt").unwrap(), expected);
+    }
+    let primary_key = md.primary.metadata.pub_key().to_vec();
+    let mut stat = Stat::new();
+    stat.set_mode(0o100644);
+    stat.set_ctime(later);
+    md.primary.add_file_bytes("/shared.txt", &mut stat, b"tie").unwrap();
+    let winner = md.get_file("/shared.txt").unwrap().unwrap().writer;
+    assert_eq!(winner, if primary_key > writer_key { primary_key.clone() } else { writer_key.clone() });
+
+    // Removing a file from another writer's drive (even one stamped in the future)
+    let mut stat = Stat::new();
+    stat.set_mode(0o100644);
+    stat.set_ctime(now_secs() + 1000);
+    md.writers.get_mut(&writer_key).unwrap().add_file_bytes("/theirs.txt", &mut stat, b"future").unwrap();
+    md.remove_file("/theirs.txt").unwrap();
+    assert!(md.get_file("/theirs.txt").unwrap().is_none());
+    assert!(md.remove_file("/theirs.txt").is_err());
+    assert_eq!(md.read_dir_recursive("/").unwrap().len(), 2);
+    assert!(md.add_file_bytes(WRITERS_PATH, &mut Stat::new(), b"").is_err());
+
+    // Versions from the far future don't count, so can't claim a path for good
+    let mut stat = Stat::new();
+    stat.set_mode(0o100644);
+    stat.set_ctime(u64::MAX);
+    md.writers.get_mut(&writer_key).unwrap().add_file_bytes("/shared.txt", &mut stat, b"forever").unwrap();
+    assert_eq!(md.read_file_bytes("/shared.txt").unwrap(), b"tie");
+    let mut stat = Stat::new();
+    stat.set_mode(0o100644);
+    md.add_file_bytes("/shared.txt", &mut stat, b"after").unwrap();
+    assert_eq!(md.read_file_bytes("/shared.txt").unwrap(), b"after");
+    assert!(md.get_file("/shared.txt").unwrap().unwrap().stat.get_ctime() <= now_secs() + MAX_CLOCK_SKEW);
+
+    // Revoked writers' files drop out
+    md.revoke_writer(&writer_key).unwrap();
+    assert!(md.revoke_writer(&primary_key).is_err());
+    assert_eq!(md.read_file_bytes("/shared.txt").unwrap(), b"after");
+    assert!(md.verify().is_ok());
+}
@@ -1,13 +1,17 @@
 
 use errors::*;
 use std::thread;
-use std::net::ToSocketAddrs;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
 use std::fmt::Display;
 use std::time::Duration;
 use protocol::{DatConnection, DatNetMessage};
 use network_msgs::*;
 use sodiumoxide::crypto::stream::Key;
 use make_discovery_key;
+use relay::connect_via_relay;
+use holepunch::{Datagram, HolePunchToken, HOLEPUNCH_TIMEOUT, udp_hole_punch};
+use udp_stream::UdpStream;
 use chan;
 
 /// Wraps a low-level DatConnection in a thread (or two). Contains very little context about
@@ -24,6 +28,21 @@ pub struct PeerMsg {
     pub msg: DatNetMessage,
 }
 
+/// Everything a peer thread passes upwards on the unified channel
+pub enum PeerEvent {
+    /// A new incoming connection (see `DatPeerThread::accept()`); the handshake is still to come.
+    Accepted(DatPeerThread),
+    /// The handshake completed. `addr` is the address the peer was dialed at (or connected from).
+    /// `direct` is only true for connections we dialed straight to `addr`, the only kind of
+    /// address other peers could use: relayed connections may not even be to the peer at `addr`,
+    /// and incoming and hole punched ones come from wherever a NAT put them.
+    Connected { handle: u64, addr: SocketAddr, direct: bool },
+    Msg(PeerMsg),
+    /// The connection failed or was closed (with the error, if any), and the thread has exited.
+    /// Nothing more will be received from this peer.
+    Closed { handle: u64, error: Option<Error> },
+}
+
 /// This is what the "receive" loop does: simply blocking reads on the TCP socket, passing any
 /// received messages into a channel back to the worker thread.
 fn receiver_loop(mut dc: DatConnection, peer_rx: chan::Sender<Result<(DatNetMessage, u8)>>) {
@@ -46,9 +65,9 @@ fn receiver_loop(mut dc: DatConnection, peer_rx: chan::Sender<Result<(DatNetMess
 /// on the command channel, and sends these directly (blocking). Also looks for raw received
 /// messages (via a spawned receiver thread), and enhances these with extra context then passes
 /// upwards on the unified peer message channel.
-fn worker_thread(mut dc: DatConnection, handle: u64, outbound_chan: chan::Receiver<(DatNetMessage, u8)>, unified_chan: chan::Sender<Result<PeerMsg>>) {
+fn worker_thread(mut dc: DatConnection, handle: u64, outbound_chan: chan::Receiver<(DatNetMessage, u8)>, unified_chan: chan::Sender<PeerEvent>) {
 
-    dc.tcp.set_write_timeout(Some(Duration::new(2, 0))).unwrap();
+    dc.transport.set_write_timeout(Some(Duration::new(2, 0))).unwrap();
 
     let rx_dc = dc.clone();
     let (receiver_chan, raw_peer_rx) = chan::async();
@@ -64,7 +83,8 @@ fn worker_thread(mut dc: DatConnection, handle: u64, outbound_chan: chan::Receiv
                         Ok(_) => {},
                         Err(e) => {
                             // TODO: error chain!
-                            unified_chan.send(Err(e));
+                            unified_chan.send(PeerEvent::Closed { handle, error: Some(e) });
+                            dc.close();
                             return
                         }
                     }
@@ -79,19 +99,17 @@ fn worker_thread(mut dc: DatConnection, handle: u64, outbound_chan: chan::Receiv
                             feed_index: feed_index,
                             msg,
                         };
-                        unified_chan.send(Ok(pm));
+                        unified_chan.send(PeerEvent::Msg(pm));
                     },
                     Some(Err(err)) => {
                         println!("remote socket error: {:?}", err);
-                        // XXX: Need to send something so we know to close
-                        unified_chan.send(Err(err));
+                        unified_chan.send(PeerEvent::Closed { handle, error: Some(err) });
                         dc.close();
                         return;
                     },
                     None => {
                         println!("remote socket closed");
-                        // XXX: Need to send something so we know to close
-                        //unified_chan.send(Err(err));
+                        unified_chan.send(PeerEvent::Closed { handle, error: None });
                         dc.close();
                         return;
                     }
@@ -103,7 +121,14 @@ fn worker_thread(mut dc: DatConnection, handle: u64, outbound_chan: chan::Receiv
 
 impl DatPeerThread {
 
-    pub fn connect<A: ToSocketAddrs + Display>(addr: A, feed_key: Key, handle: u64, is_live: bool, local_id: Option<&[u8]>, unified_chan: chan::Sender<Result<PeerMsg>>) -> Result<DatPeerThread> {
+    pub fn connect<A: ToSocketAddrs + Display>(addr: A, feed_key: Key, handle: u64, is_live: bool, local_id: Option<&[u8]>, unified_chan: chan::Sender<PeerEvent>) -> Result<DatPeerThread> {
+        DatPeerThread::connect_relayed(addr, None, feed_key, handle, is_live, local_id, unified_chan)
+    }
+
+    /// Like `connect()`, but if a direct connection fails and a relay is given, falls back to
+    /// connecting through the relay (to whichever peer is listening there for this feed, which
+    /// may not be `addr`).
+    pub fn connect_relayed<A: ToSocketAddrs + Display>(addr: A, relay: Option<SocketAddr>, feed_key: Key, handle: u64, is_live: bool, local_id: Option<&[u8]>, unified_chan: chan::Sender<PeerEvent>) -> Result<DatPeerThread> {
 
         let addr = addr.to_socket_addrs().unwrap().nth(0).unwrap();
         let (outbound_chan, tx_chan) = chan::async();
@@ -127,10 +152,23 @@ impl DatPeerThread {
                 },
             };
             let dc = match DatConnection::connect(addr, &feed_key, is_live, local_id) {
-                Ok(c) => c,
+                Ok(c) => Ok((c, true)),
+                Err(e) => match relay {
+                    Some(relay) => {
+                        warn!("direct connection to {} failed ({}), trying relay", addr, e);
+                        connect_via_relay(relay, &feed_key, is_live, local_id).map(|c| (c, false))
+                    },
+                    None => Err(e),
+                },
+            };
+            let dc = match dc {
+                Ok((c, direct)) => {
+                    unified_chan.send(PeerEvent::Connected { handle, addr, direct });
+                    c
+                },
                 Err(e) => {
                     // TODO: error chain!
-                    unified_chan.send(Err(e));
+                    unified_chan.send(PeerEvent::Closed { handle, error: Some(e) });
                     return;
                 },
             };
@@ -146,6 +184,65 @@ impl DatPeerThread {
         Ok(dp)
     }
 
+    /// Handshakes over an already accepted TCP connection (eg, from a `TcpListener`, or a relay).
+    /// The new `DatPeerThread` is passed up the unified channel (as `PeerEvent::Accepted`),
+    /// before any other events for it.
+    pub fn accept(tcp: TcpStream, feed_key: Key, handle: u64, is_live: bool, local_id: Option<&[u8]>, unified_chan: chan::Sender<PeerEvent>) -> Result<()> {
+        let addr = tcp.peer_addr()?;
+        let (outbound_chan, tx_chan) = chan::async();
+        unified_chan.send(PeerEvent::Accepted(DatPeerThread {
+            handle,
+            outbound_chan,
+            feeds: vec![(0, feed_key.clone())],
+        }));
+        let local_id = local_id.map(|id| id.to_vec());
+        thread::spawn(move || {
+            match DatConnection::from_tcp(tcp, &feed_key, is_live, local_id.as_ref().map(|id| &id[..])) {
+                Ok(dc) => {
+                    unified_chan.send(PeerEvent::Connected { handle, addr, direct: false });
+                    worker_thread(dc, handle, tx_chan, unified_chan);
+                },
+                Err(e) => unified_chan.send(PeerEvent::Closed { handle, error: Some(e) }),
+            }
+        });
+        Ok(())
+    }
+
+    /// Connects over UDP, after an introduction by a rendezvous peer: punches through to the
+    /// introduced address (using the introduction's token), then handshakes over the punched
+    /// socket. The other peer must be doing the same at about the same time.
+    pub fn connect_punched(sock: Arc<dyn Datagram + Send + Sync>, introduction: (SocketAddr, HolePunchToken), feed_key: Key, handle: u64, is_live: bool, local_id: Option<&[u8]>, unified_chan: chan::Sender<PeerEvent>) -> Result<DatPeerThread> {
+        let (outbound_chan, tx_chan) = chan::async();
+        let dp = DatPeerThread {
+            handle,
+            outbound_chan,
+            feeds: vec![(0, feed_key.clone())],
+        };
+        let (remote, token) = introduction;
+        let local_id = local_id.map(|id| id.to_vec());
+        thread::spawn(move || {
+            let dc = udp_hole_punch(&*sock, remote, &token, HOLEPUNCH_TIMEOUT).and_then(|punched| {
+                info!("punched through to {} (introduced as {})", punched, remote);
+                let stream = UdpStream::new(sock, punched);
+                let dc = DatConnection::from_udp(stream, &feed_key, is_live, local_id.as_ref().map(|id| &id[..]))?;
+                Ok((dc, punched))
+            });
+            match dc {
+                Ok((dc, addr)) => {
+                    unified_chan.send(PeerEvent::Connected { handle, addr, direct: false });
+                    worker_thread(dc, handle, tx_chan, unified_chan);
+                },
+                Err(e) => unified_chan.send(PeerEvent::Closed { handle, error: Some(e) }),
+            }
+        });
+        Ok(dp)
+    }
+
+    /// Another way to send on this connection, eg from a helper thread
+    pub fn outbound(&self) -> chan::Sender<(DatNetMessage, u8)> {
+        self.outbound_chan.clone()
+    }
+
     pub fn send(&mut self, net_msg: DatNetMessage, feed_index: u8) -> Result<()> {
         self.outbound_chan.send((net_msg, feed_index));
         Ok(())
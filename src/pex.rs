@@ -0,0 +1,89 @@
+
+use errors::*;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use protocol::{DatNetMessage, Extension};
+
+/// Name of the peer exchange protocol extension
+pub const PEX_EXTENSION: &str = "peer-exchange";
+
+/// Cap on the number of peers sent (or accepted) in a single message
+pub const MAX_PEX_PEERS: usize = 64;
+
+/// Encodes a peer list in a compact binary format: for each peer, a one byte address family (4
+/// or 6), then the raw IP address bytes, then the port (big-endian u16).
+pub fn encode_pex(peers: &[SocketAddr]) -> Vec<u8> {
+    let mut buf = vec![];
+    for peer in peers.iter().take(MAX_PEX_PEERS) {
+        match peer.ip() {
+            IpAddr::V4(ip) => {
+                buf.push(4);
+                buf.extend_from_slice(&ip.octets());
+            },
+            IpAddr::V6(ip) => {
+                buf.push(6);
+                buf.extend_from_slice(&ip.octets());
+            },
+        }
+        buf.push((peer.port() >> 8) as u8);
+        buf.push((peer.port() & 0xFF) as u8);
+    }
+    buf
+}
+
+/// Inverse of `encode_pex()`. Errors on any truncated or malformed entry.
+pub fn decode_pex(raw: &[u8]) -> Result<Vec<SocketAddr>> {
+    let mut peers = vec![];
+    let mut offset = 0;
+    while offset < raw.len() {
+        if peers.len() >= MAX_PEX_PEERS {
+            bail!("Too many peers in peer-exchange message");
+        }
+        let addr_len = match raw[offset] {
+            4 => 4,
+            6 => 16,
+            other => bail!("Unknown address family in peer-exchange message: {}", other),
+        };
+        offset += 1;
+        if raw.len() < offset + addr_len + 2 {
+            bail!("Truncated peer-exchange message");
+        }
+        let ip = if addr_len == 4 {
+            let mut octets = [0; 4];
+            octets.copy_from_slice(&raw[offset..(offset + 4)]);
+            IpAddr::V4(Ipv4Addr::from(octets))
+        } else {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&raw[offset..(offset + 16)]);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        };
+        offset += addr_len;
+        let port = ((raw[offset] as u16) << 8) | (raw[offset + 1] as u16);
+        offset += 2;
+        peers.push(SocketAddr::new(ip, port));
+    }
+    Ok(peers)
+}
+
+/// Helper to wrap a peer list as a network message
+pub fn pex_msg(peers: &[SocketAddr]) -> DatNetMessage {
+    DatNetMessage::Extension(Extension {
+        name: PEX_EXTENSION.to_string(),
+        payload: encode_pex(peers),
+    })
+}
+
+#[test]
+fn test_pex_roundtrip() {
+    let peers: Vec<SocketAddr> = vec![
+        "127.0.0.1:3282".parse().unwrap(),
+        "[2001:db8::1]:65535".parse().unwrap(),
+        "10.0.0.1:0".parse().unwrap(),
+    ];
+    let raw = encode_pex(&peers);
+    assert_eq!(raw.len(), 7 + 19 + 7);
+    assert_eq!(decode_pex(&raw).unwrap(), peers);
+    assert_eq!(decode_pex(&[]).unwrap(), vec![]);
+
+    assert!(decode_pex(&raw[0..5]).is_err());
+    assert!(decode_pex(&[5, 1, 2, 3, 4, 0, 1]).is_err());
+}
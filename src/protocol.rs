@@ -1,5 +1,6 @@
 
-use std::net::{TcpStream, ToSocketAddrs, Shutdown};
+use std::io;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, Shutdown};
 use std::time::Duration;
 use std::io::{Read, Write};
 use std::cmp;
@@ -8,11 +9,12 @@ use sodiumoxide::crypto::stream::*;
 use rand::{OsRng, Rng};
 use protobuf::Message;
 use protobuf::parse_from_bytes;
-use integer_encoding::{VarIntReader, VarIntWriter};
+use integer_encoding::{VarInt, VarIntReader, VarIntWriter};
 
 use errors::*;
 use network_msgs::*;
 use make_discovery_key;
+use udp_stream::UdpStream;
 
 #[derive(Debug)]
 pub enum DatNetMessage {
@@ -26,8 +28,24 @@ pub enum DatNetMessage {
     Request(Request),
     Cancel(Cancel),
     Data(Data),
+    Extension(Extension),
 }
 
+/// Protocol extension message (type 15). On the wire the extension is identified by it's index in
+/// the sender's handshake `extensions` list; here it is identified by name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Extension {
+    pub name: String,
+    pub payload: Vec<u8>,
+}
+
+/// Names of protocol extensions this implementation understands (sent in the handshake). Must be
+/// sorted.
+pub const SUPPORTED_EXTENSIONS: &[&str] = &[
+    "holepunch",
+    "peer-exchange",
+];
+
 fn msg_code(msg: &DatNetMessage) -> u8 {
     match msg {
         &DatNetMessage::Feed(_) => 0,
@@ -40,9 +58,11 @@ fn msg_code(msg: &DatNetMessage) -> u8 {
         &DatNetMessage::Request(_) => 7,
         &DatNetMessage::Cancel(_) => 8,
         &DatNetMessage::Data(_) => 9,
+        &DatNetMessage::Extension(_) => 15,
     }
 }
 
+/// Extension messages aren't protobuf, and must be handled separately.
 fn msg_sugar(msg: &DatNetMessage) -> &dyn Message {
     match msg {
         &DatNetMessage::Feed(ref m) => m,
@@ -55,6 +75,7 @@ fn msg_sugar(msg: &DatNetMessage) -> &dyn Message {
         &DatNetMessage::Request(ref m) => m,
         &DatNetMessage::Cancel(ref m) => m,
         &DatNetMessage::Data(ref m) => m,
+        &DatNetMessage::Extension(_) => panic!("Extension messages aren't protobuf"),
     }
 }
 
@@ -130,16 +151,86 @@ fn test_bsxii_continued() {
     assert_eq!(a, c);
 }
 
+/// The (unencrypted) byte stream a connection runs over
+pub enum Transport {
+    Tcp(TcpStream),
+    /// Over a hole punched UDP socket
+    Udp(UdpStream),
+}
+
+impl Transport {
+
+    pub fn try_clone(&self) -> io::Result<Transport> {
+        match *self {
+            Transport::Tcp(ref tcp) => Ok(Transport::Tcp(tcp.try_clone()?)),
+            Transport::Udp(ref udp) => Ok(Transport::Udp(udp.clone())),
+        }
+    }
+
+    pub fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        match *self {
+            Transport::Tcp(ref tcp) => tcp.set_write_timeout(dur),
+            Transport::Udp(ref udp) => {
+                udp.set_write_timeout(dur);
+                Ok(())
+            },
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match *self {
+            Transport::Tcp(ref tcp) => tcp.peer_addr(),
+            Transport::Udp(ref udp) => Ok(udp.peer_addr()),
+        }
+    }
+
+    pub fn shutdown(&self) {
+        match *self {
+            Transport::Tcp(ref tcp) => {
+                let _ = tcp.shutdown(Shutdown::Both);
+            },
+            Transport::Udp(ref udp) => udp.shutdown(),
+        }
+    }
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Transport::Tcp(ref mut tcp) => tcp.read(buf),
+            Transport::Udp(ref mut udp) => udp.read(buf),
+        }
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Transport::Tcp(ref mut tcp) => tcp.write(buf),
+            Transport::Udp(ref mut udp) => udp.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Transport::Tcp(ref mut tcp) => tcp.flush(),
+            Transport::Udp(ref mut udp) => udp.flush(),
+        }
+    }
+}
+
 /// Represents a bi-directional connection to a network peer
 ///
 /// Spec says nonce is 32 bytes, by dat implementation (hypercore-protocol) is 24 bytes.
 pub struct DatConnection {
     pub id: [u8; 32],
     remote_id: [u8; 32],
-    pub tcp: TcpStream,
+    pub transport: Transport,
     pub live: bool,
     pub key: Key,
     pub discovery_key: [u8; 32],
+    extensions: Vec<String>,
+    remote_extensions: Vec<String>,
     tx_nonce: Nonce,
     tx_offset: u64,
     rx_nonce: Nonce,
@@ -147,10 +238,10 @@ pub struct DatConnection {
 }
 
 impl Read for DatConnection {
-    /// Encrypted TCP read (after connection initialized). Uses XOR of an XSalsa20 stream, using
+    /// Encrypted read (after connection initialized). Uses XOR of an XSalsa20 stream, using
     /// block offsets.
     fn read(&mut self, buf: &mut [u8]) -> ::std::io::Result<usize> {
-        let len = self.tcp.read(buf)?;
+        let len = self.transport.read(buf)?;
         bytewise_stream_xor_ic_inplace(&mut buf[0..len], self.rx_offset, &self.rx_nonce, &self.key);
         self.rx_offset += len as u64;
 
@@ -168,11 +259,13 @@ impl Write for DatConnection {
         bytewise_stream_xor_ic_inplace(&mut enc, self.tx_offset, &self.tx_nonce, &self.key);
         self.tx_offset += enc.len() as u64;
 
-        self.tcp.write(&enc)
+        // All of it, or the stream offset gets out of sync
+        self.transport.write_all(&enc)?;
+        Ok(enc.len())
     }
 
     fn flush(&mut self) -> ::std::io::Result<()> {
-        self.tcp.flush()
+        self.transport.flush()
     }
 }
 
@@ -181,10 +274,12 @@ impl Clone for DatConnection {
         DatConnection {
             id: self.id.clone(),
             remote_id: self.remote_id.clone(),
-            tcp: self.tcp.try_clone().unwrap(),
+            transport: self.transport.try_clone().unwrap(),
             live: self.live,
             key: self.key.clone(),
             discovery_key: self.discovery_key.clone(),
+            extensions: self.extensions.clone(),
+            remote_extensions: self.remote_extensions.clone(),
             tx_nonce: self.tx_nonce.clone(),
             tx_offset: self.tx_offset,
             rx_nonce: self.rx_nonce.clone(),
@@ -207,6 +302,16 @@ impl DatConnection {
 
     // It's sort of a hack, but this should be usable from an accept() as well as a connect()
     pub fn from_tcp(tcp: TcpStream, key: &Key, live: bool, local_id: Option<&[u8]>) -> Result<DatConnection> {
+        DatConnection::from_transport(Transport::Tcp(tcp), key, live, local_id)
+    }
+
+    /// Like `from_tcp()`, over a hole punched UDP socket. Both ends do the same thing; there's no
+    /// client or server side.
+    pub fn from_udp(udp: UdpStream, key: &Key, live: bool, local_id: Option<&[u8]>) -> Result<DatConnection> {
+        DatConnection::from_transport(Transport::Udp(udp), key, live, local_id)
+    }
+
+    fn from_transport(transport: Transport, key: &Key, live: bool, local_id: Option<&[u8]>) -> Result<DatConnection> {
 
         let tx_nonce = gen_nonce();
         let mut rng = OsRng::new()?;
@@ -227,15 +332,17 @@ impl DatConnection {
         dk.copy_from_slice(&make_discovery_key(&key[0..32])[0..32]);
 
         let timeout = Duration::new(7, 0);
-        tcp.set_write_timeout(Some(timeout))?;
+        transport.set_write_timeout(Some(timeout))?;
 
         let mut dc = DatConnection {
             id: local_id,
-            tcp,
+            transport,
             live,
             remote_id: [0; 32],
             key: key.clone(),
             discovery_key: dk,
+            extensions: SUPPORTED_EXTENSIONS.iter().map(|s| s.to_string()).collect(),
+            remote_extensions: vec![],
             tx_nonce: tx_nonce,
             tx_offset: 0,
             rx_nonce: gen_nonce(), // dummy
@@ -260,6 +367,7 @@ impl DatConnection {
         let mut handshake_msg = Handshake::new();
         handshake_msg.set_live(dc.live);
         handshake_msg.set_id(dc.id.to_vec());
+        handshake_msg.set_extensions(::protobuf::RepeatedField::from_vec(dc.extensions.clone()));
         dc.send_msg(&DatNetMessage::Handshake(handshake_msg), 0)?;
 
         // read handshake
@@ -273,6 +381,7 @@ impl DatConnection {
             for i in 0..32 {
                 dc.remote_id[i] = hid[i];
             }
+            dc.remote_extensions = handshake.get_extensions().to_vec();
         } else {
             bail!("Expected Handshake message, got something else");
         }
@@ -280,9 +389,19 @@ impl DatConnection {
         Ok(dc)
     }
 
+    /// Whether both ends of this connection listed the named extension in their handshake.
+    pub fn supports_extension(&self, name: &str) -> bool {
+        self.extensions.iter().any(|e| e == name) && self.remote_extensions.iter().any(|e| e == name)
+    }
+
     /// For hyperdrive connections, `feed_index` is equivalent to a `is_content` boolean flag.
+    ///
+    /// Extension messages for extensions the remote doesn't support are silently dropped.
     pub fn send_msg(&mut self, dnm: &DatNetMessage, feed_index: u8) -> Result<()> {
         let header_int: u8 = (feed_index as u8) << 4 | (msg_code(dnm) & 0x0F);
+        if let DatNetMessage::Extension(ref ext) = *dnm {
+            return self.send_extension(ext, header_int);
+        }
         let msg: &dyn Message = msg_sugar(dnm);
         let total_message_size = (msg.compute_size() as usize) + 1;
 
@@ -309,10 +428,33 @@ impl DatConnection {
             &DatNetMessage::Request(ref m) => m.write_to_writer(self)?,
             &DatNetMessage::Cancel(ref m) => m.write_to_writer(self)?,
             &DatNetMessage::Data(ref m) => m.write_to_writer(self)?,
+            &DatNetMessage::Extension(_) => unreachable!(),
         }
         Ok(())
     }
 
+    fn send_extension(&mut self, ext: &Extension, header_int: u8) -> Result<()> {
+        if !self.supports_extension(&ext.name) {
+            debug!("not sending unsupported extension message: {}", ext.name);
+            return Ok(());
+        }
+        let ext_id = self.extensions.iter().position(|e| *e == ext.name).unwrap() as u64;
+        let total_message_size = 1 + ext_id.required_space() + ext.payload.len();
+
+        trace!(
+            "SEND total_len={}  header={}  extension={}",
+            total_message_size,
+            header_int,
+            ext.name,
+        );
+
+        self.write_varint(total_message_size as u64)?;
+        self.write_varint(header_int as u32)?;
+        self.write_varint(ext_id)?;
+        self.write_all(&ext.payload)?;
+        Ok(())
+    }
+
     /// Returns a tuple of the received message and the register index it corresponds to.
     pub fn recv_msg(&mut self) -> Result<(DatNetMessage, u8)> {
         let total_len: u64 = self.read_varint()?;
@@ -346,6 +488,16 @@ impl DatConnection {
             7 => DatNetMessage::Request(parse_from_bytes::<Request>(&mut buf)?),
             8 => DatNetMessage::Cancel(parse_from_bytes::<Cancel>(&mut buf)?),
             9 => DatNetMessage::Data(parse_from_bytes::<Data>(&mut buf)?),
+            15 => {
+                let (ext_id, inc): (u64, usize) = VarInt::decode_var(&buf);
+                if inc == 0 || ext_id >= self.remote_extensions.len() as u64 {
+                    bail!("Unknown extension id received: {}", ext_id);
+                }
+                DatNetMessage::Extension(Extension {
+                    name: self.remote_extensions[ext_id as usize].clone(),
+                    payload: buf[inc..].to_vec(),
+                })
+            },
             other => bail!("Unimplemented message type received: {}", other),
         };
         trace!("\twas: {:?}", &dnm);
@@ -368,9 +520,9 @@ impl DatConnection {
             reg
         );
 
-        self.tcp.write_varint(total_message_size as u64)?;
-        self.tcp.write_varint(header_int as u32)?;
-        reg.write_to_writer(&mut self.tcp)?;
+        self.transport.write_varint(total_message_size as u64)?;
+        self.transport.write_varint(header_int as u32)?;
+        reg.write_to_writer(&mut self.transport)?;
         Ok(())
     }
 
@@ -378,8 +530,8 @@ impl DatConnection {
     /// establishment).
     fn recv_feed(&mut self) -> Result<Feed> {
 
-        let total_len: u64 = self.tcp.read_varint()?;
-        let header: u8 = self.tcp.read_varint()?;
+        let total_len: u64 = self.transport.read_varint()?;
+        let header: u8 = self.transport.read_varint()?;
 
         if header != 0 {
             bail!("Invalid Feed header received");
@@ -389,7 +541,7 @@ impl DatConnection {
 
         let msg_len = (total_len - 1) as usize;
         let mut buf = vec![0; msg_len];
-        self.tcp.read_exact(&mut buf[0..msg_len])?;
+        self.transport.read_exact(&mut buf[0..msg_len])?;
 
         let reg = parse_from_bytes::<Feed>(&mut buf)?;
         trace!("\twas: {:?}", reg);
@@ -397,6 +549,42 @@ impl DatConnection {
     }
 
     pub fn close(&mut self) {
-        self.tcp.shutdown(Shutdown::Both);
+        self.transport.shutdown();
+    }
+}
+
+#[test]
+fn test_extension_msgs() {
+    use std::thread;
+    use std::net::TcpListener;
+
+    let key = gen_key();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let remote_key = key.clone();
+    let remote = thread::spawn(move || {
+        let (tcp, _) = listener.accept().unwrap();
+        let mut dc = DatConnection::from_tcp(tcp, &remote_key, false, None).unwrap();
+        // unsupported extensions are dropped, not sent
+        dc.send_msg(&DatNetMessage::Extension(Extension {
+            name: "not-a-real-extension".to_string(),
+            payload: vec![1, 2, 3],
+        }), 0).unwrap();
+        dc.send_msg(&DatNetMessage::Extension(Extension {
+            name: SUPPORTED_EXTENSIONS[0].to_string(),
+            payload: vec![4, 5, 6],
+        }), 0).unwrap();
+    });
+
+    let mut dc = DatConnection::connect(addr, &key, false, None).unwrap();
+    assert!(dc.supports_extension(SUPPORTED_EXTENSIONS[0]));
+    assert!(!dc.supports_extension("not-a-real-extension"));
+    match dc.recv_msg().unwrap() {
+        (DatNetMessage::Extension(ext), 0) => {
+            assert_eq!(ext.name, SUPPORTED_EXTENSIONS[0]);
+            assert_eq!(ext.payload, vec![4, 5, 6]);
+        },
+        other => panic!("unexpected message: {:?}", other),
     }
+    remote.join().unwrap();
 }
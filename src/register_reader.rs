@@ -0,0 +1,237 @@
+
+use std::io::{self, Read, Seek, SeekFrom};
+use sodiumoxide::crypto::secretbox;
+
+use errors::*;
+use sleep_register::*;
+use helpers::*;
+
+fn to_io_error(e: Error) -> io::Error {
+    io::Error::other(e.to_string())
+}
+
+/// Streaming `Read` and `Seek` access to a contiguous range of entries in a register (eg, all
+/// the blocks of a single file in a drive's content register), one chunk in memory at a time.
+///
+/// Positions are relative to the start of the range.
+pub struct RegisterReader<'a, H: HyperRegister + 'a> {
+    reg: &'a mut H,
+    start_entry: u64,
+    end_entry: u64,
+    start_byte: u64,
+    len: u64,
+    pos: u64,
+    // Set if entries were sealed with `seal_entry()`; positions are then of the decrypted data
+    key: Option<secretbox::Key>,
+    // (entry index, relative byte offset of chunk start, chunk data)
+    chunk: Option<(u64, u64, Vec<u8>)>,
+}
+
+impl<'a, H: HyperRegister> RegisterReader<'a, H> {
+
+    /// Covers `entry_count` entries, starting at `start_entry`.
+    pub fn new(reg: &'a mut H, start_entry: u64, entry_count: u64) -> Result<RegisterReader<'a, H>> {
+        let end_entry = start_entry + entry_count;
+        if end_entry > reg.len()? {
+            bail!("Entry range {}..{} is past end of register", start_entry, end_entry);
+        }
+        let start_byte = HyperRegister::get_data_offset(reg, start_entry)?;
+        let end_byte = HyperRegister::get_data_offset(reg, end_entry)?;
+        Ok(RegisterReader {
+            reg,
+            start_entry,
+            end_entry,
+            start_byte,
+            len: end_byte - start_byte,
+            pos: 0,
+            key: None,
+            chunk: None,
+        })
+    }
+
+    /// Like `new()`, but each entry was sealed with `seal_entry()` (eg, the content of a private
+    /// drive); reads, seeks, and `len()` are all in terms of the decrypted data.
+    pub fn new_sealed(reg: &'a mut H, start_entry: u64, entry_count: u64, key: &secretbox::Key) -> Result<RegisterReader<'a, H>> {
+        let mut rr = RegisterReader::new(reg, start_entry, entry_count)?;
+        let overhead = entry_count * SEALED_ENTRY_OVERHEAD;
+        if rr.len < overhead {
+            bail!("Entries {}..{} are too short to be sealed", start_entry, rr.end_entry);
+        }
+        rr.len -= overhead;
+        rr.key = Some(key.clone());
+        Ok(rr)
+    }
+
+    /// Total length of the range, in bytes
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Makes sure `self.chunk` contains the current position (which must be before the end).
+    fn load_chunk(&mut self) -> Result<()> {
+        loop {
+            let next = match self.chunk {
+                Some((_, start, ref data)) if self.pos >= start && self.pos < start + data.len() as u64 => {
+                    return Ok(());
+                },
+                // Common case: sequential reads just move on to the next entry (skipping any
+                // empty ones)
+                Some((index, start, ref data)) if self.pos == start + data.len() as u64 && index + 1 < self.end_entry => {
+                    Some((index + 1, self.pos))
+                },
+                _ => None,
+            };
+            let (index, start) = match next {
+                Some(v) => v,
+                None => self.find_entry()?,
+            };
+            let data = self.reg.get_data_entry(index)?;
+            let data = match self.key {
+                Some(ref key) => open_entry(key, index, &data)?,
+                None => data,
+            };
+            self.chunk = Some((index, start, data));
+        }
+    }
+
+    /// Finds the entry containing the current position; returns (entry index, relative byte
+    /// offset of chunk start).
+    fn find_entry(&mut self) -> Result<(u64, u64)> {
+        if self.key.is_none() {
+            return match HyperRegister::find_data_entry(self.reg, self.start_byte + self.pos)? {
+                Some((index, abs_start)) => Ok((index, abs_start - self.start_byte)),
+                None => bail!("Read position past end of register"),
+            };
+        }
+        // Register offsets are of the sealed entries, so binary search by decrypted offset
+        // instead: the last entry starting at or before the position
+        let (mut low, mut high) = (self.start_entry, self.end_entry);
+        while high - low > 1 {
+            let mid = low + (high - low) / 2;
+            if self.sealed_entry_start(mid)? <= self.pos {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+        Ok((low, self.sealed_entry_start(low)?))
+    }
+
+    /// Relative (decrypted) byte offset of the start of a sealed entry
+    fn sealed_entry_start(&mut self, index: u64) -> Result<u64> {
+        let abs_start = HyperRegister::get_data_offset(self.reg, index)?;
+        Ok(abs_start - self.start_byte - (index - self.start_entry) * SEALED_ENTRY_OVERHEAD)
+    }
+}
+
+impl<'a, H: HyperRegister> Read for RegisterReader<'a, H> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        self.load_chunk().map_err(to_io_error)?;
+        let (_, start, ref data) = *self.chunk.as_ref().unwrap();
+        let from = (self.pos - start) as usize;
+        let count = buf.len().min(data.len() - from).min((self.len - self.pos) as usize);
+        buf[..count].copy_from_slice(&data[from..(from + count)]);
+        self.pos += count as u64;
+        Ok(count)
+    }
+}
+
+impl<'a, H: HyperRegister> Seek for RegisterReader<'a, H> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.len.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        match new_pos {
+            Some(p) => {
+                self.pos = p;
+                Ok(p)
+            },
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position")),
+        }
+    }
+}
+
+#[test]
+fn test_register_reader() {
+    let mut mr = MemoryRegister::new().unwrap();
+    let mut all = vec![];
+    for i in 0..23u8 {
+        let chunk: Vec<u8> = (0..(i as usize * 7 % 11)).map(|j| i.wrapping_mul(31).wrapping_add(j as u8)).collect();
+        mr.append(&chunk).unwrap();
+        all.extend_from_slice(&chunk);
+    }
+    for offset in 0..(all.len() as u64) {
+        let (index, start) = HyperRegister::find_data_entry(&mut mr, offset).unwrap().unwrap();
+        assert!(start <= offset);
+        assert!(offset < start + mr.get_data_entry(index).unwrap().len() as u64);
+    }
+    assert_eq!(HyperRegister::find_data_entry(&mut mr, all.len() as u64).unwrap(), None);
+
+    // Whole register
+    let mut buf = vec![];
+    RegisterReader::new(&mut mr, 0, 23).unwrap().read_to_end(&mut buf).unwrap();
+    assert_eq!(buf, all);
+
+    // A sub-range, with seeking
+    let start = HyperRegister::get_data_offset(&mut mr, 5).unwrap() as usize;
+    let end = HyperRegister::get_data_offset(&mut mr, 17).unwrap() as usize;
+    let mut rr = RegisterReader::new(&mut mr, 5, 12).unwrap();
+    assert_eq!(rr.len(), (end - start) as u64);
+    assert_eq!(rr.seek(SeekFrom::Start(13)).unwrap(), 13);
+    let mut buf = [0; 20];
+    rr.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf[..], &all[(start + 13)..(start + 33)]);
+    rr.seek(SeekFrom::End(-4)).unwrap();
+    let mut buf = vec![];
+    rr.read_to_end(&mut buf).unwrap();
+    assert_eq!(&buf[..], &all[(end - 4)..end]);
+    rr.seek(SeekFrom::Current(-10)).unwrap();
+    let mut buf = [0; 3];
+    rr.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf[..], &all[(end - 10)..(end - 7)]);
+    assert!(rr.seek(SeekFrom::Current(-100)).is_err());
+    rr.seek(SeekFrom::Start(1000)).unwrap();
+    assert_eq!(rr.read(&mut [0; 3]).unwrap(), 0);
+    assert!(RegisterReader::new(&mut mr, 20, 4).is_err());
+}
+
+#[test]
+fn test_register_reader_sealed() {
+    let key = secretbox::gen_key();
+    let mut mr = MemoryRegister::new().unwrap();
+    mr.append(b"not sealed").unwrap();
+    let mut all = vec![];
+    for i in 1..20u8 {
+        let chunk: Vec<u8> = (0..(i as usize * 5 % 9)).map(|j| i.wrapping_add(j as u8)).collect();
+        mr.append(&seal_entry(&key, i as u64, &chunk)).unwrap();
+        all.extend_from_slice(&chunk);
+    }
+    let mut rr = RegisterReader::new_sealed(&mut mr, 1, 19, &key).unwrap();
+    assert_eq!(rr.len(), all.len() as u64);
+    let mut buf = vec![];
+    rr.read_to_end(&mut buf).unwrap();
+    assert_eq!(buf, all);
+    for offset in (0..all.len()).rev() {
+        rr.seek(SeekFrom::Start(offset as u64)).unwrap();
+        let mut buf = [0; 1];
+        rr.read_exact(&mut buf).unwrap();
+        assert_eq!(buf[0], all[offset]);
+    }
+
+    // Wrong key, or entries sealed for other positions
+    let mut buf = vec![];
+    assert!(RegisterReader::new_sealed(&mut mr, 1, 19, &secretbox::gen_key()).unwrap().read_to_end(&mut buf).is_err());
+    mr.append(&seal_entry(&key, 3, b"moved")).unwrap();
+    let mut rr = RegisterReader::new_sealed(&mut mr, 20, 1, &key).unwrap();
+    assert!(rr.read_to_end(&mut buf).is_err());
+}
@@ -0,0 +1,207 @@
+
+use errors::*;
+use std::io;
+use std::io::{Read, Write};
+use std::thread;
+use std::collections::HashMap;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use sodiumoxide::crypto::stream::Key;
+use protocol::DatConnection;
+use make_discovery_key;
+
+/// Prefix of the relay request, sent by clients right after connecting
+const RELAY_MAGIC: &[u8] = b"GZRL";
+const ROLE_LISTEN: u8 = 0;
+const ROLE_CONNECT: u8 = 1;
+const STATUS_NO_PEER: u8 = 0;
+const STATUS_PAIRED: u8 = 1;
+
+/// Cap on the number of listeners waiting (per discovery key) at a relay
+const MAX_WAITING_PER_KEY: usize = 16;
+
+type WaitingMap = Arc<Mutex<HashMap<[u8; 32], Vec<TcpStream>>>>;
+
+/// Forwards raw byte streams between pairs of peers that can't connect to each other directly.
+///
+/// Peers willing to accept connections (eg, seeds behind a firewall) register as listeners for a
+/// discovery key and wait. Peers wanting to connect name the same discovery key, are paired with a
+/// waiting listener, and from then on the relay just copies bytes in both directions. The dat
+/// handshake and stream encryption happen end-to-end on top of that, so the relay only ever sees
+/// the discovery key, never the content (or the public key).
+pub struct RelayServer {
+    listener: TcpListener,
+    waiting: WaitingMap,
+}
+
+impl RelayServer {
+
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<RelayServer> {
+        Ok(RelayServer {
+            listener: TcpListener::bind(addr)?,
+            waiting: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Accepts and relays connections forever (or until a listener error). Each client gets a
+    /// thread.
+    pub fn run(&mut self) -> Result<()> {
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let waiting = self.waiting.clone();
+            thread::spawn(move || {
+                if let Err(e) = handle_client(stream, waiting) {
+                    debug!("relay client error: {}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+fn handle_client(mut stream: TcpStream, waiting: WaitingMap) -> Result<()> {
+
+    stream.set_read_timeout(Some(Duration::new(7, 0)))?;
+    let mut request = [0; 37];
+    stream.read_exact(&mut request)?;
+    stream.set_read_timeout(None)?;
+    if &request[0..4] != RELAY_MAGIC {
+        bail!("Not a relay request");
+    }
+    let mut dk = [0; 32];
+    dk.copy_from_slice(&request[5..37]);
+
+    match request[4] {
+        ROLE_LISTEN => {
+            let mut waiting = waiting.lock().unwrap();
+            let listeners = waiting.entry(dk).or_default();
+            if listeners.len() >= MAX_WAITING_PER_KEY {
+                stream.write_all(&[STATUS_NO_PEER])?;
+                bail!("Too many relay listeners for one key");
+            }
+            listeners.push(stream);
+            Ok(())
+        },
+        ROLE_CONNECT => {
+            // TODO: listeners which have since hung up are only noticed once paired
+            let listener = {
+                let mut waiting = waiting.lock().unwrap();
+                let listener = waiting.get_mut(&dk).and_then(|l| l.pop());
+                if waiting.get(&dk).map(|l| l.is_empty()).unwrap_or(false) {
+                    waiting.remove(&dk);
+                }
+                listener
+            };
+            let mut listener = match listener {
+                Some(l) => l,
+                None => {
+                    stream.write_all(&[STATUS_NO_PEER])?;
+                    bail!("No relay listener for requested key");
+                },
+            };
+            listener.write_all(&[STATUS_PAIRED])?;
+            stream.write_all(&[STATUS_PAIRED])?;
+            splice(stream, listener)
+        },
+        other => bail!("Unknown relay role: {}", other),
+    }
+}
+
+/// Copies bytes in both directions until either side hangs up
+fn splice(a: TcpStream, b: TcpStream) -> Result<()> {
+    let mut a_rx = a.try_clone()?;
+    let mut b_tx = b.try_clone()?;
+    let forward = thread::spawn(move || {
+        let _ = io::copy(&mut a_rx, &mut b_tx);
+        let _ = b_tx.shutdown(Shutdown::Write);
+    });
+    let (mut a_tx, mut b_rx) = (a, b);
+    let _ = io::copy(&mut b_rx, &mut a_tx);
+    let _ = a_tx.shutdown(Shutdown::Write);
+    forward.join().unwrap();
+    Ok(())
+}
+
+fn relay_request(relay: SocketAddr, discovery_key: &[u8], role: u8) -> Result<TcpStream> {
+    if discovery_key.len() != 32 {
+        bail!("Discovery key must be 32 bytes");
+    }
+    let mut stream = TcpStream::connect(relay)?;
+    let mut request = RELAY_MAGIC.to_vec();
+    request.push(role);
+    request.extend_from_slice(discovery_key);
+    stream.write_all(&request)?;
+    let mut status = [0; 1];
+    stream.read_exact(&mut status)?;
+    if status[0] != STATUS_PAIRED {
+        bail!("Relay {} has no peer for this key", relay);
+    }
+    Ok(stream)
+}
+
+/// Registers at a relay as a listener for the given discovery key, and blocks until a connecting
+/// peer is paired with us. The returned stream is ready for `DatConnection::from_tcp()`.
+pub fn relay_listen(relay: SocketAddr, discovery_key: &[u8]) -> Result<TcpStream> {
+    relay_request(relay, discovery_key, ROLE_LISTEN)
+}
+
+/// Asks a relay for a stream to any peer listening for the given discovery key. Fails right away
+/// if there is no such peer.
+pub fn relay_connect(relay: SocketAddr, discovery_key: &[u8]) -> Result<TcpStream> {
+    relay_request(relay, discovery_key, ROLE_CONNECT)
+}
+
+/// Like `DatConnection::connect()`, but through a relay.
+pub fn connect_via_relay(relay: SocketAddr, key: &Key, live: bool, local_id: Option<&[u8]>) -> Result<DatConnection> {
+    info!("Connecting via relay {}", relay);
+    let tcp = relay_connect(relay, &make_discovery_key(&key[0..32]))?;
+    DatConnection::from_tcp(tcp, key, live, local_id)
+}
+
+#[test]
+fn test_relay_connection() {
+    use protocol::DatNetMessage;
+    use network_msgs::Want;
+
+    let mut server = RelayServer::bind("127.0.0.1:0").unwrap();
+    let relay = server.local_addr().unwrap();
+    thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let key = Key::from_slice(&[5; 32]).unwrap();
+    let dk = make_discovery_key(&key[0..32]);
+
+    // Nobody listening yet
+    assert!(connect_via_relay(relay, &key, false, None).is_err());
+
+    let seed_key = key.clone();
+    let seed = thread::spawn(move || {
+        let tcp = relay_listen(relay, &dk).unwrap();
+        let mut dc = DatConnection::from_tcp(tcp, &seed_key, false, None).unwrap();
+        match dc.recv_msg().unwrap() {
+            (DatNetMessage::Want(want), 0) => assert_eq!(want.get_start(), 12),
+            _ => panic!("expected a Want message"),
+        }
+    });
+
+    // Wait for the seed to register
+    let mut dc = None;
+    for _ in 0..50 {
+        if let Ok(c) = connect_via_relay(relay, &key, false, None) {
+            dc = Some(c);
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    let mut dc = dc.expect("relayed connection");
+    let mut want = Want::new();
+    want.set_start(12);
+    dc.send_msg(&DatNetMessage::Want(want), 0).unwrap();
+    seed.join().unwrap();
+}
@@ -0,0 +1,125 @@
+
+use lru::LruCache;
+
+use errors::*;
+use sleep_file::*;
+
+/// Counts of reads served from (hits) or past (misses) a cache
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Wraps any SleepStorage with a bounded LRU cache of entries. Reads are served from the cache
+/// when possible; writes go through to the underlying storage and update the cache.
+///
+/// Mostly useful for tree files, where the same nodes (eg, roots) get read over and over.
+pub struct CachedSleepStorage<S: SleepStorage> {
+    inner: S,
+    cache: LruCache<u64, Vec<u8>>,
+    stats: CacheStats,
+}
+
+impl<S: SleepStorage> CachedSleepStorage<S> {
+
+    /// `capacity` is a count of entries, not bytes, and must be non-zero.
+    pub fn new(inner: S, capacity: usize) -> CachedSleepStorage<S> {
+        assert!(capacity > 0);
+        CachedSleepStorage {
+            inner,
+            cache: LruCache::new(capacity),
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = CacheStats::default();
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: SleepStorage> SleepStorage for CachedSleepStorage<S> {
+    fn get_magic(&self) -> u32 {
+        self.inner.get_magic()
+    }
+    fn get_algorithm(&self) -> Option<String> {
+        self.inner.get_algorithm()
+    }
+    fn get_entry_size(&self) -> u16 {
+        self.inner.get_entry_size()
+    }
+
+    fn read(&mut self, index: u64) -> Result<Vec<u8>> {
+        if let Some(entry) = self.cache.get(&index) {
+            self.stats.hits += 1;
+            return Ok(entry.clone());
+        }
+        self.stats.misses += 1;
+        let entry = self.inner.read(index)?;
+        self.cache.put(index, entry.clone());
+        Ok(entry)
+    }
+
+    fn write(&mut self, index: u64, data: &[u8]) -> Result<()> {
+        self.inner.write(index, data)?;
+        self.cache.put(index, data.to_vec());
+        Ok(())
+    }
+
+    fn append(&mut self, data: &[u8]) -> Result<()> {
+        let index = self.inner.len()?;
+        self.write(index, data)
+    }
+
+    fn len(&self) -> Result<u64> {
+        self.inner.len()
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.inner.sync()
+    }
+}
+
+#[test]
+fn test_cached_sleep_storage() {
+    let mut inner = MemorySleepFile::new(0x05025702, 40, None).unwrap();
+    for i in 0..10 {
+        inner.append(&[i; 40]).unwrap();
+    }
+    let mut cs = CachedSleepStorage::new(inner, 4);
+    assert_eq!(cs.len().unwrap(), 10);
+
+    assert_eq!(cs.read(0).unwrap(), vec![0; 40]);
+    assert_eq!(cs.read(0).unwrap(), vec![0; 40]);
+    assert_eq!(cs.stats(), CacheStats { hits: 1, misses: 1 });
+
+    // Evict entry 0
+    for i in 1..5 {
+        cs.read(i).unwrap();
+    }
+    cs.read(0).unwrap();
+    assert_eq!(cs.stats(), CacheStats { hits: 1, misses: 6 });
+
+    // Writes go through, and are cached
+    cs.write(3, &[33; 40]).unwrap();
+    cs.append(&[44; 40]).unwrap();
+    cs.reset_stats();
+    assert_eq!(cs.read(3).unwrap(), vec![33; 40]);
+    assert_eq!(cs.read(10).unwrap(), vec![44; 40]);
+    assert_eq!(cs.stats(), CacheStats { hits: 2, misses: 0 });
+    let mut inner = cs.into_inner();
+    assert_eq!(inner.read(3).unwrap(), vec![33; 40]);
+    assert_eq!(inner.len().unwrap(), 11);
+}
@@ -1,10 +1,13 @@
 
 use std::io::prelude::*;
+use std::io::SeekFrom;
 use std::os::unix::fs::FileExt;
 use std::path::Path;
 use std::fs::File;
 use integer_encoding::FixedInt;
 use std::fs::OpenOptions;
+use std::cmp;
+use memmap::{Mmap, MmapOptions};
 
 use errors::*;
 
@@ -36,12 +39,196 @@ pub trait SleepStorage {
     /// Returns the count of entries, meaning the highest index entry plus one (not necessarily the
     /// number of entries which have actually been written).
     fn len(&self) -> Result<u64>;
+
+    /// Reads `count` contiguous entries, starting at the given entry index, returned concatenated.
+    /// Back-ends should override this to do a single read.
+    fn read_range(&mut self, start: u64, count: u64) -> Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity((count as usize) * (self.get_entry_size() as usize));
+        for i in start..(start + count) {
+            buf.append(&mut self.read(i)?);
+        }
+        Ok(buf)
+    }
+
+    /// Writes contiguous entries (concatenated in `data`, which must be a multiple of entry size
+    /// long), starting at the given entry index. Back-ends should override this to do a single
+    /// write.
+    fn write_range(&mut self, start: u64, data: &[u8]) -> Result<()> {
+        let entry_size = self.get_entry_size() as usize;
+        if !data.len().is_multiple_of(entry_size) {
+            bail!("Tried to write mis-sized data");
+        }
+        for (i, entry) in data.chunks(entry_size).enumerate() {
+            self.write(start + i as u64, entry)?;
+        }
+        Ok(())
+    }
+
+    /// Flushes any writes to durable storage (if there is such a thing).
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Iterator over all entries, in order. Reads in batches under the hood.
+    fn entries(&mut self) -> SleepEntries<'_, Self> where Self: Sized {
+        SleepEntries {
+            storage: self,
+            next: 0,
+            batch: vec![],
+            batch_start: 0,
+        }
+    }
+}
+
+/// Number of entries read at a time by `SleepEntries`
+const ENTRIES_BATCH: u64 = 1024;
+
+/// Iterator returned by `SleepStorage::entries()`
+pub struct SleepEntries<'a, S: SleepStorage + 'a> {
+    storage: &'a mut S,
+    next: u64,
+    batch: Vec<u8>,
+    batch_start: u64,
+}
+
+impl<'a, S: SleepStorage> Iterator for SleepEntries<'a, S> {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Result<Vec<u8>>> {
+        let entry_size = self.storage.get_entry_size() as usize;
+        let len = match self.storage.len() {
+            Ok(len) => len,
+            Err(e) => return Some(Err(e)),
+        };
+        if self.next >= len {
+            return None;
+        }
+        let batch_count = (self.batch.len() / entry_size) as u64;
+        if self.next >= self.batch_start + batch_count {
+            let count = ::std::cmp::min(ENTRIES_BATCH, len - self.next);
+            self.batch = match self.storage.read_range(self.next, count) {
+                Ok(b) => b,
+                Err(e) => {
+                    // Don't keep returning the same error
+                    self.next = len;
+                    return Some(Err(e));
+                },
+            };
+            self.batch_start = self.next;
+        }
+        let offset = ((self.next - self.batch_start) as usize) * entry_size;
+        self.next += 1;
+        Some(Ok(self.batch[offset..(offset + entry_size)].to_vec()))
+    }
+}
+
+/// Abstract access to a register's data (the concatenation of all appended entries).
+pub trait DataStorage {
+    /// Fills `buf` with bytes starting at `offset`; it's an error if there aren't enough.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()>;
+
+    /// Writes data at the end.
+    fn append(&mut self, data: &[u8]) -> Result<()>;
+
+    /// Overwrites existing bytes starting at `offset`.
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<()>;
+
+    /// Drops `len` bytes starting at `offset`; the size stays the same. The default zeros them.
+    fn clear(&mut self, offset: u64, len: u64) -> Result<()> {
+        let zeros = vec![0; cmp::min(len, 65536) as usize];
+        let mut done = 0;
+        while done < len {
+            let count = cmp::min(len - done, zeros.len() as u64);
+            self.write_at(offset + done, &zeros[0..(count as usize)])?;
+            done += count;
+        }
+        Ok(())
+    }
+
+    /// Total size in bytes.
+    fn len(&self) -> Result<u64>;
+
+    /// Whether there's no data at all.
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Flushes any written data to durable storage (if there is such a thing).
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl DataStorage for File {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let got = FileExt::read_at(self, buf, offset)?;
+        if got != buf.len() {
+            bail!("Short file read");
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, data: &[u8]) -> Result<()> {
+        self.seek(SeekFrom::End(0))?;
+        self.write_all(data)?;
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        if offset + data.len() as u64 > DataStorage::len(self)? {
+            bail!("Tried to write past end of data file");
+        }
+        self.write_all_at(data, offset)?;
+        Ok(())
+    }
+
+    fn len(&self) -> Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.sync_data()?;
+        Ok(())
+    }
+}
+
+impl DataStorage for Vec<u8> {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let offset = offset as usize;
+        if offset + buf.len() > self.len() {
+            bail!("Short data read");
+        }
+        buf.copy_from_slice(&self[offset..(offset + buf.len())]);
+        Ok(())
+    }
+
+    fn append(&mut self, data: &[u8]) -> Result<()> {
+        self.extend_from_slice(data);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        let offset = offset as usize;
+        if offset + data.len() > self.len() {
+            bail!("Tried to write past end of data");
+        }
+        self[offset..(offset + data.len())].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn len(&self) -> Result<u64> {
+        Ok(Vec::len(self) as u64)
+    }
 }
 
 /// Local File implementation of SleepStorage
+///
+/// The entry count is read from the file system once, when opened, and tracked in memory after
+/// that; writing to `file` directly will confuse it.
 #[derive(Debug)]
 pub struct SleepFile {
     pub file: File,
+    len: u64,
     magic: u32,
     entry_size: u16,
     // Option isn't necessary here... idiomatic?
@@ -73,14 +260,15 @@ impl SleepFile {
         } else {
             Some(String::from_utf8_lossy(&header[8..(8 + (algo_len as usize))]).into_owned())
         };
-        let sf = SleepFile {
+        let mut sf = SleepFile {
             file: f,
+            len: 0,
             magic: u32::from_be(FixedInt::decode_fixed(&header[0..4])),
             entry_size: u16::from_be(FixedInt::decode_fixed(&header[5..7])),
             algorithm_name: algorithm_name,
         };
-        // call length for consistency checks
-        sf.len()?;
+        // also does consistency checks
+        sf.len = sf.file_len()?;
         Ok(sf)
     }
 
@@ -115,11 +303,53 @@ impl SleepFile {
         f.write_all(&header)?;
         Ok(SleepFile {
             file: f,
+            len: 0,
             magic: magic,
             entry_size: entry_size,
             algorithm_name: algo,
         })
     }
+
+    /// Opens (writable) after truncating any partially-written entry off the end of the file,
+    /// as could be left behind by a crash. Returns the number of bytes dropped.
+    pub fn open_repair(path: &Path) -> Result<(SleepFile, u64)> {
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(false)
+            .open(path)?;
+        let mut header = [0; 32];
+        f.read_exact_at(&mut header, 0)?;
+        let entry_size = u16::from_be(FixedInt::decode_fixed(&header[5..7])) as u64;
+        if entry_size == 0 {
+            bail!("Invalid SLEEP header: entry_size can't be zero");
+        }
+        let length = f.metadata()?.len();
+        let partial = (length - 32) % entry_size;
+        if partial != 0 {
+            f.set_len(length - partial)?;
+        }
+        Ok((SleepFile::open(path, true)?, partial))
+    }
+
+    /// Drops all entries from `count` onwards.
+    pub fn truncate(&mut self, count: u64) -> Result<()> {
+        if count > self.len {
+            bail!("Can't truncate SLEEP file to beyond its end");
+        }
+        self.file.set_len(32 + count * (self.entry_size as u64))?;
+        self.len = count;
+        Ok(())
+    }
+
+    /// Entry count, as calculated from the actual file size
+    fn file_len(&self) -> Result<u64> {
+        let length = self.file.metadata()?.len();
+        if length < 32 || (length - 32) % (self.entry_size as u64) != 0 {
+            bail!("Bad SLEEP file: missing header or not multiple of entry_size");
+        }
+        Ok((length - 32) / (self.entry_size as u64))
+    }
 }
 
 impl SleepStorage for SleepFile {
@@ -155,6 +385,247 @@ impl SleepStorage for SleepFile {
         if put != data.len() {
             bail!("Short file write");
         }
+        if index + 1 > self.len {
+            self.len = index + 1;
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, data: &[u8]) -> Result<()> {
+        let index = self.len;
+        self.write(index, data)
+    }
+
+    fn len(&self) -> Result<u64> {
+        Ok(self.len)
+    }
+
+    fn read_range(&mut self, start: u64, count: u64) -> Result<Vec<u8>> {
+        let entry_size = self.entry_size as u64;
+        if start + count > self.len {
+            bail!("Tried to read beyond end of SLEEP file");
+        }
+        let mut buf = vec![0; (count * entry_size) as usize];
+        self.file.read_exact_at(&mut buf, 32 + entry_size * start)?;
+        Ok(buf)
+    }
+
+    fn write_range(&mut self, start: u64, data: &[u8]) -> Result<()> {
+        let entry_size = self.entry_size as u64;
+        if !(data.len() as u64).is_multiple_of(entry_size) {
+            bail!("Tried to write mis-sized data");
+        }
+        self.file.write_all_at(data, 32 + entry_size * start)?;
+        let end = start + (data.len() as u64 / entry_size);
+        if end > self.len {
+            self.len = end;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
+
+/// Memory-mapped (local file) implementation of SleepStorage.
+///
+/// Reads come straight out of the mapping, without any syscalls or copying (see `read_slice()`).
+/// Writes go through `pwrite()`, which on Linux is coherent with shared mappings; the mapping is
+/// only re-created (grown) when a read goes beyond it. The entry count is tracked in memory
+/// instead of calling `fstat()`.
+#[derive(Debug)]
+pub struct MmapSleepFile {
+    file: File,
+    map: Option<Mmap>,
+    len: u64,
+    magic: u32,
+    entry_size: u16,
+    algorithm_name: Option<String>,
+}
+
+impl MmapSleepFile {
+
+    pub fn open(path: &Path, writable: bool) -> Result<MmapSleepFile> {
+        MmapSleepFile::from_sleep_file(SleepFile::open(path, writable)?)
+    }
+
+    /// This function will *not* allow overwriting an existing file.
+    pub fn create(path: &Path, magic: u32, entry_size: u16, algo: Option<String>) -> Result<MmapSleepFile> {
+        MmapSleepFile::from_sleep_file(SleepFile::create(path, magic, entry_size, algo)?)
+    }
+
+    pub fn from_sleep_file(sf: SleepFile) -> Result<MmapSleepFile> {
+        let mut msf = MmapSleepFile {
+            len: sf.len()?,
+            magic: sf.get_magic(),
+            entry_size: sf.get_entry_size(),
+            algorithm_name: sf.get_algorithm(),
+            file: sf.file,
+            map: None,
+        };
+        msf.remap()?;
+        Ok(msf)
+    }
+
+    /// (Re-)maps the entire current file, header included.
+    fn remap(&mut self) -> Result<()> {
+        let file_len = 32 + self.len * (self.entry_size as u64);
+        // Zero-length mappings aren't allowed, and there is nothing to read anyways
+        self.map = if self.len == 0 {
+            None
+        } else {
+            Some(unsafe { MmapOptions::new().len(file_len as usize).map(&self.file)? })
+        };
+        Ok(())
+    }
+
+    /// Like `read()`, but returns a slice borrowed from the mapping instead of a copy.
+    pub fn read_slice(&mut self, index: u64) -> Result<&[u8]> {
+        let entry_size = self.entry_size as usize;
+        if index + 1 > self.len {
+            bail!("Tried to read beyond end of SLEEP file");
+        }
+        let offset = 32 + entry_size * (index as usize);
+        let mapped_len = self.map.as_ref().map(|m| m.len()).unwrap_or(0);
+        if offset + entry_size > mapped_len {
+            self.remap()?;
+        }
+        let map = self.map.as_ref().unwrap();
+        Ok(&map[offset..(offset + entry_size)])
+    }
+}
+
+impl SleepStorage for MmapSleepFile {
+    fn get_magic(&self) -> u32 {
+        self.magic
+    }
+    fn get_algorithm(&self) -> Option<String> {
+        self.algorithm_name.clone()
+    }
+    fn get_entry_size(&self) -> u16 {
+        self.entry_size
+    }
+
+    fn read(&mut self, index: u64) -> Result<Vec<u8>> {
+        Ok(self.read_slice(index)?.to_vec())
+    }
+
+    fn write(&mut self, index: u64, data: &[u8]) -> Result<()> {
+        if data.len() != self.entry_size as usize {
+            bail!("Tried to write mis-sized data");
+        }
+        let put = self.file.write_at(data, 32 + (self.entry_size as u64) * index)?;
+        if put != data.len() {
+            bail!("Short file write");
+        }
+        if index + 1 > self.len {
+            self.len = index + 1;
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, data: &[u8]) -> Result<()> {
+        let index = self.len;
+        self.write(index, data)
+    }
+
+    fn len(&self) -> Result<u64> {
+        Ok(self.len)
+    }
+
+    fn read_range(&mut self, start: u64, count: u64) -> Result<Vec<u8>> {
+        if count == 0 {
+            return Ok(vec![]);
+        }
+        let entry_size = self.entry_size as usize;
+        // Make sure the mapping covers the last entry
+        self.read_slice(start + count - 1)?;
+        let offset = 32 + entry_size * (start as usize);
+        let map = self.map.as_ref().unwrap();
+        Ok(map[offset..(offset + entry_size * (count as usize))].to_vec())
+    }
+
+    fn write_range(&mut self, start: u64, data: &[u8]) -> Result<()> {
+        let entry_size = self.entry_size as u64;
+        if !(data.len() as u64).is_multiple_of(entry_size) {
+            bail!("Tried to write mis-sized data");
+        }
+        self.file.write_all_at(data, 32 + entry_size * start)?;
+        let end = start + (data.len() as u64 / entry_size);
+        if end > self.len {
+            self.len = end;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
+
+/// In-memory implementation of SleepStorage. Entries are kept contiguously in a single buffer
+/// (without the 32-byte header).
+#[derive(Debug, Clone)]
+pub struct MemorySleepFile {
+    data: Vec<u8>,
+    magic: u32,
+    entry_size: u16,
+    algorithm_name: Option<String>,
+}
+
+impl MemorySleepFile {
+    pub fn new(magic: u32, entry_size: u16, algo: Option<String>) -> Result<MemorySleepFile> {
+        if let Some(ref name) = algo {
+            if name.len() > 24 {
+                bail!("Algorithm name must be 24 bytes at most");
+            }
+        }
+        if entry_size == 0 {
+            bail!("SLEEP entry size must be non-zero");
+        }
+        Ok(MemorySleepFile {
+            data: vec![],
+            magic,
+            entry_size,
+            algorithm_name: algo,
+        })
+    }
+}
+
+impl SleepStorage for MemorySleepFile {
+    fn get_magic(&self) -> u32 {
+        self.magic
+    }
+    fn get_algorithm(&self) -> Option<String> {
+        self.algorithm_name.clone()
+    }
+    fn get_entry_size(&self) -> u16 {
+        self.entry_size
+    }
+
+    fn read(&mut self, index: u64) -> Result<Vec<u8>> {
+        let entry_size = self.entry_size as usize;
+        if index + 1 > self.len()? {
+            bail!("Tried to read beyond end of SLEEP file");
+        }
+        let offset = entry_size * (index as usize);
+        Ok(self.data[offset..(offset + entry_size)].to_vec())
+    }
+
+    fn write(&mut self, index: u64, data: &[u8]) -> Result<()> {
+        let entry_size = self.entry_size as usize;
+        if data.len() != entry_size {
+            bail!("Tried to write mis-sized data");
+        }
+        let offset = entry_size * (index as usize);
+        // Like a sparse file, writing past the end fills the gap with zeros
+        if self.data.len() < offset + entry_size {
+            self.data.resize(offset + entry_size, 0);
+        }
+        self.data[offset..(offset + entry_size)].copy_from_slice(data);
         Ok(())
     }
 
@@ -164,11 +635,16 @@ impl SleepStorage for SleepFile {
     }
 
     fn len(&self) -> Result<u64> {
-        let length = self.file.metadata()?.len();
-        if length < 32 || (length - 32) % (self.entry_size as u64) != 0 {
-            bail!("Bad SLEEP file: missing header or not multiple of entry_size");
+        Ok((self.data.len() / (self.entry_size as usize)) as u64)
+    }
+
+    fn read_range(&mut self, start: u64, count: u64) -> Result<Vec<u8>> {
+        let entry_size = self.entry_size as usize;
+        if start + count > self.len()? {
+            bail!("Tried to read beyond end of SLEEP file");
         }
-        return Ok((length - 32) / (self.entry_size as u64));
+        let offset = entry_size * (start as usize);
+        Ok(self.data[offset..(offset + entry_size * (count as usize))].to_vec())
     }
 }
 
@@ -206,3 +682,105 @@ fn test_sleep_create() {
         Some("BLAKE2b".into()),
     ).unwrap();
 }
+
+#[test]
+fn test_memory_sleep_file() {
+    let mut msf = MemorySleepFile::new(0x05025702, 40, Some("BLAKE2b".into())).unwrap();
+    assert_eq!(msf.len().unwrap(), 0);
+    assert_eq!(msf.get_magic(), 0x05025702);
+    assert_eq!(msf.get_algorithm(), Some("BLAKE2b".to_string()));
+    assert!(msf.read(0).is_err());
+
+    msf.append(&[1; 40]).unwrap();
+    msf.write(2, &[3; 40]).unwrap();
+    assert_eq!(msf.len().unwrap(), 3);
+    assert_eq!(msf.read(0).unwrap(), vec![1; 40]);
+    assert_eq!(msf.read(1).unwrap(), vec![0; 40]);
+    assert_eq!(msf.read(2).unwrap(), vec![3; 40]);
+    assert!(msf.write(1, &[2; 39]).is_err());
+
+    assert!(MemorySleepFile::new(0x05025700, 1, Some("this name is much too long!".into())).is_err());
+}
+
+#[test]
+fn test_mmap_sleep_file() {
+    use tempdir::TempDir;
+    let tmp_dir = TempDir::new("geniza-test").unwrap();
+
+    let sf = MmapSleepFile::open(Path::new("test-data/dat/simple/.dat/metadata.tree"), false).unwrap();
+    assert_eq!(sf.len().unwrap(), 5);
+    assert_eq!(sf.get_algorithm(), Some("BLAKE2b".to_string()));
+    let mut plain = SleepFile::open(Path::new("test-data/dat/simple/.dat/metadata.tree"), false).unwrap();
+    let mut sf = sf;
+    for i in 0..5 {
+        assert_eq!(sf.read_slice(i).unwrap(), &plain.read(i).unwrap()[..]);
+    }
+    assert!(sf.read(5).is_err());
+
+    let path = tmp_dir.path().join("mmap.sleep");
+    let mut sf = MmapSleepFile::create(&path, 0x05025702, 40, Some("BLAKE2b".into())).unwrap();
+    assert_eq!(sf.len().unwrap(), 0);
+    for i in 0..100 {
+        sf.append(&[i as u8; 40]).unwrap();
+        // read back right away, forcing the mapping to grow
+        assert_eq!(sf.read_slice(i).unwrap(), &[i as u8; 40][..]);
+    }
+    sf.write(3, &[0xFF; 40]).unwrap();
+    assert_eq!(sf.read(3).unwrap(), vec![0xFF; 40]);
+    assert_eq!(sf.len().unwrap(), 100);
+    drop(sf);
+    let mut plain = SleepFile::open(&path, false).unwrap();
+    assert_eq!(plain.len().unwrap(), 100);
+    assert_eq!(plain.read(99).unwrap(), vec![99; 40]);
+}
+
+#[test]
+fn test_sleep_ranges() {
+    use tempdir::TempDir;
+    let tmp_dir = TempDir::new("geniza-test").unwrap();
+
+    let mut sf = SleepFile::create(&tmp_dir.path().join("range.sleep"), 0x05025702, 4, None).unwrap();
+    let mut msf = MemorySleepFile::new(0x05025702, 4, None).unwrap();
+    let data: Vec<u8> = (0..(4 * 3000)).map(|i| (i % 251) as u8).collect();
+    sf.write_range(0, &data).unwrap();
+    msf.write_range(0, &data).unwrap();
+    assert!(sf.write_range(0, &data[0..5]).is_err());
+    assert_eq!(sf.len().unwrap(), 3000);
+    assert_eq!(sf.read_range(0, 3000).unwrap(), data);
+    assert_eq!(sf.read_range(10, 2).unwrap(), &data[40..48]);
+    assert_eq!(msf.read_range(10, 2).unwrap(), &data[40..48]);
+    assert!(sf.read_range(2999, 2).is_err());
+
+    // Iterates across batch boundaries
+    let entries: Vec<Vec<u8>> = sf.entries().map(|e| e.unwrap()).collect();
+    assert_eq!(entries.len(), 3000);
+    assert_eq!(entries[1025], &data[4100..4104]);
+    assert_eq!(msf.entries().count(), 3000);
+
+    let mut mmf = MmapSleepFile::open(&tmp_dir.path().join("range.sleep"), true).unwrap();
+    assert_eq!(mmf.read_range(2990, 10).unwrap(), &data[(4 * 2990)..]);
+    mmf.write_range(3000, &[7; 8]).unwrap();
+    assert_eq!(mmf.read_range(2999, 3).unwrap()[4..], [7; 8]);
+}
+
+#[test]
+fn test_sleep_open_repair() {
+    use tempdir::TempDir;
+    let tmp_dir = TempDir::new("geniza-test").unwrap();
+    let path = tmp_dir.path().join("torn.sleep");
+
+    let mut sf = SleepFile::create(&path, 0x05025702, 40, None).unwrap();
+    sf.append(&[1; 40]).unwrap();
+    sf.append(&[2; 40]).unwrap();
+    // Simulate a torn write of a third entry
+    sf.file.write_all_at(&[3; 17], 32 + 80).unwrap();
+    drop(sf);
+    assert!(SleepFile::open(&path, false).is_err());
+
+    let (mut sf, dropped) = SleepFile::open_repair(&path).unwrap();
+    assert_eq!(dropped, 17);
+    assert_eq!(sf.len().unwrap(), 2);
+    sf.truncate(1).unwrap();
+    assert_eq!(SleepFile::open(&path, false).unwrap().len().unwrap(), 1);
+    assert!(sf.truncate(5).is_err());
+}
@@ -2,18 +2,145 @@
 use std::io::prelude::*;
 use std::fs::File;
 use std::path::{Path, PathBuf};
-use std::io::SeekFrom;
-use std::os::unix::fs::FileExt;
 use integer_encoding::FixedInt;
-use std::fs::OpenOptions;
+use std::fs::{OpenOptions, remove_file, rename};
+use std::cmp;
+use std::fmt;
+use std::thread;
 use crypto::blake2b::Blake2b;
+use crypto::sha2::Sha256;
 use crypto::digest::Digest;
 use crypto::ed25519;
-use rand::{OsRng, Rng};
+use bit_vec::BitVec;
+use sodiumoxide::crypto::secretbox;
 
 use errors::*;
 use sleep_file::*;
-use make_discovery_key;
+use sleep_cache::*;
+use keystore::*;
+use helpers::*;
+
+/// Number of entries `verify()` reads at a time
+const VERIFY_BATCH: u64 = 1024;
+
+/// Entries claiming to be bigger than this (~500 MB) are treated as corrupt instead of read
+const MAX_ENTRY_BYTES: u64 = 1 << 29;
+
+/// Bytes at the start of each bitfield SLEEP entry which are the data bitfield (the rest are tree
+/// and index bitfields, which geniza doesn't use).
+const BITFIELD_DATA_BYTES: usize = 1024;
+
+/// Placeholder signature for entries in the middle of an `append_batch()`; the signature of the
+/// final entry covers the whole tree, including them.
+const UNSIGNED: [u8; 64] = [0; 64];
+
+fn is_unsigned(sig: &[u8]) -> bool {
+    sig == &UNSIGNED[..]
+}
+
+/// Start of a data file which is encrypted at rest (see `SleepDirRegister::encrypt_data()`)
+const ENCRYPTED_DATA_MAGIC: &[u8; 4] = b"GZEN";
+
+/// Position in the data file of entry `index`, whose data starts at byte `offset` of the
+/// register (also works for the end of the file, with `index` the entry count).
+fn stored_data_offset(encrypted: bool, index: u64, offset: u64) -> u64 {
+    if encrypted {
+        ENCRYPTED_DATA_MAGIC.len() as u64 + offset + index * SEALED_ENTRY_OVERHEAD
+    } else {
+        offset
+    }
+}
+
+/// Hash function for a register's merkle tree, named in the header of its `.tree` SLEEP file. The
+/// tree layout is the same either way: 32 byte hashes, each followed by an 8 byte size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    /// "BLAKE2b" (with 32 byte output); what dat uses
+    Blake2b,
+    /// "SHA256", for interop with systems that don't have BLAKE2b
+    Sha256,
+}
+
+impl HashAlgorithm {
+
+    /// Parses the algorithm name from a SLEEP header. Unknown (or missing) names are an error,
+    /// rather than risking a tree we can't actually check.
+    pub fn from_name(name: Option<&str>) -> Result<HashAlgorithm> {
+        match name {
+            Some("BLAKE2b") => Ok(HashAlgorithm::Blake2b),
+            Some("SHA256") => Ok(HashAlgorithm::Sha256),
+            Some(other) => bail!("Unsupported tree hash algorithm: '{}'", other),
+            None => bail!("No tree hash algorithm in SLEEP header"),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            HashAlgorithm::Blake2b => "BLAKE2b",
+            HashAlgorithm::Sha256 => "SHA256",
+        }
+    }
+
+    /// Runs `f` to feed input to a fresh hasher, and writes the (32 byte) hash to `out`
+    fn hash_with<F: FnOnce(&mut dyn Digest)>(&self, out: &mut [u8], f: F) {
+        match *self {
+            HashAlgorithm::Blake2b => {
+                let mut hash = Blake2b::new(32);
+                f(&mut hash);
+                hash.result(out);
+            },
+            HashAlgorithm::Sha256 => {
+                let mut hash = Sha256::new();
+                f(&mut hash);
+                hash.result(out);
+            },
+        }
+    }
+
+    pub fn hash_leaf(&self, data: &[u8]) -> [u8; 40] {
+        let mut buf = [0; 40];
+        u64::to_be(data.len() as u64).encode_fixed(&mut buf[32..40]);
+        let size = buf[32..40].to_vec();
+        self.hash_with(&mut buf[0..32], |hash| {
+            hash.input(&[0; 1]);
+            hash.input(&size);
+            hash.input(data);
+        });
+        buf
+    }
+
+    pub fn hash_parent(&self, lhash: &[u8], rhash: &[u8]) -> [u8; 40] {
+        let mut buf = [0; 40];
+        // TODO: check overflow
+        let sum_size = u64::from_be(FixedInt::decode_fixed(&lhash[32..40]))
+            + u64::from_be(FixedInt::decode_fixed(&rhash[32..40]));
+        u64::to_be(sum_size as u64).encode_fixed(&mut buf[32..40]);
+        let size = buf[32..40].to_vec();
+        self.hash_with(&mut buf[0..32], |hash| {
+            hash.input(&[1; 1]);
+            hash.input(&size);
+            hash.input(&lhash[0..32]);
+            hash.input(&rhash[0..32]);
+        });
+        buf
+    }
+
+    /// Hashes the given (tree index, node) root nodes, in order; this is what gets signed.
+    pub fn hash_root_nodes(&self, roots: &[(u64, Vec<u8>)]) -> Vec<u8> {
+        let mut buf = [0; 32];
+        let mut index_buf = [0; 8];
+        self.hash_with(&mut buf, |hash| {
+            hash.input(&[2; 1]);
+            for &(ri, ref node) in roots {
+                u64::to_be(ri).encode_fixed(&mut index_buf);
+                hash.input(&node[0..32]);
+                hash.input(&index_buf);
+                hash.input(&node[32..40]);
+            }
+        });
+        buf.to_vec()
+    }
+}
 
 /// Abstract access to Hypercore register
 pub trait HyperRegister {
@@ -33,6 +160,16 @@ pub trait HyperRegister {
     /// index written to.
     fn append(&mut self, data: &[u8]) -> Result<u64>;
 
+    /// Writes several entries to the store at once. Implementations may flush and sign only once
+    /// for the whole batch. Returns the index of the first entry written.
+    fn append_batch(&mut self, chunks: &[&[u8]]) -> Result<u64> {
+        let first = self.len()?;
+        for data in chunks {
+            self.append(data)?;
+        }
+        Ok(first)
+    }
+
     /// Count of data entries for this register. This is the total count (highest entry index plus
     /// one); this particular store might be sparse.
     fn len(&self) -> Result<u64>;
@@ -49,53 +186,29 @@ pub trait HyperRegister {
     /// Can this register be appended to?
     fn writable(&self) -> bool;
 
+    /// The register's (32 byte) public key
+    fn pub_key(&self) -> &[u8];
+
+    /// Hash function of the register's merkle tree
+    fn hash_algorithm(&self) -> HashAlgorithm;
+
+    /// Drops local copies of data entries `start..end` (end exclusive). The tree and signatures
+    /// are kept, so the data can be fetched again (and verified) later.
+    fn clear(&mut self, start: u64, end: u64) -> Result<()>;
+
     /// Returns a single tree entry (using tree indexing, not data indexing).
     fn get_tree_entry(&mut self, tree_index: u64) -> Result<Vec<u8>>;
 }
 
 impl dyn HyperRegister {
-    fn hash_leaf(data: &[u8]) -> [u8; 40] {
-        let mut buf = [0; 40];
-        u64::to_be(data.len() as u64).encode_fixed(&mut buf[32..40]);
-        let mut hash = Blake2b::new(32);
-        hash.input(&[0; 1]);
-        hash.input(&buf[32..40]);
-        hash.input(&data);
-        hash.result(&mut buf[0..32]);
-        buf
-    }
-
-    fn hash_parent(lhash: &[u8], rhash: &[u8]) -> [u8; 40] {
-        let mut buf = [0; 40];
-        // TODO: check overflow
-        let sum_size = u64::from_be(FixedInt::decode_fixed(&lhash[32..40]))
-            + u64::from_be(FixedInt::decode_fixed(&rhash[32..40]));
-        u64::to_be(sum_size as u64).encode_fixed(&mut buf[32..40]);
-
-        let mut hash = Blake2b::new(32);
-        hash.input(&[1; 1]);
-        hash.input(&buf[32..40]);
-        hash.input(&lhash[0..32]);
-        hash.input(&rhash[0..32]);
-        hash.result(&mut buf[0..32]);
-        buf
-    }
 
     /// Hashes all the tree root parents for the given entry index (data index, not tree index).
     pub fn hash_roots(reg: &mut dyn HyperRegister, entry_index: u64) -> Result<Vec<u8>> {
-        let mut buf = [0; 32];
-        let mut hash = Blake2b::new(32);
-        let mut index_buf = [0; 8];
-        hash.input(&[2; 1]);
+        let mut roots = vec![];
         for ri in HyperRegister::tree_root_nodes(entry_index + 1) {
-            u64::to_be(ri).encode_fixed(&mut index_buf);
-            let node = reg.get_tree_entry(ri)?;
-            hash.input(&node[0..32]);
-            hash.input(&index_buf);
-            hash.input(&node[32..40]);
+            roots.push((ri, reg.get_tree_entry(ri)?));
         }
-        hash.result(&mut buf);
-        Ok(buf.to_vec())
+        Ok(reg.hash_algorithm().hash_root_nodes(&roots))
     }
 
     /// Calculates the root notes for a given length (of data entries, not tree entries)
@@ -129,18 +242,71 @@ impl dyn HyperRegister {
     }
 
     /// Finds the offset of the given data chunk in the linear appended data file (not a "checked
-    /// out" individual file)
+    /// out" individual file).
+    ///
+    /// The root nodes of the first `entry_index` entries cover exactly the data before this
+    /// chunk, and each stores the byte length of its subtree, so this is O(log N) tree reads.
+    /// Sparse registers may not have those (parent) nodes, in which case the leaves under them
+    /// get added up instead.
     pub fn get_data_offset(reg: &mut dyn HyperRegister, entry_index: u64) -> Result<u64> {
-        // TODO: this is a naive (linear) implementation
-        // log(N) would go up previous parent nodes (eg, use root_nodes())
         let mut sum: u64 = 0;
-        for i in 0..entry_index {
-            let leaf = reg.get_tree_entry(i * 2)?;
-            sum += u64::from_be(FixedInt::decode_fixed(&leaf[32..40]));
+        for ri in HyperRegister::tree_root_nodes(entry_index) {
+            if let Some(size) = HyperRegister::tree_node_size(reg, ri) {
+                sum += size;
+                continue;
+            }
+            let depth = (ri + 1).trailing_zeros();
+            let first = (ri >> (depth + 1)) << depth;
+            for i in first..(first + (1 << depth)) {
+                match HyperRegister::tree_node_size(reg, i * 2) {
+                    Some(size) => sum += size,
+                    None => bail!("Missing tree leaf for entry {} (needed for data offset of entry {})", i, entry_index),
+                }
+            }
         }
         Ok(sum)
     }
 
+    /// Byte length stored in a tree node, or None if we don't have the node (sparse SLEEP files
+    /// leave zeroed holes, or end early).
+    fn tree_node_size(reg: &mut dyn HyperRegister, tree_index: u64) -> Option<u64> {
+        match reg.get_tree_entry(tree_index) {
+            Ok(ref node) if node.len() == 40 && node[0..32].iter().any(|b| *b != 0) =>
+                Some(u64::from_be(FixedInt::decode_fixed(&node[32..40]))),
+            _ => None,
+        }
+    }
+
+    /// Finds the data entry containing the given byte offset (in the linear appended data file)
+    /// by walking down from the tree roots, which is O(log N) tree reads. Returns the entry index
+    /// and the byte offset where that entry starts, or None if the offset is past the end.
+    pub fn find_data_entry(reg: &mut dyn HyperRegister, byte_offset: u64) -> Result<Option<(u64, u64)>> {
+        let len = reg.len()?;
+        let mut start: u64 = 0;
+        for root in HyperRegister::tree_root_nodes(len) {
+            let node = reg.get_tree_entry(root)?;
+            let size = u64::from_be(FixedInt::decode_fixed(&node[32..40]));
+            if byte_offset >= start + size {
+                start += size;
+                continue;
+            }
+            let mut index = root;
+            while index % 2 == 1 {
+                let (left, right) = HyperRegister::tree_child_indices(index)?;
+                let node = reg.get_tree_entry(left)?;
+                let left_size = u64::from_be(FixedInt::decode_fixed(&node[32..40]));
+                if byte_offset < start + left_size {
+                    index = left;
+                } else {
+                    start += left_size;
+                    index = right;
+                }
+            }
+            return Ok(Some((index / 2, start)));
+        }
+        Ok(None)
+    }
+
     /// Every node has a parent, so this function won't fail unless index is over 2^62, in which
     /// case it would overflow and panics instead.
     fn tree_parent_index(tree_index: u64) -> u64 {
@@ -218,18 +384,417 @@ fn test_tree_child_indices() {
     assert_eq!(HyperRegister::tree_child_indices(19).unwrap(), (17, 21));
 }
 
+/// When `SleepDirRegister::append()` flushes writes to durable storage (with `fsync()`)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncPolicy {
+    /// Data, then tree, then signature writes are each flushed before moving on (the default).
+    /// A crash can only lose the entry being appended.
+    Always,
+    /// Flush everything after every N appends (and on `sync()`)
+    Batched(u64),
+    /// Leave it up to the OS (and explicit `sync()` calls)
+    Never,
+}
+
+/// What `SleepDirRegister::open_recover()` had to throw away
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RecoveryReport {
+    /// Entry count after recovery
+    pub entries: u64,
+    /// Bytes of partially-written SLEEP entries dropped (across all SLEEP files)
+    pub partial_bytes: u64,
+    pub dropped_tree_entries: u64,
+    pub dropped_signatures: u64,
+    pub dropped_data_bytes: u64,
+    /// Signed entries kept (in registers we don't write to) whose data turned out to be missing;
+    /// they're now marked as such in the bitfield
+    pub missing_data_entries: u64,
+    /// The checkpoint no longer matched, and was removed
+    pub dropped_checkpoint: bool,
+}
+
+impl RecoveryReport {
+    /// True if nothing needed repair
+    pub fn is_clean(&self) -> bool {
+        self.partial_bytes == 0 && self.dropped_tree_entries == 0
+            && self.dropped_signatures == 0 && self.dropped_data_bytes == 0
+            && self.missing_data_entries == 0 && !self.dropped_checkpoint
+    }
+}
+
+impl fmt::Display for RecoveryReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_clean() {
+            return write!(f, "clean ({} entries)", self.entries);
+        }
+        write!(f, "recovered {} entries; dropped {} partial SLEEP bytes, {} tree entries, {} signatures, {} data bytes{}{}",
+            self.entries, self.partial_bytes, self.dropped_tree_entries, self.dropped_signatures,
+            self.dropped_data_bytes,
+            if self.missing_data_entries > 0 { format!("; {} entries missing data", self.missing_data_entries) } else { "".to_string() },
+            if self.dropped_checkpoint { "; dropped stale checkpoint" } else { "" })
+    }
+}
+
+const PROOF_MAGIC: &[u8] = b"GZP2";
+
+/// Magic of proofs from before they named the tree hash algorithm
+const PROOF_MAGIC_V1: &[u8] = b"GZPF";
+
+/// Self-contained proof that a single entry is part of a register, which can be checked (with
+/// `verify_proof()`) without having the register.
+///
+/// The proof is against the signature of the whole register (as of `length` entries): the
+/// entry's leaf hash is combined with `uncles` (sibling nodes, bottom up) to get one of the
+/// `roots`, and the hash of all roots is what's signed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegisterProof {
+    pub pub_key: Vec<u8>,
+    /// Hash function of the register's tree
+    pub algorithm: HashAlgorithm,
+    pub index: u64,
+    pub length: u64,
+    /// Tree leaf node (hash and size) for the entry
+    pub leaf: Vec<u8>,
+    /// (tree index, node) pairs
+    pub uncles: Vec<(u64, Vec<u8>)>,
+    /// (tree index, node) pairs
+    pub roots: Vec<(u64, Vec<u8>)>,
+    pub signature: Vec<u8>,
+    /// The entry's data itself, if included
+    pub data: Option<Vec<u8>>,
+}
+
+fn encode_u64(buf: &mut Vec<u8>, val: u64) {
+    let mut raw = [0; 8];
+    u64::to_be(val).encode_fixed(&mut raw);
+    buf.extend_from_slice(&raw);
+}
+
+fn decode_u64(raw: &[u8], offset: &mut usize) -> Result<u64> {
+    let val = decode_bytes(raw, offset, 8)?;
+    Ok(u64::from_be(FixedInt::decode_fixed(&val)))
+}
+
+/// Lengths come from the (untrusted) proof itself, so anything past the end is an error, not a
+/// panic.
+fn decode_bytes(raw: &[u8], offset: &mut usize, len: usize) -> Result<Vec<u8>> {
+    let end = match offset.checked_add(len) {
+        Some(end) if end <= raw.len() => end,
+        _ => bail!("Truncated proof"),
+    };
+    let val = raw[*offset..end].to_vec();
+    *offset = end;
+    Ok(val)
+}
+
+impl RegisterProof {
+
+    /// Binary encoding (for writing to a file): "GZP2" magic, the tree hash algorithm name (a
+    /// length byte, then the name), then the public key, index, length, leaf, uncles and roots
+    /// (each a count followed by index/node pairs), signature, and optional data (a flag byte,
+    /// then length and bytes). Integers are 64-bit big-endian.
+    ///
+    /// `from_bytes()` also reads the older "GZPF" encoding, which has no algorithm name (it's
+    /// always BLAKE2b).
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = PROOF_MAGIC.to_vec();
+        buf.push(self.algorithm.name().len() as u8);
+        buf.extend_from_slice(self.algorithm.name().as_bytes());
+        buf.extend_from_slice(&self.pub_key);
+        encode_u64(&mut buf, self.index);
+        encode_u64(&mut buf, self.length);
+        buf.extend_from_slice(&self.leaf);
+        for nodes in &[&self.uncles, &self.roots] {
+            encode_u64(&mut buf, nodes.len() as u64);
+            for &(index, ref node) in nodes.iter() {
+                encode_u64(&mut buf, index);
+                buf.extend_from_slice(node);
+            }
+        }
+        buf.extend_from_slice(&self.signature);
+        match self.data {
+            Some(ref data) => {
+                buf.push(1);
+                encode_u64(&mut buf, data.len() as u64);
+                buf.extend_from_slice(data);
+            },
+            None => buf.push(0),
+        }
+        buf
+    }
+
+    pub fn from_bytes(raw: &[u8]) -> Result<RegisterProof> {
+        let mut offset = PROOF_MAGIC.len();
+        let algorithm = if raw.starts_with(PROOF_MAGIC) {
+            let name_len = decode_bytes(raw, &mut offset, 1)?[0] as usize;
+            let name = decode_bytes(raw, &mut offset, name_len)?;
+            HashAlgorithm::from_name(Some(&String::from_utf8_lossy(&name)))?
+        } else if raw.starts_with(PROOF_MAGIC_V1) {
+            HashAlgorithm::Blake2b
+        } else {
+            bail!("Not a register proof (bad magic)");
+        };
+        let pub_key = decode_bytes(raw, &mut offset, 32)?;
+        let index = decode_u64(raw, &mut offset)?;
+        let length = decode_u64(raw, &mut offset)?;
+        let leaf = decode_bytes(raw, &mut offset, 40)?;
+        let mut node_lists = vec![];
+        for _ in 0..2 {
+            let count = decode_u64(raw, &mut offset)?;
+            // Trees can't be more than 64 levels deep
+            if count > 64 {
+                bail!("Too many nodes in proof ({})", count);
+            }
+            let mut nodes = vec![];
+            for _ in 0..count {
+                let ni = decode_u64(raw, &mut offset)?;
+                nodes.push((ni, decode_bytes(raw, &mut offset, 40)?));
+            }
+            node_lists.push(nodes);
+        }
+        let signature = decode_bytes(raw, &mut offset, 64)?;
+        let data = match decode_bytes(raw, &mut offset, 1)?[0] {
+            0 => None,
+            1 => {
+                let len = decode_u64(raw, &mut offset)?;
+                if len > (raw.len() - offset) as u64 {
+                    bail!("Truncated proof");
+                }
+                Some(decode_bytes(raw, &mut offset, len as usize)?)
+            },
+            other => bail!("Bad data flag in proof: {}", other),
+        };
+        if offset != raw.len() {
+            bail!("Trailing bytes after proof");
+        }
+        let roots = node_lists.pop().unwrap();
+        let uncles = node_lists.pop().unwrap();
+        Ok(RegisterProof { pub_key, algorithm, index, length, leaf, uncles, roots, signature, data })
+    }
+}
+
+/// Checks a proof: the data (if included) against the leaf, the leaf and uncles against the
+/// roots, and the roots against the signature and public key. Doesn't need the register.
+pub fn verify_proof(proof: &RegisterProof) -> Result<()> {
+    if proof.index >= proof.length {
+        bail!("Proof entry index {} is past length {}", proof.index, proof.length);
+    }
+    // Tree indices (twice the entry count) have to fit, with room for parents
+    if proof.length > 1 << 62 {
+        bail!("Proof length {} is too large", proof.length);
+    }
+    // Proofs can be decoded from anywhere, and hashing nodes assumes they're the right size
+    if proof.leaf.len() != 40
+            || proof.uncles.iter().chain(proof.roots.iter()).any(|(_, node)| node.len() != 40) {
+        bail!("Proof tree nodes must be 40 bytes");
+    }
+    if let Some(ref data) = proof.data {
+        if proof.leaf[..] != proof.algorithm.hash_leaf(data)[..] {
+            bail!("Proof data doesn't match leaf hash");
+        }
+    }
+    let mut tree_index = proof.index * 2;
+    let mut node = proof.leaf.clone();
+    for &(uncle_index, ref uncle) in &proof.uncles {
+        let parent = HyperRegister::tree_parent_index(tree_index);
+        let (left, right) = HyperRegister::tree_child_indices(parent)?;
+        node = if tree_index == left && uncle_index == right {
+            proof.algorithm.hash_parent(&node, uncle).to_vec()
+        } else if tree_index == right && uncle_index == left {
+            proof.algorithm.hash_parent(uncle, &node).to_vec()
+        } else {
+            bail!("Proof uncle node {} isn't a sibling of {}", uncle_index, tree_index);
+        };
+        tree_index = parent;
+    }
+    let root_indices: Vec<u64> = proof.roots.iter().map(|&(ri, _)| ri).collect();
+    if root_indices != HyperRegister::tree_root_nodes(proof.length) {
+        bail!("Proof roots don't match length {}", proof.length);
+    }
+    if !proof.roots.iter().any(|&(ri, ref root)| ri == tree_index && *root == node) {
+        bail!("Proof path doesn't lead to a root (got to node {})", tree_index);
+    }
+    let root_hash = proof.algorithm.hash_root_nodes(&proof.roots);
+    if proof.pub_key.len() != 32 || proof.signature.len() != 64
+            || !ed25519::verify(&root_hash, &proof.pub_key, &proof.signature) {
+        bail!("Proof signature doesn't verify");
+    }
+    Ok(())
+}
+
+/// How much of a register `SleepDirRegister::verify_entries()` checks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VerifyMode {
+    /// Every data chunk (against its tree leaf) and every signature
+    Full,
+    /// Every signature (against the tree); no data is read
+    SignaturesOnly,
+    /// Data and signatures of the last N entries
+    LastEntries(u64),
+    /// Data and signatures of entries start..end (end exclusive)
+    Range(u64, u64),
+    /// Data and signatures of entries added since the last complete verification (see
+    /// `Checkpoint`), or everything if there isn't one (or it no longer matches)
+    SinceCheckpoint,
+}
+
+/// Result of `SleepDirRegister::verify_entries()`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VerifyReport {
+    /// Count of entries checked
+    pub checked: u64,
+    /// Entries whose data doesn't match the tree
+    pub bad_data: Vec<u64>,
+    /// Entries whose signature doesn't match the tree
+    pub bad_signatures: Vec<u64>,
+    /// Tree roots or data size before the checkpoint have changed (`SinceCheckpoint` mode)
+    pub history_changed: bool,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.bad_data.is_empty() && self.bad_signatures.is_empty() && !self.history_changed
+    }
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_ok() {
+            return write!(f, "OK ({} entries checked)", self.checked);
+        }
+        write!(f, "FAILED ({} entries checked); bad data: {:?}; bad signatures: {:?}{}",
+            self.checked, self.bad_data, self.bad_signatures,
+            if self.history_changed { "; changed since checkpoint" } else { "" })
+    }
+}
+
+const CHECKPOINT_MAGIC: &[u8] = b"GZCK";
+
+/// Record of the last complete, successful verification of a register, stored in
+/// `<prefix>.checkpoint` next to the SLEEP files (by default, only when the register was opened
+/// writable; see `set_save_checkpoints()`).
+///
+/// Later verifications can start from here, after checking that the tree roots (and total data
+/// size) as of `length` are unchanged. Changes to tree nodes or data which leave those intact
+/// aren't caught without a full verify.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+    /// Count of entries verified
+    pub length: u64,
+    /// Total data size (bytes) of those entries
+    pub data_bytes: u64,
+    /// Hash of the tree roots (what gets signed) as of `length`
+    pub roots_hash: Vec<u8>,
+}
+
+impl Checkpoint {
+
+    /// Encoded as "GZCK" magic, length and data_bytes (64-bit big-endian), the roots hash, and a
+    /// BLAKE2b checksum of all that, keyed with the register's public key.
+    fn to_bytes(&self, pub_key: &[u8]) -> Vec<u8> {
+        let mut buf = CHECKPOINT_MAGIC.to_vec();
+        encode_u64(&mut buf, self.length);
+        encode_u64(&mut buf, self.data_bytes);
+        buf.extend_from_slice(&self.roots_hash);
+        let checksum = checkpoint_checksum(&buf, pub_key);
+        buf.extend_from_slice(&checksum);
+        buf
+    }
+
+    fn from_bytes(raw: &[u8], pub_key: &[u8]) -> Result<Checkpoint> {
+        if raw.len() != 4 + 8 + 8 + 32 + 32 || !raw.starts_with(CHECKPOINT_MAGIC) {
+            bail!("Not a checkpoint file");
+        }
+        if checkpoint_checksum(&raw[0..52], pub_key)[..] != raw[52..84] {
+            bail!("Bad checkpoint checksum");
+        }
+        let mut offset = 4;
+        Ok(Checkpoint {
+            length: decode_u64(raw, &mut offset)?,
+            data_bytes: decode_u64(raw, &mut offset)?,
+            roots_hash: raw[20..52].to_vec(),
+        })
+    }
+}
+
+fn checkpoint_checksum(raw: &[u8], pub_key: &[u8]) -> [u8; 32] {
+    let mut buf = [0; 32];
+    let mut hash = Blake2b::new_keyed(32, pub_key);
+    hash.input(raw);
+    hash.result(&mut buf);
+    buf
+}
+
+/// Checks the hash algorithm named in a tree file header (at `path`, for error messages)
+fn tree_algorithm(path: &Path, name: Option<String>) -> Result<HashAlgorithm> {
+    match HashAlgorithm::from_name(name.as_deref()) {
+        Ok(algorithm) => Ok(algorithm),
+        Err(e) => bail!("{}: {}", e, path.display()),
+    }
+}
+
+/// Checks (index, leaf, data) chunks and (index, root hash, signature) signatures, split across
+/// `threads` worker threads. Returns the failing indices of each (sorted).
+fn verify_jobs(chunks: &[(u64, Vec<u8>, Vec<u8>)], signed: &[(u64, Vec<u8>, Vec<u8>)],
+               pub_key: &[u8], algorithm: HashAlgorithm, threads: usize) -> (Vec<u64>, Vec<u64>) {
+    let check_chunks = |part: &[(u64, Vec<u8>, Vec<u8>)]| -> Vec<u64> {
+        part.iter()
+            .filter(|(_, leaf, data)| leaf[..] != algorithm.hash_leaf(data)[..])
+            .map(|(i, _, _)| *i)
+            .collect()
+    };
+    let check_signed = |part: &[(u64, Vec<u8>, Vec<u8>)]| -> Vec<u64> {
+        part.iter()
+            .filter(|(_, hash, sig)| !ed25519::verify(hash, pub_key, sig))
+            .map(|(i, _, _)| *i)
+            .collect()
+    };
+    if threads <= 1 {
+        return (check_chunks(chunks), check_signed(signed));
+    }
+    thread::scope(|scope| {
+        let chunk_handles: Vec<_> = chunks.chunks(cmp::max(1, chunks.len().div_ceil(threads)))
+            .map(|part| scope.spawn(move || check_chunks(part)))
+            .collect();
+        let signed_handles: Vec<_> = signed.chunks(cmp::max(1, signed.len().div_ceil(threads)))
+            .map(|part| scope.spawn(move || check_signed(part)))
+            .collect();
+        let mut bad_data: Vec<u64> = chunk_handles.into_iter().flat_map(|h| h.join().unwrap()).collect();
+        let mut bad_sigs: Vec<u64> = signed_handles.into_iter().flat_map(|h| h.join().unwrap()).collect();
+        bad_data.sort();
+        bad_sigs.sort();
+        (bad_data, bad_sigs)
+    })
+}
+
 /// Implementation of HyperRegister using a local directory of SLEEP files
+///
+/// Generic over the storage of SLEEP "files" and of data, which default to local files; see
+/// `MemoryRegister` for a variant that keeps everything in memory.
 #[derive(Debug)]
-pub struct SleepDirRegister {
-    tree_sleep: SleepFile,
-    sign_sleep: SleepFile,
-    bitfield_sleep: SleepFile,
-    data_file: Option<File>,
+pub struct SleepDirRegister<S: SleepStorage = SleepFile, D: DataStorage = File> {
+    tree_sleep: S,
+    sign_sleep: S,
+    bitfield_sleep: S,
+    data_file: Option<D>,
     // Except, these should be Ed25519 keys, not bytes
     pub_key: Vec<u8>,
     secret_key: Option<Vec<u8>>,
     path: PathBuf,
     prefix: String,
+    sync_policy: SyncPolicy,
+    unsynced: u64,
+    // Which data entries we have locally (in-memory copy of the bitfield file)
+    have: BitVec,
+    // False for registers whose (dense) bitfield has never been written out
+    bitfield_written: bool,
+    checkpoint: Option<Checkpoint>,
+    // Whether to write checkpoint files (by default, only when opened writable)
+    save_checkpoints: bool,
+    // Set if the data file is encrypted at rest
+    data_key: Option<secretbox::Key>,
+    // Tree hash function, from the tree file header
+    algorithm: HashAlgorithm,
 }
 
 fn read_key_file(path: &Path, is_secret: bool) -> Result<Vec<u8>> {
@@ -248,6 +813,22 @@ fn read_key_file(path: &Path, is_secret: bool) -> Result<Vec<u8>> {
     Ok(key)
 }
 
+/// Key store first, then a `<prefix>.secret_key` file next to the register files (where
+/// `create()` puts it).
+fn find_secret_key(directory: &Path, prefix: &str, pub_key: &[u8],
+                   keystore: Option<&dyn KeyStore>) -> Result<Vec<u8>> {
+    if let Some(ks) = keystore {
+        if let Some(secret_key) = ks.get_secret_key(pub_key)? {
+            return Ok(secret_key);
+        }
+    }
+    let path = directory.join(Path::new(&(prefix.to_owned() + ".secret_key")));
+    if !path.is_file() {
+        bail!("No secret key for register found (in key store or at {})", path.display());
+    }
+    read_key_file(&path, true)
+}
+
 fn write_key_file(path: &Path, key: &[u8], is_secret: bool) -> Result<()> {
     let expected = if is_secret { 64 } else { 32 };
     if key.len() != expected {
@@ -264,7 +845,188 @@ fn write_key_file(path: &Path, key: &[u8], is_secret: bool) -> Result<()> {
 }
 
 impl SleepDirRegister {
+    /// Writable opens look for the secret key in the default key store (`~/.dat/secret_keys/`)
+    /// first, then next to the register files. The data key of registers encrypted at rest also
+    /// comes from the default key store.
+    ///
+    /// Writable opens first repair registers left inconsistent by a crash (see `open_recover()`).
+    /// Read-only opens never change the files: they fail instead, and the caller has to run
+    /// `open_recover()` itself.
     pub fn open(directory: &Path, prefix: &str, writable: bool) -> Result<SleepDirRegister> {
+        let default_ks = DirKeyStore::open_default().ok();
+        SleepDirRegister::open_keyed(directory, prefix, writable,
+                                     default_ks.as_ref().map(|ks| ks as &dyn KeyStore))
+    }
+
+    /// Like `open()`, but looks up keys in the given key store instead of the default one.
+    pub fn open_with_keystore(directory: &Path, prefix: &str, writable: bool,
+                              keystore: &dyn KeyStore) -> Result<SleepDirRegister> {
+        SleepDirRegister::open_keyed(directory, prefix, writable, Some(keystore))
+    }
+
+    fn open_keyed(directory: &Path, prefix: &str, writable: bool,
+                  keystore: Option<&dyn KeyStore>) -> Result<SleepDirRegister> {
+        let open = || -> Result<SleepDirRegister> {
+            let mut sf = SleepDirRegister::open_unchecked(directory, prefix, writable, true, keystore)?;
+            sf.check()?;
+            sf.load_bitfield()?;
+            sf.load_checkpoint();
+            Ok(sf)
+        };
+        match open() {
+            Ok(sf) => Ok(sf),
+            Err(e) if !writable => bail!("{} (open_recover(), or `geniza-sleep recover`, may repair it)", e),
+            Err(e) => match SleepDirRegister::open_recover_keyed(directory, prefix, keystore) {
+                Ok((_, ref report)) if !report.is_clean() => open(),
+                _ => Err(e),
+            },
+        }
+    }
+
+    /// Opens with write access to the files, first repairing any inconsistency a crash in the
+    /// middle of `append()` (or of a download) could have left behind.
+    ///
+    /// The signature is the last thing written for each entry, so it serves as the "commit"
+    /// record: anything past the last entry with a signature (and all its tree nodes) is rolled
+    /// back. If we have the secret key, so wrote the data ourselves, signed entries without all of
+    /// their data are rolled back too; otherwise they're kept, and marked as missing in the
+    /// bitfield. Nothing gets signed, so the secret key isn't required (the register is only
+    /// `writable()` if it's found). The bitfield and checkpoint are brought in line with whatever
+    /// is left.
+    pub fn open_recover(directory: &Path, prefix: &str) -> Result<(SleepDirRegister, RecoveryReport)> {
+        let default_ks = DirKeyStore::open_default().ok();
+        SleepDirRegister::open_recover_keyed(directory, prefix, default_ks.as_ref().map(|ks| ks as &dyn KeyStore))
+    }
+
+    /// Like `open_recover()`, but looks up keys in the given key store instead of the default one.
+    pub fn open_recover_with_keystore(directory: &Path, prefix: &str,
+                                      keystore: &dyn KeyStore) -> Result<(SleepDirRegister, RecoveryReport)> {
+        SleepDirRegister::open_recover_keyed(directory, prefix, Some(keystore))
+    }
+
+    fn open_recover_keyed(directory: &Path, prefix: &str,
+                          keystore: Option<&dyn KeyStore>) -> Result<(SleepDirRegister, RecoveryReport)> {
+        let mut report = RecoveryReport::default();
+        for suffix in &[".tree", ".signatures", ".bitfield"] {
+            let (_, dropped) = SleepFile::open_repair(&directory.join(Path::new(&(prefix.to_owned() + suffix))))?;
+            report.partial_bytes += dropped;
+        }
+        let mut sdr = SleepDirRegister::open_unchecked(directory, prefix, true, false, keystore)?;
+        let missing_from = sdr.recover(&mut report)?;
+        sdr.check()?;
+        sdr.load_bitfield()?;
+        sdr.reconcile_bitfield(missing_from, &mut report)?;
+        sdr.load_checkpoint();
+        if let Some(cp) = sdr.checkpoint.clone() {
+            if !sdr.checkpoint_matches(&cp)? {
+                if let Some(path) = sdr.checkpoint_path() {
+                    remove_file(&path)?;
+                }
+                sdr.checkpoint = None;
+                report.dropped_checkpoint = true;
+            }
+        }
+        if !report.is_clean() {
+            warn!("Recovered register (dir={} prefix={}): {}", directory.display(), prefix, report);
+        }
+        Ok((sdr, report))
+    }
+
+    /// Returns the first entry (if any) past the end of the data file, which only registers we
+    /// don't write to keep.
+    fn recover(&mut self, report: &mut RecoveryReport) -> Result<Option<u64>> {
+        let tree_len = self.tree_sleep.len()?;
+        let sign_len = self.sign_sleep.len()?;
+        let entries = cmp::min(sign_len, tree_len.div_ceil(2));
+
+        // Our own data may be missing if it wasn't synced before the tree and signatures were;
+        // anybody else's just hasn't all arrived (data_ends[i] is the data file length through
+        // entry i)
+        let encrypted = self.data_key.is_some();
+        let data_len = match self.data_file {
+            Some(ref df) => Some(df.metadata()?.len()),
+            None => None,
+        };
+        let mut data_ends: Vec<u64> = vec![stored_data_offset(encrypted, 0, 0)];
+        let mut missing_from = None;
+        let mut plain_end = 0;
+        for leaf in self.tree_sleep.entries().step_by(2).take(entries as usize) {
+            let leaf = leaf?;
+            plain_end += u64::from_be(FixedInt::decode_fixed(&leaf[32..40]));
+            let end = stored_data_offset(encrypted, data_ends.len() as u64, plain_end);
+            if missing_from.is_none() && data_len.is_some_and(|len| end > len) {
+                if self.secret_key.is_some() {
+                    break;
+                }
+                missing_from = Some(data_ends.len() as u64 - 1);
+            }
+            data_ends.push(end);
+        }
+        let mut entries = data_ends.len() as u64 - 1;
+
+        // Entries appended as part of a batch are only signed at the end of the batch
+        while entries > 0 && is_unsigned(&self.sign_sleep.read(entries - 1)?) {
+            entries -= 1;
+        }
+
+        if let Some(ref df) = self.data_file {
+            let data_len = df.metadata()?.len();
+            let data_bytes = data_ends[entries as usize];
+            if data_len > data_bytes {
+                df.set_len(data_bytes)?;
+                report.dropped_data_bytes = data_len - data_bytes;
+            } else if data_len < data_bytes {
+                // Pad out (sparsely) where missing data would go, so later offsets line up
+                df.set_len(data_bytes)?;
+            }
+        }
+
+        let tree_entries = if entries == 0 { 0 } else { entries * 2 - 1 };
+        if tree_len > tree_entries {
+            self.tree_sleep.truncate(tree_entries)?;
+            report.dropped_tree_entries = tree_len - tree_entries;
+        }
+        if sign_len > entries {
+            self.sign_sleep.truncate(entries)?;
+            report.dropped_signatures = sign_len - entries;
+        }
+        report.entries = entries;
+
+        // Whatever is left at the end had better be properly signed
+        if entries > 0 {
+            let rehash = HyperRegister::hash_roots(self, entries - 1)?;
+            let sig = self.sign_sleep.read(entries - 1)?;
+            if !ed25519::verify(&rehash, &self.pub_key, &sig) {
+                bail!("Last entry ({}) fails signature verification after recovery", entries - 1);
+            }
+        }
+        Ok(missing_from.filter(|i| *i < entries))
+    }
+
+    /// After `recover()`: marks entries from `missing_from` on as missing, and clears bits past
+    /// the end of the register (for entries that were rolled back).
+    fn reconcile_bitfield(&mut self, missing_from: Option<u64>, report: &mut RecoveryReport) -> Result<()> {
+        let len = self.len()?;
+        if let Some(start) = missing_from {
+            for i in start..len {
+                if self.have.get(i as usize) == Some(true) {
+                    self.have.set(i as usize, false);
+                    report.missing_data_entries += 1;
+                }
+            }
+        }
+        let bitfield_bits = self.bitfield_sleep.len()? * BITFIELD_DATA_BYTES as u64 * 8;
+        if report.missing_data_entries > 0 || bitfield_bits > len {
+            self.write_bitfield(0, cmp::max(len, bitfield_bits))?;
+            self.bitfield_sleep.sync()?;
+        }
+        Ok(())
+    }
+
+    /// With `writable`, the files are opened for writing; the secret key is then looked up too,
+    /// but only required with `require_secret`.
+    fn open_unchecked(directory: &Path, prefix: &str, writable: bool, require_secret: bool,
+                      keystore: Option<&dyn KeyStore>) -> Result<SleepDirRegister> {
         // read public key from disk
         let pub_key: Vec<u8> = read_key_file(
             &directory.join(Path::new(&(prefix.to_owned() + ".key"))),
@@ -272,10 +1034,11 @@ impl SleepDirRegister {
         )?;
         let mut secret_key = None;
         if writable {
-            secret_key = Some(read_key_file(
-                &directory.join(Path::new(&(prefix.to_owned() + ".secret_key"))),
-                true,
-            )?);
+            match find_secret_key(directory, prefix, &pub_key, keystore) {
+                Ok(key) => secret_key = Some(key),
+                Err(_) if !require_secret => {},
+                Err(e) => return Err(e),
+            }
         }
         let data_path = &directory.join(Path::new(&(prefix.to_owned() + ".data")));
         let data_file = if data_path.is_file() {
@@ -287,10 +1050,9 @@ impl SleepDirRegister {
             warn!("SleepDirRegister data file not found: {}", data_path.display());
             None
         };
-        let tree_sleep = SleepFile::open(
-            &directory.join(Path::new(&(prefix.to_owned() + ".tree"))),
-            writable,
-        )?;
+        let tree_sleep_path = directory.join(Path::new(&(prefix.to_owned() + ".tree")));
+        let tree_sleep = SleepFile::open(&tree_sleep_path, writable)?;
+        let algorithm_name = tree_sleep.get_algorithm();
         let sign_sleep = SleepFile::open(
             &directory.join(Path::new(&(prefix.to_owned() + ".signatures"))),
             writable,
@@ -308,28 +1070,198 @@ impl SleepDirRegister {
             secret_key,
             path: directory.to_path_buf(),
             prefix: prefix.to_string(),
+            sync_policy: SyncPolicy::Always,
+            unsynced: 0,
+            have: BitVec::new(),
+            bitfield_written: true,
+            checkpoint: None,
+            save_checkpoints: writable,
+            data_key: None,
+            algorithm: tree_algorithm(&tree_sleep_path, algorithm_name)?,
         };
-        sf.check()?;
+        let signing = sf.secret_key.is_some();
+        sf.load_data_key(keystore, signing)?;
         Ok(sf)
     }
 
-    /// In addition to what one would expect, also creates an Ed25519 key-pair using OsRng
+    /// If the data file is encrypted, finds its key. Encrypted files have a marker file next to
+    /// them, and start with a magic number. Without a marker (files encrypted before there were
+    /// any), plain data that just happens to start with the magic number is told apart by its
+    /// size.
+    ///
+    /// Without the key, read-only opens carry on with no data file (tree and signatures still
+    /// work); writable opens fail.
+    fn load_data_key(&mut self, keystore: Option<&dyn KeyStore>, writable: bool) -> Result<()> {
+        let (magic, file_len) = match self.data_file {
+            Some(ref mut df) => {
+                let file_len = df.len()?;
+                let mut magic = [0; 4];
+                if file_len < 4 {
+                    return Ok(());
+                }
+                df.read_at(0, &mut magic)?;
+                (magic, file_len)
+            },
+            None => return Ok(()),
+        };
+        let marked = self.encrypted_marker_path().is_file();
+        if &magic != ENCRYPTED_DATA_MAGIC {
+            if marked {
+                warn!("Ignoring encryption marker for plain data file (dir={} prefix={})",
+                    self.path.display(), self.prefix);
+            }
+            return Ok(());
+        }
+        if !marked {
+            let len = self.len()?;
+            if HyperRegister::get_data_offset(self, len).ok() == Some(file_len) {
+                return Ok(());
+            }
+        }
+        let data_key = match keystore {
+            Some(ks) => ks.get_data_key(&self.pub_key)?,
+            None => None,
+        };
+        match data_key {
+            Some(key) => {
+                match secretbox::Key::from_slice(&key) {
+                    Some(key) => self.data_key = Some(key),
+                    None => bail!("Bad data key in key store (len {} != {})", key.len(), secretbox::KEYBYTES),
+                };
+            },
+            None => {
+                if writable {
+                    bail!("Register data is encrypted, but there's no data key in the key store (dir={} prefix={})",
+                        self.path.display(), self.prefix);
+                }
+                warn!("Register data is encrypted, but there's no data key; data won't be available (dir={} prefix={})",
+                    self.path.display(), self.prefix);
+                self.data_file = None;
+            },
+        };
+        Ok(())
+    }
+
+    /// Encrypts the data file at rest with a new (random) key, which goes in the key store.
+    /// Existing data is re-written to a temporary file, which then replaces the data file, and a
+    /// `<prefix>.data.encrypted` marker file records that it's encrypted.
+    ///
+    /// Each chunk is sealed separately (with libsodium's secretbox), so random access still works.
+    /// Hashes and signatures still cover the plain data, as sent to peers.
+    ///
+    /// The old plain data file is only unlinked, not overwritten, so its blocks may well still be
+    /// readable from the disk; this protects data written from now on, not what was already there.
+    pub fn encrypt_data(&mut self, keystore: &mut dyn KeyStore) -> Result<()> {
+        if self.data_key.is_some() {
+            bail!("Register data is already encrypted");
+        }
+        if self.data_file.is_none() {
+            bail!("No data file in this register");
+        }
+        let key = secretbox::gen_key();
+        let data_path = self.path.join(Path::new(&(self.prefix.clone() + ".data")));
+        let tmp_path = self.path.join(Path::new(&(self.prefix.clone() + ".data.tmp")));
+        let marker_path = self.encrypted_marker_path();
+        // The key has to be stored before the encrypted file replaces the plain one, but not
+        // before there's an encrypted file to replace it with
+        let replaced = self.write_sealed_copy(&key, &tmp_path)
+            .and_then(|tmp| {
+                keystore.put_data_key(&self.pub_key, &key.0)?;
+                File::create(&marker_path)?.sync_all()?;
+                rename(&tmp_path, &data_path)?;
+                Ok(tmp)
+            });
+        let tmp = match replaced {
+            Ok(tmp) => tmp,
+            Err(e) => {
+                let _ = remove_file(&tmp_path);
+                let _ = remove_file(&marker_path);
+                return Err(e);
+            },
+        };
+        self.data_file = Some(tmp);
+        self.data_key = Some(key);
+        Ok(())
+    }
+
+    /// Writes all data, sealed with `key`, to a new file at `path`.
+    fn write_sealed_copy(&mut self, key: &secretbox::Key, path: &Path) -> Result<File> {
+        let mut tmp = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        tmp.write_all(ENCRYPTED_DATA_MAGIC)?;
+        for i in 0..self.len()? {
+            if self.has(i)? {
+                let data = self.get_data_entry(i)?;
+                tmp.write_all(&seal_entry(key, i, &data))?;
+            } else {
+                // (cleared entries stay zeroed)
+                let leaf = self.tree_sleep.read(i * 2)?;
+                let data_len = u64::from_be(FixedInt::decode_fixed(&leaf[32..40]));
+                tmp.write_all(&vec![0; (data_len + SEALED_ENTRY_OVERHEAD) as usize])?;
+            }
+        }
+        tmp.sync_all()?;
+        Ok(tmp)
+    }
+
+    fn encrypted_marker_path(&self) -> PathBuf {
+        self.path.join(Path::new(&(self.prefix.clone() + ".data.encrypted")))
+    }
+
+    /// In addition to what one would expect, also creates an Ed25519 key-pair using OsRng. The
+    /// secret key is written next to the other files (as `<prefix>.secret_key`); see
+    /// `create_with_keystore()` to keep it elsewhere.
     pub fn create(directory: &Path, prefix: &str) -> Result<SleepDirRegister> {
-        let mut rand_seed = vec![0; 32];
-        let mut rng = OsRng::new()?;
-        rng.fill_bytes(&mut rand_seed);
-        let (secret_key, pub_key) = ed25519::keypair(&rand_seed);
+        SleepDirRegister::create_keyed(directory, prefix, &random_seed()?, None, HashAlgorithm::Blake2b)
+    }
+
+    /// Like `create()`, but the secret key goes in the given key store instead of the register
+    /// directory, which can then be copied or published without giving away write access.
+    pub fn create_with_keystore(directory: &Path, prefix: &str,
+                                keystore: &mut dyn KeyStore) -> Result<SleepDirRegister> {
+        SleepDirRegister::create_keyed(directory, prefix, &random_seed()?, Some(keystore), HashAlgorithm::Blake2b)
+    }
+
+    /// Like `create()`, but the key-pair is derived from the given 32 byte seed, so it can be
+    /// regenerated later (see `keypair_from_seed()`).
+    pub fn create_from_seed(directory: &Path, prefix: &str, seed: &[u8]) -> Result<SleepDirRegister> {
+        SleepDirRegister::create_keyed(directory, prefix, seed, None, HashAlgorithm::Blake2b)
+    }
+
+    /// Combination of `create_from_seed()` and `create_with_keystore()`.
+    pub fn create_from_seed_with_keystore(directory: &Path, prefix: &str, seed: &[u8],
+                                          keystore: &mut dyn KeyStore) -> Result<SleepDirRegister> {
+        SleepDirRegister::create_keyed(directory, prefix, seed, Some(keystore), HashAlgorithm::Blake2b)
+    }
+
+    /// Like `create()`, but with the given tree hash function instead of BLAKE2b. Only other
+    /// implementations that know the algorithm will be able to use the register.
+    pub fn create_with_algorithm(directory: &Path, prefix: &str,
+                                 algorithm: HashAlgorithm) -> Result<SleepDirRegister> {
+        SleepDirRegister::create_keyed(directory, prefix, &random_seed()?, None, algorithm)
+    }
+
+    fn create_keyed(directory: &Path, prefix: &str, seed: &[u8], keystore: Option<&mut dyn KeyStore>,
+                    algorithm: HashAlgorithm) -> Result<SleepDirRegister> {
+        let (secret_key, pub_key) = keypair_from_seed(seed)?;
         write_key_file(
             &directory.join(Path::new(&(prefix.to_owned() + ".key"))),
             &pub_key,
             false,
         )?;
-        write_key_file(
-            &directory.join(Path::new(&(prefix.to_owned() + ".secret_key"))),
-            &secret_key,
-            true,
-        )?;
-        let data_file = OpenOptions::new()
+        match keystore {
+            Some(ks) => ks.put_secret_key(&pub_key, &secret_key)?,
+            None => write_key_file(
+                &directory.join(Path::new(&(prefix.to_owned() + ".secret_key"))),
+                &secret_key,
+                true,
+            )?,
+        };
+        let data_file = OpenOptions::new()
             .read(true)
             .write(true)
             .create_new(true)
@@ -338,7 +1270,7 @@ impl SleepDirRegister {
             &directory.join(Path::new(&(prefix.to_owned() + ".tree"))),
             0x05025702,
             40,
-            Some("BLAKE2b".to_string()),
+            Some(algorithm.name().to_string()),
         )?;
         let sign_sleep = SleepFile::create(
             &directory.join(Path::new(&(prefix.to_owned() + ".signatures"))),
@@ -357,29 +1289,541 @@ impl SleepDirRegister {
             sign_sleep,
             bitfield_sleep,
             data_file: Some(data_file),
-            pub_key: pub_key.to_vec(),
-            secret_key: Some(secret_key.to_vec()),
+            pub_key,
+            secret_key: Some(secret_key),
             path: directory.to_path_buf(),
             prefix: prefix.to_string(),
+            sync_policy: SyncPolicy::Always,
+            unsynced: 0,
+            have: BitVec::new(),
+            bitfield_written: true,
+            checkpoint: None,
+            save_checkpoints: true,
+            data_key: None,
+            algorithm,
         };
         sf.check()?;
         Ok(sf)
     }
 
+    /// Puts the secret key (which must have been loaded, ie, opened writable) in the given key
+    /// store, and deletes the `<prefix>.secret_key` file next to the register, if there is one.
+    pub fn move_secret_key(&self, keystore: &mut dyn KeyStore) -> Result<()> {
+        let secret_key = match self.secret_key {
+            Some(ref key) => key,
+            None => bail!("Secret key not loaded (register not opened writable?)"),
+        };
+        keystore.put_secret_key(&self.pub_key, secret_key)?;
+        let path = self.path.join(Path::new(&(self.prefix.clone() + ".secret_key")));
+        if path.is_file() {
+            remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+/// SLEEP directory register with memory-mapped tree, signature and bitfield files.
+pub type MmapRegister = SleepDirRegister<MmapSleepFile, File>;
+
+impl MmapRegister {
+
+    /// Same as `SleepDirRegister::open()`, but with memory-mapped SLEEP files.
+    pub fn open_mmap(directory: &Path, prefix: &str, writable: bool) -> Result<MmapRegister> {
+        let sdr = SleepDirRegister::open(directory, prefix, writable)?;
+        Ok(SleepDirRegister {
+            tree_sleep: MmapSleepFile::from_sleep_file(sdr.tree_sleep)?,
+            sign_sleep: MmapSleepFile::from_sleep_file(sdr.sign_sleep)?,
+            bitfield_sleep: MmapSleepFile::from_sleep_file(sdr.bitfield_sleep)?,
+            data_file: sdr.data_file,
+            pub_key: sdr.pub_key,
+            secret_key: sdr.secret_key,
+            path: sdr.path,
+            prefix: sdr.prefix,
+            sync_policy: sdr.sync_policy,
+            unsynced: sdr.unsynced,
+            have: sdr.have,
+            bitfield_written: sdr.bitfield_written,
+            checkpoint: sdr.checkpoint,
+            save_checkpoints: sdr.save_checkpoints,
+            data_key: sdr.data_key,
+            algorithm: sdr.algorithm,
+        })
+    }
+}
+
+/// SLEEP directory register with an LRU read cache in front of each SLEEP file.
+pub type CachedRegister = SleepDirRegister<CachedSleepStorage<SleepFile>, File>;
+
+impl CachedRegister {
+
+    /// Same as `SleepDirRegister::open()`, but caching up to `capacity` entries of each SLEEP
+    /// file.
+    pub fn open_cached(directory: &Path, prefix: &str, writable: bool, capacity: usize) -> Result<CachedRegister> {
+        let sdr = SleepDirRegister::open(directory, prefix, writable)?;
+        Ok(SleepDirRegister {
+            tree_sleep: CachedSleepStorage::new(sdr.tree_sleep, capacity),
+            sign_sleep: CachedSleepStorage::new(sdr.sign_sleep, capacity),
+            bitfield_sleep: CachedSleepStorage::new(sdr.bitfield_sleep, capacity),
+            data_file: sdr.data_file,
+            pub_key: sdr.pub_key,
+            secret_key: sdr.secret_key,
+            path: sdr.path,
+            prefix: sdr.prefix,
+            sync_policy: sdr.sync_policy,
+            unsynced: sdr.unsynced,
+            have: sdr.have,
+            bitfield_written: sdr.bitfield_written,
+            checkpoint: sdr.checkpoint,
+            save_checkpoints: sdr.save_checkpoints,
+            data_key: sdr.data_key,
+            algorithm: sdr.algorithm,
+        })
+    }
+}
+
+impl<S: SleepStorage, D: DataStorage> SleepDirRegister<CachedSleepStorage<S>, D> {
+
+    /// Hit/miss counts for the tree file cache
+    pub fn tree_cache_stats(&self) -> CacheStats {
+        self.tree_sleep.stats()
+    }
+}
+
+/// Implementation of HyperRegister which keeps everything in memory; nothing is persisted.
+pub type MemoryRegister = SleepDirRegister<MemorySleepFile, Vec<u8>>;
+
+impl MemoryRegister {
+
+    /// Creates an empty register with a new Ed25519 key-pair (using OsRng)
+    pub fn new() -> Result<MemoryRegister> {
+        MemoryRegister::from_seed(&random_seed()?)
+    }
+
+    /// Creates an empty register with the key-pair derived from the given 32 byte seed
+    pub fn from_seed(seed: &[u8]) -> Result<MemoryRegister> {
+        let (secret_key, pub_key) = keypair_from_seed(seed)?;
+        MemoryRegister::with_keys(&pub_key, Some(&secret_key))
+    }
+
+    /// Creates an empty register for an existing key (pair). Without a secret key the register
+    /// is read-only.
+    pub fn with_keys(pub_key: &[u8], secret_key: Option<&[u8]>) -> Result<MemoryRegister> {
+        MemoryRegister::with_keys_and_algorithm(pub_key, secret_key, HashAlgorithm::Blake2b)
+    }
+
+    /// Like `with_keys()`, with the given tree hash function instead of BLAKE2b.
+    pub fn with_keys_and_algorithm(pub_key: &[u8], secret_key: Option<&[u8]>,
+                                   algorithm: HashAlgorithm) -> Result<MemoryRegister> {
+        SleepDirRegister::from_parts(
+            MemorySleepFile::new(0x05025702, 40, Some(algorithm.name().to_string()))?,
+            MemorySleepFile::new(0x05025701, 64, Some("Ed25519".to_string()))?,
+            MemorySleepFile::new(0x05025700, 3328, None)?,
+            Some(vec![]),
+            pub_key,
+            secret_key,
+        )
+    }
+}
+
+impl<S: SleepStorage, D: DataStorage> SleepDirRegister<S, D> {
+
+    /// Assembles a register from already opened (or created) storage. `path` and `prefix` are
+    /// left empty; they are only used in error messages.
+    pub fn from_parts(tree_sleep: S, sign_sleep: S, bitfield_sleep: S, data_file: Option<D>,
+                      pub_key: &[u8], secret_key: Option<&[u8]>) -> Result<SleepDirRegister<S, D>> {
+        if pub_key.len() != 32 {
+            bail!("Bad public key (len {} != 32)", pub_key.len());
+        }
+        if let Some(sk) = secret_key {
+            if sk.len() != 64 {
+                bail!("Bad secret key (len {} != 64)", sk.len());
+            }
+        }
+        let algorithm = tree_algorithm(Path::new("(tree storage)"), tree_sleep.get_algorithm())?;
+        let mut reg = SleepDirRegister {
+            tree_sleep,
+            sign_sleep,
+            bitfield_sleep,
+            data_file,
+            pub_key: pub_key.to_vec(),
+            secret_key: secret_key.map(|sk| sk.to_vec()),
+            path: PathBuf::new(),
+            prefix: String::new(),
+            sync_policy: SyncPolicy::Always,
+            unsynced: 0,
+            have: BitVec::new(),
+            bitfield_written: true,
+            checkpoint: None,
+            save_checkpoints: true,
+            data_key: None,
+            algorithm,
+        };
+        reg.check()?;
+        reg.load_bitfield()?;
+        Ok(reg)
+    }
+
     pub fn discovery_key(&self) -> Vec<u8> {
         make_discovery_key(&self.pub_key)
     }
+
+    /// Reads the data bits of the bitfield file into memory. The data bitfield is the first 1024
+    /// bytes of each (3328 byte) bitfield entry, most significant bit first, same as dat.
+    fn load_bitfield(&mut self) -> Result<()> {
+        let len = self.len()? as usize;
+        if self.bitfield_sleep.len()? == 0 {
+            // Registers written by older versions of geniza never filled in the bitfield; they
+            // are always dense.
+            self.have = BitVec::from_elem(len, true);
+            self.bitfield_written = len == 0;
+            return Ok(());
+        }
+        let mut have = BitVec::new();
+        for entry in self.bitfield_sleep.entries() {
+            have.extend(BitVec::from_bytes(&entry?[0..BITFIELD_DATA_BYTES]).iter());
+        }
+        have.truncate(len);
+        let missing = len - have.len();
+        have.grow(missing, false);
+        self.have = have;
+        self.bitfield_written = true;
+        Ok(())
+    }
+
+    /// Writes out bitfield entries covering data entries `start..end` from memory (preserving the
+    /// non-data parts of each entry).
+    fn write_bitfield(&mut self, start: u64, end: u64) -> Result<()> {
+        let (start, end) = if self.bitfield_written {
+            (start, end)
+        } else {
+            (0, cmp::max(end, self.have.len() as u64))
+        };
+        if end <= start {
+            return Ok(());
+        }
+        let page_bits = BITFIELD_DATA_BYTES as u64 * 8;
+        let existing = self.bitfield_sleep.len()?;
+        for page in cmp::min(start / page_bits, existing)..((end - 1) / page_bits + 1) {
+            let mut entry = if page < existing {
+                self.bitfield_sleep.read(page)?
+            } else {
+                vec![0; self.bitfield_sleep.get_entry_size() as usize]
+            };
+            for (i, byte) in entry[0..BITFIELD_DATA_BYTES].iter_mut().enumerate() {
+                let first = (page * page_bits) as usize + i * 8;
+                *byte = (0..8).fold(0, |acc, bit| {
+                    if self.have.get(first + bit) == Some(true) { acc | (128 >> bit) } else { acc }
+                });
+            }
+            self.bitfield_sleep.write(page, &entry)?;
+        }
+        self.bitfield_written = true;
+        Ok(())
+    }
+
+    /// Writes back a (previously cleared, or never fetched) data entry. The data must match the
+    /// tree leaf hash for that entry.
+    pub fn restore_data_entry(&mut self, index: u64, data: &[u8]) -> Result<()> {
+        if index >= self.len()? {
+            bail!("No entry {} in register", index);
+        }
+        let leaf = self.tree_sleep.read(index * 2)?;
+        if leaf[..] != self.algorithm.hash_leaf(data)[..] {
+            bail!("Data for entry {} doesn't match tree (leaf hash)", index);
+        }
+        let offset = HyperRegister::get_data_offset(self, index)?;
+        let offset = stored_data_offset(self.data_key.is_some(), index, offset);
+        if let Some(ref mut df) = self.data_file {
+            match self.data_key {
+                Some(ref key) => df.write_at(offset, &seal_entry(key, index, data))?,
+                None => df.write_at(offset, data)?,
+            };
+            if self.sync_policy == SyncPolicy::Always {
+                df.sync()?;
+            }
+        } else {
+            bail!("No data file in this register");
+        }
+        self.have.set(index as usize, true);
+        self.write_bitfield(index, index + 1)
+    }
+
+    /// Like `verify()`, but can check just part of the register, hashes and checks signatures on
+    /// `threads` worker threads, and keeps going after failures (returning all of them).
+    /// `progress`, if given, is called after each batch with (entries done, entries total).
+    ///
+    /// Tree and data reads still happen on the calling thread, so more threads only help when
+    /// hashing (not I/O) is the bottleneck, and there are cores to spare.
+    pub fn verify_entries(&mut self, mode: VerifyMode, threads: usize,
+                          mut progress: Option<&mut dyn FnMut(u64, u64)>) -> Result<VerifyReport> {
+        let len = self.len()?;
+        let mut report = VerifyReport::default();
+        let (start, end, check_data) = match mode {
+            VerifyMode::Full => (0, len, true),
+            VerifyMode::SignaturesOnly => (0, len, false),
+            VerifyMode::LastEntries(n) => (len.saturating_sub(n), len, true),
+            VerifyMode::Range(start, end) => {
+                if start > end || end > len {
+                    bail!("Can't verify entries {}..{} (register length {})", start, end, len);
+                }
+                (start, end, true)
+            },
+            VerifyMode::SinceCheckpoint => {
+                match self.checkpoint.clone() {
+                    Some(ref cp) if self.checkpoint_matches(cp)? => (cp.length, len, true),
+                    Some(_) => {
+                        report.history_changed = true;
+                        (0, len, true)
+                    },
+                    None => (0, len, true),
+                }
+            },
+        };
+        if check_data && self.data_file.is_none() {
+            warn!("No simple datafile, can't verify hashes");
+        }
+        let check_data = check_data && self.data_file.is_some();
+        let pub_key = self.pub_key.clone();
+
+        // Tree leaves and signatures are read in batches, and data offsets tracked as a running
+        // sum, instead of doing a lookup (or three) per entry
+        let mut data_offset = if check_data { HyperRegister::get_data_offset(self, start)? } else { 0 };
+        let mut batch_start = start;
+        while batch_start < end {
+            let count = cmp::min(VERIFY_BATCH, end - batch_start);
+            let tree = self.tree_sleep.read_range(batch_start * 2, count * 2 - 1)?;
+            let sigs = self.sign_sleep.read_range(batch_start, count)?;
+            let mut chunks = vec![];
+            let mut signed = vec![];
+            for j in 0..(count as usize) {
+                let i = batch_start + j as u64;
+                let leaf = &tree[(j * 2 * 40)..(j * 2 * 40 + 40)];
+                let data_len = u64::from_be(FixedInt::decode_fixed(&leaf[32..40]));
+
+                // 1. Read data (cleared entries have nothing to check)
+                if check_data && self.have.get(i as usize) == Some(true) {
+                    if data_len >= MAX_ENTRY_BYTES {
+                        warn!("Entry {} claims to be {} bytes; treating as corrupt", i, data_len);
+                        report.bad_data.push(i);
+                    } else if let Some(ref mut df) = self.data_file {
+                        let stored_offset = stored_data_offset(self.data_key.is_some(), i, data_offset);
+                        let data_chunk = match self.data_key {
+                            Some(ref key) => {
+                                let mut sealed = vec![0; (data_len + SEALED_ENTRY_OVERHEAD) as usize];
+                                df.read_at(stored_offset, &mut sealed).and_then(|_| open_entry(key, i, &sealed))
+                            },
+                            None => {
+                                let mut data_chunk = vec![0; data_len as usize];
+                                df.read_at(stored_offset, &mut data_chunk).map(|_| data_chunk)
+                            },
+                        };
+                        match data_chunk {
+                            Ok(data_chunk) => chunks.push((i, leaf.to_vec(), data_chunk)),
+                            Err(e) => {
+                                warn!("Couldn't read entry {}: {}", i, e);
+                                report.bad_data.push(i);
+                            },
+                        };
+                    }
+                }
+                data_offset = data_offset.saturating_add(data_len);
+
+                // 2. Entries from the middle of a batch are covered by a later signature
+                let sig = &sigs[(j * 64)..(j * 64 + 64)];
+                if is_unsigned(sig) {
+                    if i + 1 == len {
+                        report.bad_signatures.push(i);
+                    }
+                    continue;
+                }
+
+                // 3. Recurse up parents, hashing all parents
+                signed.push((i, HyperRegister::hash_roots(self, i)?, sig.to_vec()));
+            }
+
+            // A partial range might stop in the middle of a batch; check the signature covering it
+            if batch_start + count == end && end < len && is_unsigned(&sigs[(sigs.len() - 64)..]) {
+                let mut i = end;
+                while i + 1 < len && is_unsigned(&self.sign_sleep.read(i)?) {
+                    i += 1;
+                }
+                let sig = self.sign_sleep.read(i)?;
+                signed.push((i, HyperRegister::hash_roots(self, i)?, sig));
+            }
+
+            // 4. Check leaf hashes and signatures
+            let (mut bad_data, mut bad_sigs) = verify_jobs(&chunks, &signed, &pub_key, self.algorithm, threads);
+            report.bad_data.append(&mut bad_data);
+            // (entries that couldn't be read at all were added earlier)
+            report.bad_data.sort_unstable();
+            report.bad_signatures.append(&mut bad_sigs);
+            report.checked += count;
+            batch_start += count;
+            if let Some(ref mut cb) = progress {
+                cb(batch_start - start, end - start);
+            }
+        }
+
+        // Everything up to the end is now verified (either just now, or as of the checkpoint)
+        let complete = matches!(mode, VerifyMode::Full | VerifyMode::SinceCheckpoint);
+        if complete && report.is_ok() && len > 0 {
+            if let Err(e) = self.save_checkpoint(len) {
+                warn!("Couldn't save checkpoint (dir={} prefix={}): {}", self.path.display(), self.prefix, e);
+            }
+        }
+        Ok(report)
+    }
+
+    /// The last complete verification, if any
+    pub fn checkpoint(&self) -> Option<&Checkpoint> {
+        self.checkpoint.as_ref()
+    }
+
+    /// Checkpoint files are separate from the SLEEP files, so can be written even if the
+    /// register was opened read-only (eg, for a mirror without the secret key).
+    pub fn set_save_checkpoints(&mut self, save: bool) {
+        self.save_checkpoints = save;
+    }
+
+    fn checkpoint_path(&self) -> Option<PathBuf> {
+        if self.path.as_os_str().is_empty() {
+            None
+        } else {
+            Some(self.path.join(Path::new(&(self.prefix.clone() + ".checkpoint"))))
+        }
+    }
+
+    /// Reads the checkpoint file, if there is one. Problems are only warned about: the worst case
+    /// is verifying more than needed.
+    fn load_checkpoint(&mut self) {
+        let path = match self.checkpoint_path() {
+            Some(p) => p,
+            None => return,
+        };
+        let mut raw = vec![];
+        match File::open(&path) {
+            Ok(mut f) => {
+                if let Err(e) = f.read_to_end(&mut raw) {
+                    warn!("Couldn't read checkpoint {}: {}", path.display(), e);
+                    return;
+                }
+            },
+            Err(_) => return,
+        }
+        let cp = match Checkpoint::from_bytes(&raw, &self.pub_key) {
+            Ok(cp) => cp,
+            Err(e) => {
+                warn!("Ignoring checkpoint {}: {}", path.display(), e);
+                return;
+            },
+        };
+        match self.checkpoint_matches(&cp) {
+            Ok(true) => {},
+            _ => warn!("Register has changed since checkpoint (dir={} prefix={} length={})",
+                self.path.display(), self.prefix, cp.length),
+        }
+        self.checkpoint = Some(cp);
+    }
+
+    fn checkpoint_matches(&mut self, cp: &Checkpoint) -> Result<bool> {
+        if cp.length == 0 || cp.length > self.len()? {
+            return Ok(false);
+        }
+        Ok(HyperRegister::hash_roots(self, cp.length - 1)? == cp.roots_hash
+            && HyperRegister::get_data_offset(self, cp.length)? == cp.data_bytes)
+    }
+
+    fn save_checkpoint(&mut self, length: u64) -> Result<()> {
+        let cp = Checkpoint {
+            length,
+            data_bytes: HyperRegister::get_data_offset(self, length)?,
+            roots_hash: HyperRegister::hash_roots(self, length - 1)?,
+        };
+        if let (true, Some(path)) = (self.save_checkpoints, self.checkpoint_path()) {
+            // Write-then-rename, so there's never a partial checkpoint file
+            let tmp_path = path.with_extension("checkpoint.tmp");
+            let mut f = File::create(&tmp_path)?;
+            f.write_all(&cp.to_bytes(&self.pub_key))?;
+            f.sync_data()?;
+            rename(&tmp_path, &path)?;
+        }
+        self.checkpoint = Some(cp);
+        Ok(())
+    }
+
+    /// Builds a proof for a single entry (against the full current length of the register).
+    /// The entry's data is included if `with_data` is set (and we have it).
+    pub fn prove(&mut self, index: u64, with_data: bool) -> Result<RegisterProof> {
+        let length = self.len()?;
+        if index >= length {
+            bail!("No entry {} in register (length {})", index, length);
+        }
+        let signature = self.sign_sleep.read(length - 1)?;
+        if is_unsigned(&signature) {
+            bail!("Last entry ({}) is unsigned", length - 1);
+        }
+        let root_indices = HyperRegister::tree_root_nodes(length);
+        let mut roots = vec![];
+        for ri in &root_indices {
+            roots.push((*ri, self.tree_sleep.read(*ri)?));
+        }
+        let mut uncles = vec![];
+        let mut tree_index = index * 2;
+        while !root_indices.contains(&tree_index) {
+            let parent = HyperRegister::tree_parent_index(tree_index);
+            let (left, right) = HyperRegister::tree_child_indices(parent)?;
+            let uncle = if left == tree_index { right } else { left };
+            uncles.push((uncle, self.tree_sleep.read(uncle)?));
+            tree_index = parent;
+        }
+        let data = if with_data && self.has(index)? {
+            Some(self.get_data_entry(index)?)
+        } else {
+            None
+        };
+        Ok(RegisterProof {
+            pub_key: self.pub_key.clone(),
+            algorithm: self.algorithm,
+            index,
+            length,
+            leaf: self.tree_sleep.read(index * 2)?,
+            uncles,
+            roots,
+            signature,
+            data,
+        })
+    }
+
+    pub fn set_sync_policy(&mut self, policy: SyncPolicy) {
+        self.sync_policy = policy;
+    }
+
+    /// Flushes all writes to durable storage: data, then tree and bitfield, then signatures (the
+    /// same order they are written in, so a crash never leaves a signature pointing at missing
+    /// data).
+    pub fn sync(&mut self) -> Result<()> {
+        if let Some(ref mut df) = self.data_file {
+            df.sync()?;
+        }
+        self.tree_sleep.sync()?;
+        self.bitfield_sleep.sync()?;
+        self.sign_sleep.sync()?;
+        self.unsynced = 0;
+        Ok(())
+    }
 }
 
-impl HyperRegister for SleepDirRegister {
-    /// TODO: this version only works for "dense" registers: it just checks if the index is in the
-    /// total length, instead of using the bitfield.
+impl<S: SleepStorage, D: DataStorage> HyperRegister for SleepDirRegister<S, D> {
     fn has(&self, entry_index: u64) -> Result<bool> {
-        return Ok(entry_index < self.len()?);
+        Ok(self.have.get(entry_index as usize) == Some(true))
     }
 
     fn has_all(&self) -> Result<bool> {
-        self.has_range(0, self.len()?)
+        let len = self.len()?;
+        if len == 0 {
+            return Ok(true);
+        }
+        self.has_range(0, len)
     }
 
     fn has_range(&self, start: u64, end: u64) -> Result<bool> {
@@ -409,15 +1853,19 @@ impl HyperRegister for SleepDirRegister {
         };
         let leaf = self.tree_sleep.read(index * 2)?;
         let data_len = u64::from_be(FixedInt::decode_fixed(&leaf[32..40]));
-        // avoid foot-gun in development: cap at ~1 billion bytes
-        assert!(data_len < 2u64.pow(29));
+        if data_len >= MAX_ENTRY_BYTES {
+            bail!("Entry {} claims to be {} bytes; refusing to read it", index, data_len);
+        }
 
         // Read chunk
-        let mut data = vec![0; data_len as usize];
-        let got = data_file.read_at(&mut data, offset)?;
-        if got != data.len() {
-            bail!("Short file read");
+        let offset = stored_data_offset(self.data_key.is_some(), index, offset);
+        if let Some(ref key) = self.data_key {
+            let mut sealed = vec![0; (data_len + SEALED_ENTRY_OVERHEAD) as usize];
+            data_file.read_at(offset, &mut sealed)?;
+            return open_entry(key, index, &sealed);
         }
+        let mut data = vec![0; data_len as usize];
+        data_file.read_at(offset, &mut data)?;
 
         // TODO: check the hash? separate function?
         Ok(data)
@@ -428,39 +1876,81 @@ impl HyperRegister for SleepDirRegister {
     }
 
     fn append(&mut self, data: &[u8]) -> Result<u64> {
-        if !self.data_file.is_some() {
+        self.append_batch(&[data])
+    }
+
+    /// All data is written (and flushed) first, then all tree nodes and bitfield, then signatures. Only the
+    /// last entry of the batch gets a real signature; the rest get an all-zeros placeholder.
+    fn append_batch(&mut self, chunks: &[&[u8]]) -> Result<u64> {
+        if self.data_file.is_none() {
             bail!("No data file in this register");
         };
+        let secret_key = if let Some(ref key) = self.secret_key {
+            key.clone()
+        } else {
+            bail!("Can't append to register without secret key");
+        };
+        let first = self.len()?;
+        if chunks.is_empty() {
+            return Ok(first);
+        }
+        let always = self.sync_policy == SyncPolicy::Always;
 
-        let index = self.len()?;
-        // 1. Hash data chunk
-        let leaf_hash = HyperRegister::hash_leaf(data);
-
-        // 2. Append data to data file
+        // 1. Append data to data file
         if let Some(ref mut df) = self.data_file {
-            df.seek(SeekFrom::End(0))?;
-            df.write_all(data)?;
-            df.sync_data()?;
+            for (i, data) in chunks.iter().enumerate() {
+                match self.data_key {
+                    Some(ref key) => df.append(&seal_entry(key, first + i as u64, data))?,
+                    None => df.append(data)?,
+                };
+            }
+            if always {
+                df.sync()?;
+            }
         }
 
-        // 3. Add hash to tree file, update merkel tree
-        self.tree_sleep.write(index * 2, &leaf_hash)?;
-        let mut parent = HyperRegister::tree_parent_index(index * 2);
-        while parent < index * 2 {
-            let (left, right) = HyperRegister::tree_child_indices(parent)?;
-            let (left, right) = (self.tree_sleep.read(left)?, self.tree_sleep.read(right)?);
-            let parent_hash = HyperRegister::hash_parent(&left[0..40], &right[0..40]);
-            self.tree_sleep.write(parent, &parent_hash[0..40])?;
-            parent = HyperRegister::tree_parent_index(parent);
+        // 2. Hash data chunks, add to tree file, update merkel tree
+        for (i, data) in chunks.iter().enumerate() {
+            let index = first + i as u64;
+            let leaf_hash = self.algorithm.hash_leaf(data);
+            self.tree_sleep.write(index * 2, &leaf_hash)?;
+            let mut parent = HyperRegister::tree_parent_index(index * 2);
+            while parent < index * 2 {
+                let (left, right) = HyperRegister::tree_child_indices(parent)?;
+                let (left, right) = (self.tree_sleep.read(left)?, self.tree_sleep.read(right)?);
+                let parent_hash = self.algorithm.hash_parent(&left[0..40], &right[0..40]);
+                self.tree_sleep.write(parent, &parent_hash[0..40])?;
+                parent = HyperRegister::tree_parent_index(parent);
+            }
         }
 
-        // 4. Add signature to signature file
-        let root_hash = HyperRegister::hash_roots(self, index)?;
-        let root_sig = ed25519::signature(&root_hash, &self.secret_key.clone().unwrap());
-        self.sign_sleep.append(&root_sig)?;
+        // 3. Update bitfield
+        for _ in chunks {
+            self.have.push(true);
+        }
+        self.write_bitfield(first, first + chunks.len() as u64)?;
+        if always {
+            self.tree_sleep.sync()?;
+            self.bitfield_sleep.sync()?;
+        }
 
-        // 5. Update bitfile
-        Ok(index)
+        // 4. Add signatures to signature file
+        let last = first + chunks.len() as u64 - 1;
+        let root_hash = HyperRegister::hash_roots(self, last)?;
+        let mut sigs = vec![0; (chunks.len() - 1) * 64];
+        sigs.extend_from_slice(&ed25519::signature(&root_hash, &secret_key));
+        self.sign_sleep.write_range(first, &sigs)?;
+        match self.sync_policy {
+            SyncPolicy::Always => self.sign_sleep.sync()?,
+            SyncPolicy::Batched(n) => {
+                self.unsynced += chunks.len() as u64;
+                if self.unsynced >= n {
+                    self.sync()?;
+                }
+            },
+            SyncPolicy::Never => {},
+        }
+        Ok(first)
     }
 
     fn len(&self) -> Result<u64> {
@@ -476,41 +1966,17 @@ impl HyperRegister for SleepDirRegister {
     }
 
     fn len_bytes(&mut self) -> Result<u64> {
-        // TODO: this is a naive (linear) implementation
-        // log(N) would go up previous parent nodes (eg, use tree_root_nodes())
-        let mut sum: u64 = 0;
-        for i in 0..self.len()? {
-            let leaf = self.get_tree_entry(i * 2)?;
-            sum += u64::from_be(FixedInt::decode_fixed(&leaf[32..40]));
-        }
-        Ok(sum)
+        let len = self.len()?;
+        HyperRegister::get_data_offset(self, len)
     }
 
     fn verify(&mut self) -> Result<()> {
-        for i in 0..self.len()? {
-
-            if let Some(_) = self.data_file {
-                // 1. Read and hash data
-                let data_chunk = self.get_data_entry(i)?;
-                let leaf_recalc = HyperRegister::hash_leaf(&data_chunk);
-
-                // 2. Check tree leaf hash for this chunk
-                let leaf = self.get_tree_entry(i * 2)?;
-                if leaf.to_vec() != leaf_recalc.to_vec() {
-                    bail!("Data chunk {} failed verification (leaf hash)", i);
-                }
-            } else {
-                warn!("No simple datafile, can't verify hashes");
-            }
-
-            // 3. Recurse up parents, hashing all parents
-            let rehash = HyperRegister::hash_roots(self, i)?;
-
-            // 4. Read and verify signature in file
-            let sig = self.sign_sleep.read(i)?;
-            if !ed25519::verify(&rehash, &self.pub_key, &sig) {
-                bail!("Failed to verify signature for chunk {}", i)
-            }
+        let report = self.verify_entries(VerifyMode::Full, 1, None)?;
+        if let Some(i) = report.bad_data.first() {
+            bail!("Data chunk {} failed verification (leaf hash)", i);
+        }
+        if let Some(i) = report.bad_signatures.first() {
+            bail!("Failed to verify signature for chunk {}", i);
         }
         Ok(())
     }
@@ -524,9 +1990,9 @@ impl HyperRegister for SleepDirRegister {
         if tree_len != (sign_len * 2) - 1 {
             bail!("Inconsistent SLEEP signature/tree file sizes");
         }
-        let computed = self.len_bytes()?;
+        let computed = stored_data_offset(self.data_key.is_some(), sign_len, self.len_bytes()?);
         if let Some(ref df) = self.data_file {
-            let file_size = df.metadata()?.len();
+            let file_size = df.len()?;
             if file_size != computed {
                 bail!("Computed vs. data file size mismatch ({} != {}; path={} prefix={})",
                     computed, file_size, self.path.display(), self.prefix);
@@ -539,6 +2005,47 @@ impl HyperRegister for SleepDirRegister {
     fn writable(&self) -> bool {
         return self.secret_key.is_some();
     }
+
+    fn pub_key(&self) -> &[u8] {
+        &self.pub_key
+    }
+
+    fn hash_algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    /// Data is zeroed in place (so offsets of later entries don't change) and the bitfield
+    /// updated. Doesn't require the secret key.
+    fn clear(&mut self, start: u64, end: u64) -> Result<()> {
+        let len = self.len()?;
+        if start > end || end > len {
+            bail!("Can't clear entries {}..{} (register length {})", start, end, len);
+        }
+        let mut offset = HyperRegister::get_data_offset(self, start)?;
+        for i in start..end {
+            let leaf = self.tree_sleep.read(i * 2)?;
+            let data_len = u64::from_be(FixedInt::decode_fixed(&leaf[32..40]));
+            if self.have.get(i as usize) == Some(true) {
+                if let Some(ref mut df) = self.data_file {
+                    if self.data_key.is_some() {
+                        df.clear(stored_data_offset(true, i, offset), data_len + SEALED_ENTRY_OVERHEAD)?;
+                    } else {
+                        df.clear(offset, data_len)?;
+                    }
+                }
+                self.have.set(i as usize, false);
+            }
+            offset += data_len;
+        }
+        self.write_bitfield(start, end)?;
+        if self.sync_policy == SyncPolicy::Always {
+            if let Some(ref mut df) = self.data_file {
+                df.sync()?;
+            }
+            self.bitfield_sleep.sync()?;
+        }
+        Ok(())
+    }
 }
 
 #[test]
@@ -582,6 +2089,636 @@ fn test_sdr_open() {
 
 }
 
+#[test]
+fn test_mmap_register() {
+    use tempdir::TempDir;
+    let tmp_dir = TempDir::new("geniza-test").unwrap();
+    let mut sdr = SleepDirRegister::create(tmp_dir.path(), "dummy").unwrap();
+    for _ in 0..10 {
+        sdr.append(&[1, 2, 3, 4, 5]).unwrap();
+    }
+    drop(sdr);
+
+    let mut mr = MmapRegister::open_mmap(tmp_dir.path(), "dummy", true).unwrap();
+    assert!(mr.verify().is_ok());
+    mr.append("hello world!".as_bytes()).unwrap();
+    assert!(mr.check().is_ok());
+    assert!(mr.verify().is_ok());
+    assert_eq!(mr.len().unwrap(), 11);
+    assert_eq!(mr.len_bytes().unwrap(), 50 + 12);
+
+    let mut mr = MmapRegister::open_mmap(Path::new("test-data/dat/alphabet/.dat/"), "content", false).unwrap();
+    assert!(mr.verify().is_ok());
+    assert_eq!(mr.len().unwrap(), 6);
+}
+
+#[test]
+fn test_cached_register() {
+    let mut cr = CachedRegister::open_cached(Path::new("test-data/dat/alphabet/.dat/"), "metadata", false, 64).unwrap();
+    assert_eq!(cr.tree_cache_stats().hits, 0);
+    assert!(cr.verify().is_ok());
+    assert_eq!(cr.len_bytes().unwrap(), 307);
+    let stats = cr.tree_cache_stats();
+    // 7 entries means 13 tree nodes; everything after the first read of each is a hit
+    assert!(stats.misses <= 13);
+    assert!(stats.hits > stats.misses);
+}
+
+#[test]
+fn test_sdr_recover() {
+    use tempdir::TempDir;
+    use std::fs::OpenOptions;
+    let tmp_dir = TempDir::new("geniza-test").unwrap();
+    let dir = tmp_dir.path();
+    let mut sdr = SleepDirRegister::create(dir, "dummy").unwrap();
+    sdr.set_sync_policy(SyncPolicy::Batched(4));
+    for _ in 0..5 {
+        sdr.append(&[1, 2, 3, 4, 5]).unwrap();
+    }
+    sdr.sync().unwrap();
+    drop(sdr);
+
+    let (_, report) = SleepDirRegister::open_recover(dir, "dummy").unwrap();
+    assert!(report.is_clean());
+    assert_eq!(report.entries, 5);
+
+    // Simulate a crash after the data and tree leaf of a 6th entry were written (but before
+    // parent nodes or the signature), plus a torn signature write
+    let append_raw = |suffix: &str, raw: &[u8]| {
+        let mut f = OpenOptions::new().append(true)
+            .open(dir.join(Path::new(&("dummy".to_owned() + suffix)))).unwrap();
+        f.write_all(raw).unwrap();
+    };
+    append_raw(".data", &[6; 7]);
+    append_raw(".tree", &[0; 80]);
+    append_raw(".signatures", &[0; 10]);
+    assert!(SleepDirRegister::open_unchecked(dir, "dummy", false, true, None)
+        .and_then(|mut sdr| sdr.check()).is_err());
+
+    let (mut sdr, report) = SleepDirRegister::open_recover(dir, "dummy").unwrap();
+    assert_eq!(report, RecoveryReport {
+        entries: 5,
+        partial_bytes: 10,
+        dropped_tree_entries: 2,
+        dropped_signatures: 0,
+        dropped_data_bytes: 7,
+        missing_data_entries: 0,
+        dropped_checkpoint: false,
+    });
+    assert!(sdr.verify().is_ok());
+    assert_eq!(sdr.append(&[6; 7]).unwrap(), 5);
+    drop(sdr);
+    assert!(SleepDirRegister::open(dir, "dummy", false).unwrap().verify().is_ok());
+
+    // Writable opens repair torn appends too; read-only opens leave the files alone
+    append_raw(".data", &[7; 3]);
+    append_raw(".tree", &[0; 40]);
+    let tree_len = dir.join("dummy.tree").metadata().unwrap().len();
+    assert!(SleepDirRegister::open(dir, "dummy", false).is_err());
+    assert_eq!(dir.join("dummy.tree").metadata().unwrap().len(), tree_len);
+    let mut sdr = SleepDirRegister::open(dir, "dummy", true).unwrap();
+    assert_eq!(sdr.len().unwrap(), 6);
+    assert!(sdr.verify().is_ok());
+}
+
+#[test]
+fn test_sdr_recover_without_secret_key() {
+    use tempdir::TempDir;
+    use std::fs::{self, OpenOptions};
+    let tmp_dir = TempDir::new("geniza-test").unwrap();
+    let dir = tmp_dir.path();
+    let mut sdr = SleepDirRegister::create(dir, "dummy").unwrap();
+    for i in 0..5 {
+        sdr.append(&[i; 10]).unwrap();
+    }
+    assert!(sdr.verify_entries(VerifyMode::Full, 1, None).unwrap().is_ok());
+    assert!(sdr.checkpoint().is_some());
+    drop(sdr);
+    fs::remove_file(dir.join("dummy.secret_key")).unwrap();
+
+    // A torn download: the last signature never arrived, the last tree write was partial, and
+    // data stops part way through entry 3
+    let set_len = |suffix: &str, len: u64| {
+        OpenOptions::new().write(true).open(dir.join(Path::new(&("dummy".to_owned() + suffix))))
+            .unwrap().set_len(len).unwrap();
+    };
+    set_len(".signatures", 32 + 64 * 4);
+    set_len(".tree", 32 + 40 * 8 + 10);
+    set_len(".data", 32);
+
+    let (mut sdr, report) = SleepDirRegister::open_recover(dir, "dummy").unwrap();
+    assert_eq!(report, RecoveryReport {
+        entries: 4,
+        partial_bytes: 10,
+        dropped_tree_entries: 1,
+        dropped_signatures: 0,
+        dropped_data_bytes: 0,
+        missing_data_entries: 1,
+        dropped_checkpoint: true,
+    });
+    assert!(!sdr.writable());
+    assert!(sdr.checkpoint().is_none());
+    assert!(!dir.join("dummy.checkpoint").exists());
+    assert_eq!(sdr.get_data_entry(2).unwrap(), vec![2; 10]);
+    assert!(!sdr.has(3).unwrap());
+    assert!(sdr.verify_entries(VerifyMode::SignaturesOnly, 1, None).unwrap().is_ok());
+    drop(sdr);
+
+    let sdr = SleepDirRegister::open(dir, "dummy", false).unwrap();
+    assert_eq!(sdr.len().unwrap(), 4);
+    assert!(!sdr.has(3).unwrap());
+    drop(sdr);
+
+    // The missing data can still be filled in
+    let (mut sdr, report) = SleepDirRegister::open_recover(dir, "dummy").unwrap();
+    assert!(report.is_clean());
+    sdr.restore_data_entry(3, &[3; 10]).unwrap();
+    assert!(sdr.verify().is_ok());
+}
+
+#[test]
+fn test_sdr_encrypt_data() {
+    use tempdir::TempDir;
+    use std::fs::{self, OpenOptions};
+    let tmp_dir = TempDir::new("geniza-test").unwrap();
+    let dir = tmp_dir.path();
+    let mut ks = DirKeyStore::new(dir.join("keys"));
+    let mut sdr = SleepDirRegister::create_with_keystore(dir, "dummy", &mut ks).unwrap();
+    sdr.append(b"plain text one").unwrap();
+    sdr.append(b"plain text two").unwrap();
+    sdr.encrypt_data(&mut ks).unwrap();
+    assert!(dir.join("dummy.data.encrypted").is_file());
+    assert!(sdr.encrypt_data(&mut ks).is_err());
+    sdr.append_batch(&[b"plain text three", b"", b"plain text five"]).unwrap();
+    assert_eq!(sdr.get_data_entry(0).unwrap(), b"plain text one");
+    assert_eq!(sdr.get_data_entry(4).unwrap(), b"plain text five");
+    sdr.clear(1, 2).unwrap();
+    drop(sdr);
+    let raw = fs::read(dir.join("dummy.data")).unwrap();
+    assert!(!raw.windows(10).any(|w| w == b"plain text"));
+
+    let mut sdr = SleepDirRegister::open_with_keystore(dir, "dummy", true, &ks).unwrap();
+    assert_eq!(sdr.get_data_entry(2).unwrap(), b"plain text three");
+    assert!(sdr.get_data_entry(1).is_err());
+    sdr.restore_data_entry(1, b"plain text two").unwrap();
+    assert_eq!(sdr.get_data_entry(1).unwrap(), b"plain text two");
+    assert!(sdr.verify_entries(VerifyMode::Full, 1, None).unwrap().is_ok());
+    let proof = sdr.prove(3, true).unwrap();
+    assert_eq!(proof.data, Some(vec![]));
+    drop(sdr);
+
+    // Without the data key: read-only opens still have tree and signatures
+    let no_keys = DirKeyStore::new(dir.join("no_keys"));
+    assert!(SleepDirRegister::open_with_keystore(dir, "dummy", true, &no_keys).is_err());
+    let mut sdr = SleepDirRegister::open_with_keystore(dir, "dummy", false, &no_keys).unwrap();
+    assert!(sdr.get_data_entry(0).is_err());
+    assert!(sdr.verify_entries(VerifyMode::SignaturesOnly, 1, None).unwrap().is_ok());
+    drop(sdr);
+
+    // Tampering is caught
+    let mut raw = fs::read(dir.join("dummy.data")).unwrap();
+    raw[40] ^= 0x01;
+    fs::write(dir.join("dummy.data"), &raw).unwrap();
+    let mut sdr = SleepDirRegister::open_with_keystore(dir, "dummy", false, &ks).unwrap();
+    assert!(sdr.get_data_entry(0).is_err());
+    assert_eq!(sdr.verify_entries(VerifyMode::Full, 1, None).unwrap().bad_data, vec![0]);
+    raw[40] ^= 0x01;
+    fs::write(dir.join("dummy.data"), &raw).unwrap();
+    drop(sdr);
+
+    // Crash recovery accounts for the per-chunk overhead
+    let mut f = OpenOptions::new().append(true).open(dir.join("dummy.data")).unwrap();
+    f.write_all(&[6; 50]).unwrap();
+    drop(f);
+    let (mut sdr, report) = SleepDirRegister::open_recover_with_keystore(dir, "dummy", &ks).unwrap();
+    assert_eq!(report.entries, 5);
+    assert_eq!(report.dropped_data_bytes, 50);
+    assert!(sdr.verify().is_ok());
+    assert_eq!(sdr.get_data_entry(4).unwrap(), b"plain text five");
+}
+
+#[test]
+fn test_sdr_encrypt_data_plain_magic() {
+    use tempdir::TempDir;
+    let tmp_dir = TempDir::new("geniza-test").unwrap();
+    let dir = tmp_dir.path();
+    let mut ks = DirKeyStore::new(dir.join("keys"));
+    let mut sdr = SleepDirRegister::create_with_keystore(dir, "dummy", &mut ks).unwrap();
+    sdr.append(b"GZEN, but plain").unwrap();
+    sdr.append(b"more plain text").unwrap();
+
+    // A failed encryption leaves no key, temporary file or marker behind (here, because the key
+    // store already has a different key)
+    let stray_key = secretbox::gen_key();
+    ks.put_data_key(&sdr.pub_key, &stray_key.0).unwrap();
+    assert!(sdr.encrypt_data(&mut ks).is_err());
+    assert!(!dir.join("dummy.data.tmp").exists());
+    assert!(!dir.join("dummy.data.encrypted").exists());
+    drop(sdr);
+
+    // Even with a data key around, plain data starting with the magic number stays plain
+    let mut sdr = SleepDirRegister::open_with_keystore(dir, "dummy", true, &ks).unwrap();
+    assert_eq!(sdr.get_data_entry(0).unwrap(), b"GZEN, but plain");
+    assert!(sdr.verify().is_ok());
+}
+
+#[test]
+fn test_sdr_append_batch() {
+    use tempdir::TempDir;
+    use std::fs::OpenOptions;
+    let tmp_dir = TempDir::new("geniza-test").unwrap();
+    let dir = tmp_dir.path();
+    let mut sdr = SleepDirRegister::create(dir, "dummy").unwrap();
+    sdr.append(&[1; 10]).unwrap();
+    assert_eq!(sdr.append_batch(&[&[2; 20], &[3; 30], &[4; 40]]).unwrap(), 1);
+    assert_eq!(sdr.append_batch(&[]).unwrap(), 4);
+    assert_eq!(sdr.len().unwrap(), 4);
+    assert_eq!(sdr.len_bytes().unwrap(), 100);
+    assert_eq!(sdr.get_data_entry(2).unwrap(), vec![3; 30]);
+    assert!(is_unsigned(&sdr.sign_sleep.read(1).unwrap()));
+    assert!(is_unsigned(&sdr.sign_sleep.read(2).unwrap()));
+    assert!(!is_unsigned(&sdr.sign_sleep.read(3).unwrap()));
+    sdr.check().unwrap();
+    sdr.verify().unwrap();
+
+    // Same tree as appending one at a time
+    let mut mem = MemoryRegister::with_keys(&sdr.pub_key, sdr.secret_key.as_ref().map(|k| &k[..])).unwrap();
+    for i in 1..5 {
+        mem.append(&vec![i; i as usize * 10]).unwrap();
+    }
+    for i in 0..7 {
+        assert_eq!(mem.get_tree_entry(i).unwrap(), sdr.get_tree_entry(i).unwrap());
+    }
+    drop(sdr);
+
+    // A batch torn before its final signature is rolled back entirely
+    let f = OpenOptions::new().write(true)
+        .open(dir.join("dummy.signatures")).unwrap();
+    f.set_len(32 + 64 * 3).unwrap();
+    let (mut sdr, report) = SleepDirRegister::open_recover(dir, "dummy").unwrap();
+    assert_eq!(report.entries, 1);
+    assert_eq!(report.dropped_data_bytes, 90);
+    sdr.verify().unwrap();
+}
+
+#[test]
+fn test_data_offsets() {
+    let mut mr = MemoryRegister::new().unwrap();
+    let mut offsets = vec![0];
+    for i in 0..37 {
+        mr.append(&vec![7; i * 3 + 1]).unwrap();
+        let last = offsets[offsets.len() - 1];
+        offsets.push(last + (i as u64 * 3 + 1));
+        assert_eq!(mr.len_bytes().unwrap(), offsets[i + 1]);
+    }
+    for (i, offset) in offsets.iter().enumerate() {
+        assert_eq!(HyperRegister::get_data_offset(&mut mr, i as u64).unwrap(), *offset);
+    }
+    assert_eq!(mr.get_data_entry(20).unwrap(), vec![7; 61]);
+}
+
+#[test]
+fn test_data_offsets_sparse() {
+    use tempdir::TempDir;
+    use std::fs::OpenOptions;
+    let tmp_dir = TempDir::new("geniza-test").unwrap();
+    let dir = tmp_dir.path();
+    let mut sdr = SleepDirRegister::create(dir, "dummy").unwrap();
+    let mut offsets = vec![0];
+    for i in 0..11 {
+        sdr.append(&vec![7; i * 3 + 1]).unwrap();
+        let last = offsets[offsets.len() - 1];
+        offsets.push(last + (i as u64 * 3 + 1));
+    }
+    drop(sdr);
+
+    // A sparse clone that never got parent nodes 3 (entries 0..4) or 7 (entries 0..8)
+    let mut tree = OpenOptions::new().write(true).open(dir.join("dummy.tree")).unwrap();
+    for tree_index in &[3, 7] {
+        tree.write_at(32 + 40 * tree_index, &[0; 40]).unwrap();
+    }
+    drop(tree);
+    let mut sdr = SleepDirRegister::open(dir, "dummy", false).unwrap();
+    for (i, offset) in offsets.iter().enumerate() {
+        assert_eq!(HyperRegister::get_data_offset(&mut sdr, i as u64).unwrap(), *offset);
+    }
+    assert_eq!(sdr.get_data_entry(9).unwrap(), vec![7; 28]);
+
+    // ... but leaves are needed
+    let mut tree = OpenOptions::new().write(true).open(dir.join("dummy.tree")).unwrap();
+    tree.write_at(32 + 40 * 2, &[0; 40]).unwrap();
+    drop(tree);
+    assert!(HyperRegister::get_data_offset(&mut sdr, 9).is_err());
+}
+
+#[test]
+fn test_sdr_keystore() {
+    use tempdir::TempDir;
+    let tmp_dir = TempDir::new("geniza-test").unwrap();
+    let dir = tmp_dir.path();
+    let mut ks = DirKeyStore::new(dir.join("secret_keys"));
+    let mut sdr = SleepDirRegister::create_with_keystore(dir, "dummy", &mut ks).unwrap();
+    sdr.append(&[1; 10]).unwrap();
+    assert!(!dir.join("dummy.secret_key").exists());
+    drop(sdr);
+    let mut sdr = SleepDirRegister::open_with_keystore(dir, "dummy", true, &ks).unwrap();
+    sdr.append(&[2; 10]).unwrap();
+    sdr.verify().unwrap();
+    drop(sdr);
+    let other_ks = DirKeyStore::new(dir.join("other_keys"));
+    assert!(SleepDirRegister::open_with_keystore(dir, "dummy", true, &other_ks).is_err());
+    assert!(SleepDirRegister::open_with_keystore(dir, "dummy", false, &other_ks).is_ok());
+
+    // Existing registers can have their keys moved out
+    let mut sdr = SleepDirRegister::create(dir, "moved").unwrap();
+    sdr.append(&[3; 10]).unwrap();
+    assert!(dir.join("moved.secret_key").is_file());
+    sdr.move_secret_key(&mut ks).unwrap();
+    assert!(!dir.join("moved.secret_key").exists());
+    drop(sdr);
+    let mut sdr = SleepDirRegister::open_with_keystore(dir, "moved", true, &ks).unwrap();
+    sdr.append(&[4; 10]).unwrap();
+    sdr.verify().unwrap();
+}
+
+#[test]
+fn test_sdr_clear() {
+    use tempdir::TempDir;
+    use std::fs::OpenOptions;
+    let tmp_dir = TempDir::new("geniza-test").unwrap();
+    let dir = tmp_dir.path();
+    let mut sdr = SleepDirRegister::create(dir, "dummy").unwrap();
+    for i in 0..5 {
+        sdr.append(&[i; 10]).unwrap();
+    }
+    sdr.clear(1, 3).unwrap();
+    assert!(sdr.clear(4, 6).is_err());
+    assert!(sdr.has(0).unwrap());
+    assert!(!sdr.has(1).unwrap());
+    assert!(!sdr.has(2).unwrap());
+    assert!(sdr.get_data_entry(1).is_err());
+    assert_eq!(sdr.get_data_entry(3).unwrap(), vec![3; 10]);
+    sdr.verify().unwrap();
+    drop(sdr);
+
+    // Persisted in the bitfield, and data zeroed
+    let mut sdr = SleepDirRegister::open(dir, "dummy", true).unwrap();
+    assert!(!sdr.has(1).unwrap());
+    assert!(sdr.has(3).unwrap());
+    let mut data = vec![];
+    File::open(dir.join("dummy.data")).unwrap().read_to_end(&mut data).unwrap();
+    assert_eq!(&data[10..30], &[0; 20][..]);
+
+    // Can be restored, but only with the right data
+    assert!(sdr.restore_data_entry(1, &[9; 10]).is_err());
+    sdr.restore_data_entry(1, &[1; 10]).unwrap();
+    assert_eq!(sdr.get_data_entry(1).unwrap(), vec![1; 10]);
+    assert!(!sdr.has(2).unwrap());
+    sdr.verify().unwrap();
+    drop(sdr);
+
+    // Registers without a bitfield (eg, from older versions) are dense
+    let f = OpenOptions::new().write(true).open(dir.join("dummy.bitfield")).unwrap();
+    f.set_len(32).unwrap();
+    let mut sdr = SleepDirRegister::open(dir, "dummy", true).unwrap();
+    assert!(sdr.has_all().unwrap());
+    sdr.clear(4, 5).unwrap();
+    drop(sdr);
+    let sdr = SleepDirRegister::open(dir, "dummy", false).unwrap();
+    assert!(sdr.has_range(0, 4).unwrap());
+    assert!(!sdr.has(4).unwrap());
+}
+
+#[test]
+fn test_register_proof() {
+    let mut mr = MemoryRegister::new().unwrap();
+    for i in 0..11 {
+        mr.append(&[i; 7]).unwrap();
+    }
+    for i in 0..11 {
+        let proof = mr.prove(i, true).unwrap();
+        assert_eq!(proof.data, Some(vec![i as u8; 7]));
+        verify_proof(&proof).unwrap();
+        let decoded = RegisterProof::from_bytes(&proof.to_bytes()).unwrap();
+        assert_eq!(decoded, proof);
+        verify_proof(&decoded).unwrap();
+    }
+    assert!(mr.prove(11, false).is_err());
+
+    // Tampering with any part fails
+    let proof = mr.prove(5, true).unwrap();
+    let mut bad = proof.clone();
+    bad.data = Some(vec![9; 7]);
+    assert!(verify_proof(&bad).is_err());
+    let mut bad = proof.clone();
+    bad.uncles[0].1[0] ^= 1;
+    assert!(verify_proof(&bad).is_err());
+    let mut bad = proof.clone();
+    bad.index = 4;
+    assert!(verify_proof(&bad).is_err());
+    let mut bad = proof.clone();
+    bad.pub_key = MemoryRegister::new().unwrap().pub_key;
+    assert!(verify_proof(&bad).is_err());
+    let mut bad = proof.clone();
+    bad.algorithm = HashAlgorithm::Sha256;
+    assert!(verify_proof(&bad).is_err());
+    let mut raw = proof.to_bytes();
+    raw.pop();
+    assert!(RegisterProof::from_bytes(&raw).is_err());
+
+    // Older proofs, without the algorithm name, are BLAKE2b
+    let mut legacy = b"GZPF".to_vec();
+    legacy.extend_from_slice(&proof.to_bytes()[(4 + 1 + 7)..]);
+    assert_eq!(RegisterProof::from_bytes(&legacy).unwrap(), proof);
+
+    // Crafted proofs are rejected (without panicking)
+    let mut no_data = proof.clone();
+    no_data.data = None;
+    let mut raw = no_data.to_bytes();
+    raw.pop();
+    raw.push(1);
+    encode_u64(&mut raw, u64::MAX);
+    assert!(RegisterProof::from_bytes(&raw).is_err());
+    let mut bad = proof.clone();
+    bad.leaf.truncate(39);
+    assert!(verify_proof(&bad).is_err());
+    let mut bad = proof.clone();
+    bad.uncles[1].1 = vec![];
+    assert!(verify_proof(&bad).is_err());
+    let mut bad = proof.clone();
+    bad.roots[0].1.push(0);
+    assert!(verify_proof(&bad).is_err());
+    let mut bad = proof.clone();
+    bad.index = u64::MAX - 1;
+    bad.length = u64::MAX;
+    assert!(verify_proof(&bad).is_err());
+}
+
+#[test]
+fn test_hash_algorithm() {
+    use tempdir::TempDir;
+    let tmp_dir = TempDir::new("geniza-test").unwrap();
+    let dir = tmp_dir.path();
+    assert_eq!(HashAlgorithm::from_name(Some("SHA256")).unwrap(), HashAlgorithm::Sha256);
+    assert!(HashAlgorithm::from_name(Some("MD5")).is_err());
+    assert!(HashAlgorithm::from_name(None).is_err());
+
+    let mut sdr = SleepDirRegister::create_with_algorithm(dir, "sha", HashAlgorithm::Sha256).unwrap();
+    for i in 0..5 {
+        sdr.append(&[i; 9]).unwrap();
+    }
+    assert_eq!(sdr.tree_sleep.get_algorithm(), Some("SHA256".to_string()));
+    let proof = sdr.prove(3, true).unwrap();
+    assert_eq!(proof.algorithm, HashAlgorithm::Sha256);
+    verify_proof(&RegisterProof::from_bytes(&proof.to_bytes()).unwrap()).unwrap();
+    drop(sdr);
+
+    let mut sdr = SleepDirRegister::open(dir, "sha", false).unwrap();
+    assert_eq!(sdr.hash_algorithm(), HashAlgorithm::Sha256);
+    assert!(sdr.verify().is_ok());
+
+    // Same key and data, different tree
+    let (secret_key, pub_key) = keypair_from_seed(&[3; 32]).unwrap();
+    let mut blake = MemoryRegister::with_keys(&pub_key, Some(&secret_key)).unwrap();
+    let mut sha = MemoryRegister::with_keys_and_algorithm(&pub_key, Some(&secret_key),
+                                                          HashAlgorithm::Sha256).unwrap();
+    blake.append(&[1; 9]).unwrap();
+    sha.append(&[1; 9]).unwrap();
+    assert_eq!(sha.hash_algorithm(), HashAlgorithm::Sha256);
+    assert_ne!(blake.get_tree_entry(0).unwrap(), sha.get_tree_entry(0).unwrap());
+    assert!(sha.verify().is_ok());
+    let mut proof = sha.prove(0, true).unwrap();
+    verify_proof(&proof).unwrap();
+    proof.algorithm = HashAlgorithm::Blake2b;
+    assert!(verify_proof(&proof).is_err());
+
+    // Registers with an unknown tree algorithm aren't opened at all
+    SleepDirRegister::create(dir, "md5").unwrap();
+    std::fs::remove_file(dir.join("md5.tree")).unwrap();
+    SleepFile::create(&dir.join("md5.tree"), 0x05025702, 40, Some("MD5".to_string())).unwrap();
+    assert!(SleepDirRegister::open(dir, "md5", false).is_err());
+    assert!(SleepDirRegister::from_parts(
+        MemorySleepFile::new(0x05025702, 40, Some("MD5".to_string())).unwrap(),
+        MemorySleepFile::new(0x05025701, 64, Some("Ed25519".to_string())).unwrap(),
+        MemorySleepFile::new(0x05025700, 3328, None).unwrap(),
+        Some(vec![]),
+        &[0; 32],
+        None,
+    ).is_err());
+}
+
+#[test]
+fn test_verify_entries() {
+    use tempdir::TempDir;
+    use std::fs::OpenOptions;
+    use std::os::unix::fs::FileExt;
+    let tmp_dir = TempDir::new("geniza-test").unwrap();
+    let dir = tmp_dir.path();
+    let mut sdr = SleepDirRegister::create(dir, "dummy").unwrap();
+    sdr.set_sync_policy(SyncPolicy::Never);
+    for i in 0..20 {
+        sdr.append(&[i; 10]).unwrap();
+    }
+    sdr.append_batch(&[&[20; 10], &[21; 10], &[22; 10]]).unwrap();
+    let report = sdr.verify_entries(VerifyMode::Full, 4, None).unwrap();
+    assert!(report.is_ok());
+    assert_eq!(report.checked, 23);
+
+    // Corrupt data of entries 3 and 17, and the signature of 9
+    let df = OpenOptions::new().write(true).open(dir.join("dummy.data")).unwrap();
+    df.write_all_at(&[99], 35).unwrap();
+    df.write_all_at(&[99], 175).unwrap();
+    let sf = OpenOptions::new().write(true).open(dir.join("dummy.signatures")).unwrap();
+    sf.write_all_at(&[99], 32 + 64 * 9).unwrap();
+    drop(sdr);
+    let mut sdr = SleepDirRegister::open(dir, "dummy", false).unwrap();
+    assert!(sdr.verify().is_err());
+
+    let mut calls = vec![];
+    let report = {
+        let mut progress = |done, total| calls.push((done, total));
+        sdr.verify_entries(VerifyMode::Full, 3, Some(&mut progress)).unwrap()
+    };
+    assert_eq!(report.bad_data, vec![3, 17]);
+    assert_eq!(report.bad_signatures, vec![9]);
+    assert_eq!(calls.last(), Some(&(23, 23)));
+
+    let report = sdr.verify_entries(VerifyMode::SignaturesOnly, 1, None).unwrap();
+    assert!(report.bad_data.is_empty());
+    assert_eq!(report.bad_signatures, vec![9]);
+    let report = sdr.verify_entries(VerifyMode::LastEntries(8), 2, None).unwrap();
+    assert_eq!((report.checked, report.bad_data.clone()), (8, vec![17]));
+    assert!(report.bad_signatures.is_empty());
+    let report = sdr.verify_entries(VerifyMode::Range(10, 21), 2, None).unwrap();
+    assert_eq!(report.bad_data, vec![17]);
+    assert!(report.bad_signatures.is_empty());
+    assert!(sdr.verify_entries(VerifyMode::Range(10, 24), 2, None).is_err());
+
+    // A hostile leaf size is one more bad entry (and so is everything after it, which can't be
+    // found in the data file any more)
+    let tf = OpenOptions::new().write(true).open(dir.join("dummy.tree")).unwrap();
+    tf.write_all_at(&[0xff; 8], 32 + 40 * 2 * 19 + 32).unwrap();
+    drop(sdr);
+    // (a plain open would see the data file as short, and roll back to before the bad entry)
+    let mut sdr = SleepDirRegister::open_unchecked(dir, "dummy", false, true, None).unwrap();
+    sdr.load_bitfield().unwrap();
+    let report = sdr.verify_entries(VerifyMode::Full, 2, None).unwrap();
+    assert_eq!(report.bad_data, vec![3, 17, 19, 20, 21, 22]);
+    assert!(sdr.get_data_entry(19).is_err());
+    assert_eq!(report, sdr.verify_entries(VerifyMode::Full, 1, None).unwrap());
+}
+
+#[test]
+fn test_checkpoint() {
+    use tempdir::TempDir;
+    use std::os::unix::fs::FileExt;
+    let tmp_dir = TempDir::new("geniza-test").unwrap();
+    let dir = tmp_dir.path();
+    let mut sdr = SleepDirRegister::create(dir, "dummy").unwrap();
+    for i in 0..10 {
+        sdr.append(&[i; 10]).unwrap();
+    }
+    assert!(sdr.checkpoint().is_none());
+    sdr.verify().unwrap();
+    assert_eq!(sdr.checkpoint().unwrap().length, 10);
+    assert_eq!(sdr.checkpoint().unwrap().data_bytes, 100);
+    for i in 10..15 {
+        sdr.append(&[i; 10]).unwrap();
+    }
+    drop(sdr);
+
+    // Read-only registers only update the checkpoint in memory (by default)
+    let mut sdr = SleepDirRegister::open(dir, "dummy", false).unwrap();
+    assert_eq!(sdr.checkpoint().unwrap().length, 10);
+    let report = sdr.verify_entries(VerifyMode::SinceCheckpoint, 1, None).unwrap();
+    assert!(report.is_ok());
+    assert_eq!(report.checked, 5);
+    assert_eq!(sdr.checkpoint().unwrap().length, 15);
+    drop(sdr);
+    let mut sdr = SleepDirRegister::open(dir, "dummy", false).unwrap();
+    assert_eq!(sdr.checkpoint().unwrap().length, 10);
+    sdr.set_save_checkpoints(true);
+    sdr.verify_entries(VerifyMode::SinceCheckpoint, 1, None).unwrap();
+    drop(sdr);
+    let sdr = SleepDirRegister::open(dir, "dummy", false).unwrap();
+    assert_eq!(sdr.checkpoint().unwrap().length, 15);
+    drop(sdr);
+
+    // Tamper with a tree root from before the checkpoint (roots for 15 entries: 7, 19, 25, 28)
+    let tree = OpenOptions::new().write(true).open(dir.join("dummy.tree")).unwrap();
+    tree.write_all_at(&[0xFF], 32 + 40 * 19).unwrap();
+    let mut sdr = SleepDirRegister::open(dir, "dummy", false).unwrap();
+    let report = sdr.verify_entries(VerifyMode::SinceCheckpoint, 1, None).unwrap();
+    assert!(report.history_changed);
+    assert!(!report.is_ok());
+    assert_eq!(report.checked, 15);
+
+    // Corrupt checkpoint files are ignored
+    File::create(dir.join("dummy.checkpoint")).unwrap().write_all(b"GZCK").unwrap();
+    let sdr = SleepDirRegister::open(dir, "dummy", false).unwrap();
+    assert!(sdr.checkpoint().is_none());
+}
+
 #[test]
 fn test_sdr_create() {
     use tempdir::TempDir;
@@ -620,6 +2757,7 @@ fn test_sdr_has() {
     use tempdir::TempDir;
     let tmp_dir = TempDir::new("geniza-test").unwrap();
     let mut sdr = SleepDirRegister::create(tmp_dir.path(), "dummy").unwrap();
+    assert!(sdr.has_all().unwrap());
 
     sdr.append("hello world!".as_bytes()).unwrap();
     assert!(sdr.check().is_ok());
@@ -627,3 +2765,38 @@ fn test_sdr_has() {
     assert_eq!(sdr.has(0).unwrap(), true);
     assert_eq!(sdr.has(40).unwrap(), false);
 }
+
+#[test]
+fn test_memory_register() {
+    let mut mr = MemoryRegister::new().unwrap();
+    assert_eq!(mr.len().unwrap(), 0);
+    assert!(mr.writable());
+
+    mr.append("hello world!".as_bytes()).unwrap();
+    for i in 0..20 {
+        assert_eq!(mr.append(&[1, 2, 3, 4, 5]).unwrap(), i + 1);
+    }
+    assert!(mr.check().is_ok());
+    assert!(mr.verify().is_ok());
+    assert_eq!(mr.len().unwrap(), 21);
+    assert_eq!(mr.len_bytes().unwrap(), 12 + 20 * 5);
+    assert_eq!(mr.get_data_entry(0).unwrap(), "hello world!".as_bytes());
+    assert_eq!(mr.get_data_entry(20).unwrap(), vec![1, 2, 3, 4, 5]);
+    assert!(mr.get_data_entry(21).is_err());
+
+    // Tree hashes should match an on-disk register with the same content
+    use tempdir::TempDir;
+    let tmp_dir = TempDir::new("geniza-test").unwrap();
+    let mut sdr = SleepDirRegister::create(tmp_dir.path(), "dummy").unwrap();
+    sdr.append("hello world!".as_bytes()).unwrap();
+    for _ in 0..20 {
+        sdr.append(&[1, 2, 3, 4, 5]).unwrap();
+    }
+    for i in 0..41 {
+        assert_eq!(mr.get_tree_entry(i).unwrap(), sdr.get_tree_entry(i).unwrap());
+    }
+
+    let ro = MemoryRegister::with_keys(&mr.pub_key, None).unwrap();
+    assert!(!ro.writable());
+    assert!(MemoryRegister::with_keys(&[0; 31], None).is_err());
+}
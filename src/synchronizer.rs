@@ -7,17 +7,33 @@ use std::path::{Path, PathBuf};
 use protocol::{DatNetMessage, DatConnection};
 use rand::{OsRng, Rng};
 use sleep_register::HyperRegister;
-use peer::{DatPeerThread, PeerMsg};
+use peer::{DatPeerThread, PeerEvent, PeerMsg};
+use pex::{PEX_EXTENSION, decode_pex, pex_msg};
+use holepunch::{HOLEPUNCH_EXTENSION, HOLEPUNCH_TIMEOUT, Datagram, DatagramFactory, HolePunchCoordinator,
+                HolePunchMsg, HolePunchToken, RendezvousRegistry, UdpRendezvous, udp_register};
 use sleep_register::SleepDirRegister;
 use sodiumoxide::crypto::stream::Key;
 use bit_vec::BitVec;
-use discovery::discover_peers_dns;
+use discovery::{Discovery, DiscoveryWorker, DnsDiscovery};
+use relay::relay_listen;
+use make_discovery_key;
 use protobuf::parse_from_bytes;
 use network_msgs::Data;
 use metadata_msgs::Index;
-use std::net::SocketAddr;
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::sync::Arc;
+use std::cmp;
+use std::mem;
+use std::time::Duration;
+use std::thread;
 use chan;
 
+/// How long to wait before re-registering at a relay that failed
+const RELAY_RETRY_SECS: u64 = 10;
+
+/// Highest entry index a peer's Have message can name (unless our copy of the register is longer)
+const MAX_PEER_ENTRIES: u64 = (MAX_BITFIELD_BYTES * 8) as u64;
+
 pub enum SyncMode {
     RxMax,
     RxEndless,
@@ -30,34 +46,62 @@ pub struct RegisterStatus {
     register: SleepDirRegister,
     inflight: Vec<u64>,
     wanted: BitVec,
+    // Which entries each peer (by handle) has told us it has
+    peer_has: HashMap<u64, BitVec>,
     key: Key,
 }
 
 pub struct Synchronizer {
     peers: HashMap<u64, DatPeerThread>,
+    // Addresses we dialed, for connections still being set up
+    dialing: HashMap<u64, SocketAddr>,
+    // Addresses of directly connected peers (the ones worth passing on to others)
+    peer_addrs: HashMap<u64, SocketAddr>,
+    // Addresses of all connected peers, however they connected
+    remote_addrs: HashMap<u64, SocketAddr>,
     registers: Vec<RegisterStatus>,
     mode: SyncMode,
     is_drive: bool,
     local_id: [u8; 32],
     dir: Option<PathBuf>,
-    unified_peers_tx: chan::Sender<Result<PeerMsg>>,
-    unified_peers_rx: chan::Receiver<Result<PeerMsg>>,
+    unified_peers_tx: chan::Sender<PeerEvent>,
+    unified_peers_rx: chan::Receiver<PeerEvent>,
     potential_peers: Vec<SocketAddr>,
+    tried_peers: Vec<SocketAddr>,
+    discovery_sources: Vec<Box<dyn Discovery + Send>>,
+    discovered_once: bool,
+    discovery_worker: Option<DiscoveryWorker>,
+    discovered_tx: chan::Sender<SocketAddr>,
+    discovered_rx: chan::Receiver<SocketAddr>,
+    rendezvous: Option<RendezvousRegistry>,
+    holepunch: HolePunchCoordinator,
+    // Set if we try hole punching (as a client)
+    holepunch_sockets: Option<DatagramFactory>,
+    // Our registration with each rendezvous peer (by handle): token, socket, and the peer's address
+    punch_registrations: HashMap<u64, (HolePunchToken, Arc<dyn Datagram + Send + Sync>, SocketAddr)>,
+    // Peers we can ask to be introduced to, with the handle of the rendezvous peer that knows them
+    punchable: HashMap<SocketAddr, u64>,
+    relay: Option<SocketAddr>,
 }
 
 impl Synchronizer {
 
+    /// Local registers are always created with BLAKE2b tree hashes: the wire protocol has no way
+    /// to say which hash function a remote register uses, so SHA256 registers (see
+    /// `HashAlgorithm`) can't be cloned.
     pub fn new_downloader(key: Key, mode: SyncMode, dir: &Path) -> Result<Synchronizer> {
 
         let metadata_reg = SleepDirRegister::create(dir.as_ref(), "metadata")?;
 
         let (unified_peers_tx, unified_peers_rx) = chan::async();
+        let (discovered_tx, discovered_rx) = chan::async();
 
         let metadata_status = RegisterStatus {
             id: 0,
             register: metadata_reg,
             inflight: vec![],
             wanted: BitVec::new(),
+            peer_has: HashMap::new(),
             key,
         };
 
@@ -67,6 +111,9 @@ impl Synchronizer {
 
         let s = Synchronizer {
             peers: HashMap::new(),
+            dialing: HashMap::new(),
+            peer_addrs: HashMap::new(),
+            remote_addrs: HashMap::new(),
             mode,
             local_id,
             is_drive: true,
@@ -75,20 +122,150 @@ impl Synchronizer {
             unified_peers_tx,
             unified_peers_rx,
             potential_peers: vec![],
+            tried_peers: vec![],
+            discovery_sources: vec![Box::new(DnsDiscovery::new())],
+            discovered_once: false,
+            discovery_worker: None,
+            discovered_tx,
+            discovered_rx,
+            rendezvous: None,
+            holepunch: HolePunchCoordinator::new(),
+            holepunch_sockets: None,
+            punch_registrations: HashMap::new(),
+            punchable: HashMap::new(),
+            relay: None,
         };
         Ok(s)
     }
 
+    /// Adds a source of peers. These are all queried once by `discover()`, and then repeatedly (on
+    /// each source's refresh interval) by a background worker while `run()` is running.
+    pub fn add_discovery(&mut self, source: Box<dyn Discovery + Send>) {
+        self.discovery_sources.push(source);
+    }
+
+    /// Removes all discovery sources (including the default DNS source)
+    pub fn clear_discovery(&mut self) {
+        self.discovery_sources.clear();
+    }
+
+    /// Synchronously queries every discovery source once. Returns the number of new potential
+    /// peers found.
     pub fn discover(&mut self) -> Result<u64> {
 
         let meta_key = &self.registers.get(0).unwrap().key.clone();
-        let new_peers = discover_peers_dns(&meta_key[0..32])?;
-        let new_count = new_peers.len() as u64;
+        let mut new_peers = vec![];
+        for source in self.discovery_sources.iter_mut() {
+            match source.discover(&meta_key[0..32]) {
+                Ok(mut peers) => new_peers.append(&mut peers),
+                Err(e) => warn!("discovery source '{}' failed: {}", source.name(), e),
+            }
+        }
+        self.discovered_once = true;
 
+        let before = self.potential_peers.len();
         for p in new_peers {
             self.add_peer(p);
         }
-        Ok(new_count)
+        Ok((self.potential_peers.len() - before) as u64)
+    }
+
+    /// Accepts incoming connections (in a background thread). Returns the bound address.
+    pub fn listen(&mut self, bind: SocketAddr) -> Result<SocketAddr> {
+        let listener = TcpListener::bind(bind)?;
+        let local_addr = listener.local_addr()?;
+        let meta_key = self.registers[0].key.clone();
+        let local_id = self.local_id;
+        let unified_peers_tx = self.unified_peers_tx.clone();
+        let mut rng = OsRng::new()?;
+        thread::spawn(move || {
+            for tcp in listener.incoming() {
+                let accepted = tcp.map_err(|e| e.into()).and_then(|tcp| {
+                    DatPeerThread::accept(tcp, meta_key.clone(), rng.gen::<u64>(), false, Some(&local_id), unified_peers_tx.clone())
+                });
+                if let Err(e) = accepted {
+                    warn!("failed to accept connection: {}", e);
+                }
+            }
+        });
+        Ok(local_addr)
+    }
+
+    /// Act as a hole punching rendezvous for connected peers: listens for UDP registrations on
+    /// `udp_bind` (in a background thread), and handles introduction requests. Returns the bound
+    /// UDP address.
+    ///
+    /// Peers look for the UDP port at the same port number as our TCP port (see `listen()`).
+    pub fn enable_rendezvous(&mut self, udp_bind: SocketAddr) -> Result<SocketAddr> {
+        let mut udp = UdpRendezvous::bind(udp_bind)?;
+        let local_addr = udp.local_addr()?;
+        self.rendezvous = Some(udp.registry());
+        thread::spawn(move || {
+            if let Err(e) = udp.run() {
+                warn!("holepunch rendezvous stopped: {}", e);
+            }
+        });
+        Ok(local_addr)
+    }
+
+    /// Try to reach peers we can't connect to directly by UDP hole punching, through any connected
+    /// peer that will act as a rendezvous (see `HolePunchMsg`).
+    pub fn enable_holepunch(&mut self) {
+        self.set_holepunch_sockets(Box::new(|| {
+            Ok(Arc::new(UdpSocket::bind("0.0.0.0:0")?) as Arc<dyn Datagram + Send + Sync>)
+        }));
+    }
+
+    /// Like `enable_holepunch()`, with sockets from somewhere else (eg, bound to a specific
+    /// interface).
+    pub fn set_holepunch_sockets(&mut self, sockets: DatagramFactory) {
+        self.holepunch_sockets = Some(sockets);
+    }
+
+    /// Waits at a relay (in a background thread) for peers to be paired with, serving them like
+    /// any other incoming connection. Registers again after each pairing; if the relay can't be
+    /// reached, keeps retrying every `RELAY_RETRY_SECS`.
+    pub fn listen_via_relay(&mut self, relay: SocketAddr) -> Result<()> {
+        let meta_key = self.registers[0].key.clone();
+        let discovery_key = make_discovery_key(&meta_key[0..32]);
+        let local_id = self.local_id;
+        let unified_peers_tx = self.unified_peers_tx.clone();
+        let mut rng = OsRng::new()?;
+        thread::spawn(move || {
+            loop {
+                let accepted = relay_listen(relay, &discovery_key).and_then(|tcp| {
+                    info!("relay {} paired us with a peer", relay);
+                    DatPeerThread::accept(tcp, meta_key.clone(), rng.gen::<u64>(), false, Some(&local_id), unified_peers_tx.clone())
+                });
+                if let Err(e) = accepted {
+                    warn!("waiting at relay {} failed: {}", relay, e);
+                    thread::sleep(Duration::from_secs(RELAY_RETRY_SECS));
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Sets a relay to fall back to when direct connections to peers fail.
+    pub fn set_relay(&mut self, relay: Option<SocketAddr>) {
+        self.relay = relay;
+    }
+
+    /// Drops local copies of entries `start..end` of a register (by feed index), and tells all
+    /// connected peers we no longer have them.
+    pub fn clear(&mut self, feed_index: u8, start: u64, end: u64) -> Result<()> {
+        let status = match self.registers.get_mut(feed_index as usize) {
+            Some(status) => status,
+            None => bail!("No register for feed index {}", feed_index),
+        };
+        status.register.clear(start, end)?;
+        for pt in self.peers.values_mut() {
+            let mut uhm = Unhave::new();
+            uhm.set_start(start);
+            uhm.set_length(end - start);
+            pt.send(DatNetMessage::Unhave(uhm), feed_index)?;
+        }
+        Ok(())
     }
 
     pub fn add_peer(&mut self, sa: SocketAddr) {
@@ -98,39 +275,209 @@ impl Synchronizer {
         }
     }
 
-    pub fn run(&mut self) -> Result<()> {
+    fn connect_peer(&mut self, sa: SocketAddr) -> Result<()> {
 
-        let meta_key = &self.registers.get(0).unwrap().key.clone();
+        // Peers behind NAT can't be dialed; ask to be introduced instead (the connection happens
+        // once both sides get the introduction)
+        if let Some(rendezvous) = self.punchable.remove(&sa) {
+            if let Some(pt) = self.peers.get_mut(&rendezvous) {
+                debug!("asking for a holepunch introduction to {}", sa);
+                pt.send(HolePunchMsg::Connect(sa).to_net_msg(), 0)?;
+            }
+            return Ok(());
+        }
+
+        if self.tried_peers.contains(&sa) {
+            return Ok(());
+        }
+        self.tried_peers.push(sa);
+
+        // TODO: somewhere in here validate that we haven't already connected to this peer id
+        // by a different name
+        let meta_key = self.registers[0].key.clone();
         let mut rng = OsRng::new()?;
-        for p in &self.potential_peers {
-            // TODO: somewhere in here validate that we haven't already connected to this peer id
-            // by a different name
-            let handle = rng.gen::<u64>();
-            let pt = DatPeerThread::connect(p, meta_key.clone(), handle, false, Some(&self.local_id), self.unified_peers_tx.clone())?;
-            self.peers.insert(handle, pt);
-            let pt = self.peers.get_mut(&handle).unwrap();
-
-            match self.mode {
-                SyncMode::RxMax => {
-                    init_want_everything(pt, 0)?;
+        let handle = rng.gen::<u64>();
+        let pt = DatPeerThread::connect_relayed(sa, self.relay, meta_key, handle, false, Some(&self.local_id), self.unified_peers_tx.clone())?;
+        self.peers.insert(handle, pt);
+        self.dialing.insert(handle, sa);
+        self.init_peer(handle)
+    }
+
+    /// Queues up the first messages for a new peer (sent once the connection is up)
+    fn init_peer(&mut self, handle: u64) -> Result<()> {
+        let known_peers = self.connected_addrs(None);
+        let pt = self.peers.get_mut(&handle).unwrap();
+
+        // Tell the new peer about everybody else we are connected to
+        if !known_peers.is_empty() {
+            pt.send(pex_msg(&known_peers), 0)?;
+        }
+
+        match self.mode {
+            SyncMode::RxMax => {
+                init_want_everything(pt, 0)?;
+            },
+            SyncMode::RxEndless => unimplemented!(),
+            SyncMode::TxEndless => unimplemented!(),
+            SyncMode::RxTxEndless => unimplemented!(),
+        };
+        Ok(())
+    }
+
+    /// Addresses of all peers we have connected to, optionally excluding one (by handle).
+    fn connected_addrs(&self, exclude: Option<u64>) -> Vec<SocketAddr> {
+        self.peer_addrs.iter()
+            .filter(|&(h, _)| Some(*h) != exclude)
+            .map(|(_, sa)| *sa)
+            .collect()
+    }
+
+    /// Sends every connected peer a list of all our other connected peers. Peers that can't be
+    /// sent to are dropped.
+    fn share_peers(&mut self) {
+        let handles: Vec<u64> = self.peers.keys().cloned().collect();
+        for handle in handles {
+            let others = self.connected_addrs(Some(handle));
+            if others.is_empty() {
+                continue;
+            }
+            if let Err(e) = self.peers.get_mut(&handle).unwrap().send(pex_msg(&others), 0) {
+                warn!("dropping peer {}: failed to send peer-exchange: {}", handle, e);
+                self.remove_peer(handle);
+            }
+        }
+    }
+
+    /// Forgets everything about a peer (eg, once it's thread has exited)
+    fn remove_peer(&mut self, handle: u64) {
+        self.peers.remove(&handle);
+        self.dialing.remove(&handle);
+        self.peer_addrs.remove(&handle);
+        self.remote_addrs.remove(&handle);
+        self.holepunch.remove_peer(handle);
+        self.punch_registrations.remove(&handle);
+        self.punchable.retain(|_, rendezvous| *rendezvous != handle);
+        for status in &mut self.registers {
+            status.peer_has.remove(&handle);
+        }
+    }
+
+    /// Registers (in the background) for hole punching with a directly connected peer, in case
+    /// it's a rendezvous. Does nothing unless hole punching is enabled.
+    fn register_holepunch(&mut self, handle: u64, addr: SocketAddr) -> Result<()> {
+        let sock = match self.holepunch_sockets {
+            Some(ref sockets) => sockets()?,
+            None => return Ok(()),
+        };
+        let outbound = match self.peers.get(&handle) {
+            Some(pt) => pt.outbound(),
+            None => return Ok(()),
+        };
+        let mut token = [0; 16];
+        OsRng::new()?.fill_bytes(&mut token);
+        self.punch_registrations.insert(handle, (token, sock.clone(), addr));
+        thread::spawn(move || {
+            // The rendezvous side is only told about us once it has seen our UDP address
+            match udp_register(&*sock, addr, &token, HOLEPUNCH_TIMEOUT) {
+                Ok(observed) => {
+                    debug!("registered for holepunching with {} (seen as {})", addr, observed);
+                    outbound.send((HolePunchMsg::Register(token).to_net_msg(), 0));
                 },
-                SyncMode::RxEndless => unimplemented!(),
-                SyncMode::TxEndless => unimplemented!(),
-                SyncMode::RxTxEndless => unimplemented!(),
-            };
+                Err(e) => debug!("no holepunch rendezvous at {}: {}", addr, e),
+            }
+        });
+        Ok(())
+    }
+
+    /// Acts on an introduction from a rendezvous peer: punches through to (and connects with) the
+    /// introduced peer, using the socket registered with the rendezvous, then registers again.
+    fn punch(&mut self, rendezvous: u64, remote: SocketAddr, token: HolePunchToken) -> Result<()> {
+        let (sock, rendezvous_addr) = match self.punch_registrations.remove(&rendezvous) {
+            Some((_, sock, addr)) => (sock, addr),
+            None => bail!("holepunch introduction from a peer we haven't registered with"),
+        };
+        let meta_key = self.registers[0].key.clone();
+        let handle = OsRng::new()?.gen::<u64>();
+        let pt = DatPeerThread::connect_punched(sock, (remote, token), meta_key, handle, false, Some(&self.local_id), self.unified_peers_tx.clone())?;
+        self.peers.insert(handle, pt);
+        self.init_peer(handle)?;
+        self.register_holepunch(rendezvous, rendezvous_addr)
+    }
+
+    fn handle_event(&mut self, event: PeerEvent) -> Result<()> {
+        match event {
+            PeerEvent::Accepted(pt) => {
+                let handle = pt.handle;
+                self.peers.insert(handle, pt);
+                self.init_peer(handle)?;
+            },
+            PeerEvent::Connected { handle, addr, direct } => {
+                self.dialing.remove(&handle);
+                if !self.peers.contains_key(&handle) {
+                    return Ok(());
+                }
+                self.remote_addrs.insert(handle, addr);
+                // Only addresses we actually reached are passed on to other peers
+                if direct {
+                    self.peer_addrs.insert(handle, addr);
+                    self.register_holepunch(handle, addr)?;
+                }
+            },
+            PeerEvent::Msg(pm) => {
+                self.handle_msg(&pm)?;
+            },
+            PeerEvent::Closed { handle, error } => {
+                match error {
+                    Some(e) => info!("peer {} closed: {}", handle, e),
+                    None => info!("peer {} closed", handle),
+                }
+                self.remove_peer(handle);
+            },
+        }
+        Ok(())
+    }
+
+    pub fn run(&mut self) -> Result<()> {
+
+        for p in self.potential_peers.clone() {
+            self.connect_peer(p)?;
         };
 
+        // Hand discovery sources off to a background worker, which feeds new peers back to us
+        if self.discovery_worker.is_none() && !self.discovery_sources.is_empty() {
+            let meta_key = self.registers[0].key.clone();
+            let sources = mem::take(&mut self.discovery_sources);
+            self.discovery_worker = Some(DiscoveryWorker::spawn(
+                &meta_key[0..32],
+                sources,
+                self.discovered_tx.clone(),
+                !self.discovered_once));
+        }
+
         // bug in chan_select!() breaking `self` reference?
         // "recursion limit reached while expanding the macro `chan_select`"
         let unified_peers_rx = self.unified_peers_rx.clone();
+        let discovered_rx = self.discovered_rx.clone();
+        let pex_tick = chan::tick(Duration::from_secs(60));
 
         loop {
             chan_select! {
                 unified_peers_rx.recv() -> val => {
-                    if let Some(Ok(pm)) = val {
-                        self.handle_msg(&pm)?;
+                    if let Some(event) = val {
+                        self.handle_event(event)?;
+                    }
+                },
+                discovered_rx.recv() -> val => {
+                    if let Some(sa) = val {
+                        self.add_peer(sa);
+                        if let Err(e) = self.connect_peer(sa) {
+                            warn!("failed to connect to discovered peer {}: {}", sa, e);
+                        }
                     }
                 },
+                pex_tick.recv() => {
+                    self.share_peers();
+                },
             };
         }
     }
@@ -138,8 +485,12 @@ impl Synchronizer {
     fn handle_msg(&mut self, pm: &PeerMsg) -> Result<()> {
         // NB: this is the simplistic model of registers (only works up to 2x per peer)
 
-        // mutable ref to PeerThread for this message
-        let pt = self.peers.get_mut(&pm.peer_handle).unwrap();
+        // mutable ref to PeerThread for this message (messages can still be queued from peers
+        // that have since been dropped)
+        let pt = match self.peers.get_mut(&pm.peer_handle) {
+            Some(pt) => pt,
+            None => return Ok(()),
+        };
 
         // NB: this is the simplistic model of registers (only works up to 2x per peer?)
         if pm.feed_index as usize >= self.registers.len() {
@@ -147,28 +498,84 @@ impl Synchronizer {
             return Ok(());
         }
 
-        match &pm.msg {
-            &DatNetMessage::Feed(_) => { unimplemented!() },
-            &DatNetMessage::Handshake(_) => { unimplemented!() },
-            &DatNetMessage::Info(_) => { unimplemented!() },
-            &DatNetMessage::Have(ref msg) => {
+        // Messages to send to peers other than this one
+        let mut outgoing: Vec<(u64, HolePunchMsg)> = vec![];
+        // Hole punching introductions, and peers we could be introduced to
+        let mut introductions: Vec<(SocketAddr, HolePunchToken)> = vec![];
+        let mut punchable: Vec<SocketAddr> = vec![];
+
+        match pm.msg {
+            DatNetMessage::Feed(_) => { unimplemented!() },
+            DatNetMessage::Handshake(_) => { unimplemented!() },
+            DatNetMessage::Info(_) => {}, // TODO: track whether peer is uploading/downloading
+            DatNetMessage::Have(ref msg) => {
                 // TODO: depending on mode...
+                let status = &mut self.registers[pm.feed_index as usize];
+                let limit = cmp::max(status.register.len()?, MAX_PEER_ENTRIES);
+                let peer_has = status.peer_has.entry(pm.peer_handle).or_default();
+                if let Err(e) = apply_have(peer_has, msg, limit) {
+                    warn!("ignoring Have from peer {} (feed {}): {}", pm.peer_handle, pm.feed_index, e);
+                    return Ok(());
+                }
 
-                //let peer_has = extract_bitfield(msg)?;
                 // TODO: remove bits we already have
                 // TODO: depending on mode, extend 'wanted' bits
                 // TODO: send a Request on this channel
                 // XXX: dummy for testing
-                let mut request = Request::new();
-                request.set_index(msg.get_start());
-                pt.send(DatNetMessage::Request(request), pm.feed_index)?;
+                if peer_has.get(msg.get_start() as usize) == Some(true) {
+                    let mut request = Request::new();
+                    request.set_index(msg.get_start());
+                    pt.send(DatNetMessage::Request(request), pm.feed_index)?;
+                }
+            },
+            DatNetMessage::Unhave(ref msg) => {
+                debug!("peer {} no longer has entries {}+{} (feed {})",
+                    pm.peer_handle, msg.get_start(), msg.get_length(), pm.feed_index);
+                if let Some(peer_has) = self.registers[pm.feed_index as usize].peer_has.get_mut(&pm.peer_handle) {
+                    set_range(peer_has, msg.get_start(), msg.get_length(), false);
+                }
+            },
+            DatNetMessage::Want(_) => {}, // PASS
+            DatNetMessage::Unwant(_) => {}, // PASS
+            DatNetMessage::Request(_) => {}, // PASS
+            DatNetMessage::Cancel(_) => {}, // PASS
+            DatNetMessage::Extension(ref ext) => {
+                // Peer exchange only makes sense for the "primary" (metadata) feed
+                if ext.name == PEX_EXTENSION && pm.feed_index == 0 {
+                    match decode_pex(&ext.payload) {
+                        Ok(peers) => {
+                            debug!("got {} peers via peer-exchange", peers.len());
+                            for sa in peers {
+                                // Connected to from the main loop, same as discovered peers
+                                self.discovered_tx.send(sa);
+                            }
+                        },
+                        Err(e) => warn!("bad peer-exchange message: {}", e),
+                    }
+                }
+                if ext.name == HOLEPUNCH_EXTENSION && pm.feed_index == 0 {
+                    match HolePunchMsg::decode(&ext.payload) {
+                        Ok(HolePunchMsg::Introduce(remote, token)) => introductions.push((remote, token)),
+                        Ok(HolePunchMsg::Peers(mut addrs)) => punchable.append(&mut addrs),
+                        Ok(hpm) => if let Some(ref registry) = self.rendezvous {
+                            // Peers are known to the rendezvous by whatever address they connected
+                            // from, not just ones worth gossiping
+                            let peers: Vec<(u64, SocketAddr)> = self.remote_addrs.iter()
+                                .map(|(h, sa)| (*h, *sa))
+                                .collect();
+                            let mut rng = OsRng::new()?;
+                            let mut token = [0; 16];
+                            rng.fill_bytes(&mut token);
+                            match self.holepunch.handle(pm.peer_handle, &hpm, &peers, registry, token) {
+                                Ok(mut msgs) => outgoing.append(&mut msgs),
+                                Err(e) => warn!("holepunch request failed: {}", e),
+                            }
+                        },
+                        Err(e) => warn!("bad holepunch message: {}", e),
+                    }
+                }
             },
-            &DatNetMessage::Unhave(_) => {}, // PASS
-            &DatNetMessage::Want(_) => {}, // PASS
-            &DatNetMessage::Unwant(_) => {}, // PASS
-            &DatNetMessage::Request(_) => {}, // PASS
-            &DatNetMessage::Cancel(_) => {}, // PASS
-            &DatNetMessage::Data(ref msg) => {
+            DatNetMessage::Data(ref msg) => {
 
                 // TODO: feed indexing?
                 // Insert into local feed
@@ -192,6 +599,7 @@ impl Synchronizer {
                             register: content_reg,
                             inflight: vec![],
                             wanted: BitVec::new(),
+                            peer_has: HashMap::new(),
                             key: data_key,
                         };
 
@@ -202,6 +610,24 @@ impl Synchronizer {
                 // TODO: send next wanted, or otherwise update state
             },
         }
+        for (handle, hpm) in outgoing {
+            if let Some(pt) = self.peers.get_mut(&handle) {
+                pt.send(hpm.to_net_msg(), 0)?;
+            }
+        }
+        if self.holepunch_sockets.is_some() {
+            for (remote, token) in introductions {
+                if let Err(e) = self.punch(pm.peer_handle, remote, token) {
+                    warn!("failed to start holepunch to {}: {}", remote, e);
+                }
+            }
+            for sa in punchable {
+                if !self.remote_addrs.values().any(|a| *a == sa) {
+                    self.punchable.insert(sa, pm.peer_handle);
+                    self.connect_peer(sa)?;
+                }
+            }
+        }
         Ok(())
     }
 }
@@ -222,7 +648,10 @@ fn parse_drive_data_key(data_msg: &Data) -> Result<Key> {
 
 fn max_index(have_msg: &Have) -> Result<u64> {
     if have_msg.has_length() {
-        return Ok(have_msg.get_start() + have_msg.get_length());
+        return match have_msg.get_start().checked_add(have_msg.get_length()) {
+            Some(end) => Ok(end),
+            None => bail!("Have message range overflows"),
+        };
     } else if have_msg.has_bitfield() {
         let raw_bf = have_msg.get_bitfield();
         let bf = decode_bitfield(raw_bf)?;
@@ -233,6 +662,107 @@ fn max_index(have_msg: &Have) -> Result<u64> {
     }
 }
 
+/// Sets (or clears) bits `start..start+length`, growing `bits` as needed. Callers check the
+/// range against a limit first (see `apply_have()`); clearing never grows `bits`.
+fn set_range(bits: &mut BitVec, start: u64, length: u64, value: bool) {
+    let end = start.saturating_add(length);
+    if value && end as usize > bits.len() {
+        let missing = end as usize - bits.len();
+        bits.grow(missing, false);
+    }
+    for i in start..cmp::min(end, bits.len() as u64) {
+        bits.set(i as usize, value);
+    }
+}
+
+/// Adds the entries of a Have message (a range, or a bitfield starting at `start`) to what we
+/// know a peer has. Fails, without changing `bits`, if the message names entries at or past
+/// `limit`.
+fn apply_have(bits: &mut BitVec, msg: &Have, limit: u64) -> Result<()> {
+    let start = msg.get_start();
+    if msg.has_bitfield() {
+        // decode_bitfield() returns the bytes backwards
+        let mut raw = decode_bitfield(msg.get_bitfield())?;
+        raw.reverse();
+        let has = BitVec::from_bytes(&raw);
+        let last = match has.iter().rposition(|b| b) {
+            Some(i) => i as u64,
+            None => return Ok(()),
+        };
+        match start.checked_add(last) {
+            Some(end) if end < limit => {},
+            _ => bail!("Have bitfield {}+{} is past entry {}", start, last + 1, limit),
+        }
+        for (i, has) in has.iter().enumerate() {
+            if has {
+                set_range(bits, start + i as u64, 1, true);
+            }
+        }
+    } else {
+        let length = msg.get_length();
+        match start.checked_add(length) {
+            Some(end) if end <= limit => {},
+            _ => bail!("Have range {}+{} is past entry {}", start, length, limit),
+        }
+        set_range(bits, start, length, true);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_peer_has() {
+    let mut bits = BitVec::new();
+    let mut hm = Have::new();
+    hm.set_start(0);
+    hm.set_bitfield(vec![2, 254]);
+    apply_have(&mut bits, &hm, 100).unwrap();
+    assert_eq!(bits.iter().filter(|b| *b).count(), 7);
+    assert_eq!(bits.get(6), Some(true));
+    assert_ne!(bits.get(7), Some(true));
+
+    let mut hm = Have::new();
+    hm.set_start(10);
+    apply_have(&mut bits, &hm, 100).unwrap();
+    assert_eq!(bits.get(10), Some(true));
+    assert_eq!(bits.get(11), None);
+
+    let mut uhm = Unhave::new();
+    uhm.set_start(2);
+    uhm.set_length(3);
+    set_range(&mut bits, uhm.get_start(), uhm.get_length(), false);
+    assert_eq!(bits.iter().filter(|b| *b).count(), 5);
+    assert_eq!(bits.get(4), Some(false));
+    assert_eq!(bits.get(5), Some(true));
+
+    // Clearing past the end doesn't grow anything
+    set_range(&mut bits, 100, 1, false);
+    assert_eq!(bits.len(), 11);
+
+    // Ranges past the limit (or overflowing) are rejected without growing anything
+    let mut hm = Have::new();
+    hm.set_start(99);
+    hm.set_length(2);
+    assert!(apply_have(&mut bits, &hm, 100).is_err());
+    hm.set_start(u64::MAX);
+    assert!(apply_have(&mut bits, &hm, 100).is_err());
+    assert!(max_index(&hm).is_err());
+    let mut hm = Have::new();
+    hm.set_start(96);
+    hm.set_bitfield(vec![2, 255]);
+    assert!(apply_have(&mut bits, &hm, 100).is_err());
+    assert_eq!(bits.len(), 11);
+
+    // Huge or malformed bitfields don't decode
+    let mut hm = Have::new();
+    hm.set_start(0);
+    hm.set_bitfield(vec![0xff, 0xff, 0xff, 0xff, 0x7f]);
+    assert!(apply_have(&mut bits, &hm, u64::MAX).is_err());
+    hm.set_bitfield(vec![8, 1]);
+    assert!(apply_have(&mut bits, &hm, u64::MAX).is_err());
+    hm.set_bitfield(vec![0x80; 12]);
+    assert!(apply_have(&mut bits, &hm, u64::MAX).is_err());
+}
+
 #[test]
 fn test_max_index() {
     let mut hm = Have::new();
@@ -250,6 +780,167 @@ fn test_max_index() {
     assert_eq!(max_index(&hm).unwrap(), 5);
 }
 
+#[cfg(test)]
+fn poll_peer_event(events: &chan::Receiver<PeerEvent>, timeout: Duration) -> Option<PeerEvent> {
+    let timeout = chan::after(timeout);
+    let mut event = None;
+    chan_select! {
+        events.recv() -> val => {
+            event = val;
+        },
+        timeout.recv() => {},
+    }
+    event
+}
+
+#[test]
+fn test_peer_addrs_lifecycle() {
+    use tempdir::TempDir;
+    use std::net::TcpListener;
+
+    let tmp_dir = TempDir::new("geniza-test").unwrap();
+    let key = Key::from_slice(&[6; 32]).unwrap();
+    let mut sync = Synchronizer::new_downloader(key.clone(), SyncMode::RxMax, tmp_dir.path()).unwrap();
+    let events = sync.unified_peers_rx.clone();
+    let next_event = || poll_peer_event(&events, Duration::from_secs(10)).expect("peer event");
+
+    // Nobody listening: the address is never passed on
+    let dead = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    sync.connect_peer(dead).unwrap();
+    assert!(sync.connected_addrs(None).is_empty());
+    match next_event() {
+        event @ PeerEvent::Closed { error: Some(_), .. } => sync.handle_event(event).unwrap(),
+        _ => panic!("expected a failed connection"),
+    }
+    assert!(sync.peers.is_empty());
+
+    // Listed once the handshake is done, and dropped again when the peer goes away
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let live = listener.local_addr().unwrap();
+    let remote_key = key.clone();
+    let remote = thread::spawn(move || {
+        let (tcp, _) = listener.accept().unwrap();
+        DatConnection::from_tcp(tcp, &remote_key, false, None).unwrap();
+    });
+    sync.connect_peer(live).unwrap();
+    assert!(sync.connected_addrs(None).is_empty());
+    let event = next_event();
+    assert!(matches!(event, PeerEvent::Connected { direct: true, .. }));
+    sync.handle_event(event).unwrap();
+    assert_eq!(sync.connected_addrs(None), vec![live]);
+    remote.join().unwrap();
+    loop {
+        let event = next_event();
+        let closed = matches!(event, PeerEvent::Closed { .. });
+        sync.handle_event(event).unwrap();
+        if closed {
+            break;
+        }
+    }
+    assert!(sync.connected_addrs(None).is_empty());
+    assert!(sync.peers.is_empty());
+    sync.share_peers();
+}
+
+#[test]
+fn test_holepunch_through_rendezvous() {
+    use tempdir::TempDir;
+    use std::time::Instant;
+    use holepunch::NatSocket;
+
+    let key = Key::from_slice(&[7; 32]).unwrap();
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new("geniza-test").unwrap()).collect();
+    let mut rendezvous = Synchronizer::new_downloader(key.clone(), SyncMode::RxMax, dirs[0].path()).unwrap();
+    let tcp_addr = rendezvous.listen("127.0.0.1:0".parse().unwrap()).unwrap();
+    rendezvous.enable_rendezvous(tcp_addr).unwrap();
+
+    // Two peers "behind NAT" (on separate loopback addresses): their UDP sockets drop packets from
+    // anybody they haven't sent to first. Both connect to the rendezvous peer.
+    let mut syncs = vec![rendezvous];
+    for (i, ip) in ["127.0.0.2", "127.0.0.3"].iter().enumerate() {
+        let mut sync = Synchronizer::new_downloader(key.clone(), SyncMode::RxMax, dirs[i + 1].path()).unwrap();
+        let ip = ip.to_string();
+        sync.set_holepunch_sockets(Box::new(move || {
+            Ok(Arc::new(NatSocket::bind((&ip[..], 0))?) as Arc<dyn Datagram + Send + Sync>)
+        }));
+        sync.connect_peer(tcp_addr).unwrap();
+        syncs.push(sync);
+    }
+
+    // Run all three until each NAT-ed peer has a (UDP) connection to the other
+    let punched_to = |sync: &Synchronizer, ip: &str| {
+        sync.remote_addrs.values().any(|sa| sa.ip().to_string() == ip)
+    };
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while !(punched_to(&syncs[1], "127.0.0.3") && punched_to(&syncs[2], "127.0.0.2")) {
+        assert!(Instant::now() < deadline, "timed out waiting for hole punched connections");
+        for sync in syncs.iter_mut() {
+            let events = sync.unified_peers_rx.clone();
+            while let Some(event) = poll_peer_event(&events, Duration::from_millis(10)) {
+                sync.handle_event(event).unwrap();
+            }
+        }
+    }
+
+    // The punched connections aren't passed on to other peers
+    assert_eq!(syncs[1].connected_addrs(None), vec![tcp_addr]);
+    assert_eq!(syncs[2].connected_addrs(None), vec![tcp_addr]);
+}
+
+#[test]
+fn test_listen_via_relay() {
+    use tempdir::TempDir;
+    use std::net::TcpListener;
+    use relay::RelayServer;
+
+    let mut server = RelayServer::bind("127.0.0.1:0").unwrap();
+    let relay = server.local_addr().unwrap();
+    thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let key = Key::from_slice(&[8; 32]).unwrap();
+    let seed_dir = TempDir::new("geniza-test").unwrap();
+    let client_dir = TempDir::new("geniza-test").unwrap();
+    let mut seed = Synchronizer::new_downloader(key.clone(), SyncMode::RxMax, seed_dir.path()).unwrap();
+    seed.listen_via_relay(relay).unwrap();
+    let mut client = Synchronizer::new_downloader(key.clone(), SyncMode::RxMax, client_dir.path()).unwrap();
+    client.set_relay(Some(relay));
+    let client_events = client.unified_peers_rx.clone();
+
+    // The seed can't be reached directly, so the client falls back to the relay (retrying until
+    // the seed has registered there)
+    let mut connected = false;
+    for _ in 0..50 {
+        let unreachable = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        client.connect_peer(unreachable).unwrap();
+        match poll_peer_event(&client_events, Duration::from_secs(10)).expect("peer event") {
+            PeerEvent::Connected { direct, .. } => {
+                assert!(!direct);
+                connected = true;
+                break;
+            },
+            event => client.handle_event(event).unwrap(),
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(connected);
+
+    // ... and the seed picks up the connection like any incoming one
+    let seed_events = seed.unified_peers_rx.clone();
+    match poll_peer_event(&seed_events, Duration::from_secs(10)).expect("peer event") {
+        event @ PeerEvent::Accepted(_) => seed.handle_event(event).unwrap(),
+        _ => panic!("expected an accepted connection"),
+    }
+    match poll_peer_event(&seed_events, Duration::from_secs(10)).expect("peer event") {
+        PeerEvent::Connected { addr, direct, .. } => {
+            assert_eq!(addr, relay);
+            assert!(!direct);
+        },
+        _ => panic!("expected a connection"),
+    }
+}
+
 fn init_want_everything(dpt: &mut DatPeerThread, reg_index: u8) -> Result<()> {
 
     // Info: downloading, not uploading
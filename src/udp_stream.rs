@@ -0,0 +1,320 @@
+
+use std::io;
+use std::io::{Read, Write};
+use std::cmp;
+use std::collections::{BTreeMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use holepunch::Datagram;
+
+/// Prefix on all stream packets (distinct from hole punching packets, which may still be arriving
+/// when the stream starts)
+const STREAM_MAGIC: &[u8] = b"GZUS";
+const KIND_DATA: u8 = 0;
+const KIND_ACK: u8 = 1;
+const KIND_FIN: u8 = 2;
+
+/// Largest payload per packet; small enough to not get fragmented on any sane path
+const MAX_SEGMENT: usize = 1200;
+
+/// Number of unacknowledged packets allowed in flight
+const WINDOW: u64 = 64;
+
+const RETRANSMIT_MS: u64 = 200;
+const POLL_MS: u64 = 20;
+
+/// A packet not acknowledged after this many tries means the connection is dead (about 10
+/// seconds)
+const MAX_TRIES: u32 = 50;
+
+struct State {
+    // Received, in order, not yet read
+    rx_buf: VecDeque<u8>,
+    // Next sequence number expected from the remote
+    rx_next: u64,
+    // Received out of order (`None` is a FIN)
+    rx_ahead: BTreeMap<u64, Option<Vec<u8>>>,
+    tx_next: u64,
+    // Sent but not acknowledged: packet, time last sent, and number of tries
+    unacked: BTreeMap<u64, (Vec<u8>, Instant, u32)>,
+    // We shut down (a FIN has been queued)
+    closed: bool,
+    // The remote shut down (and we've read everything before it's FIN)
+    remote_closed: bool,
+    error: Option<io::ErrorKind>,
+    write_timeout: Option<Duration>,
+}
+
+struct Shared {
+    sock: Arc<dyn Datagram + Send + Sync>,
+    remote: SocketAddr,
+    state: Mutex<State>,
+    cond: Condvar,
+}
+
+/// Reliable, ordered byte stream over a datagram socket (eg, one that has just been hole
+/// punched), so a `DatConnection` can run over UDP.
+///
+/// This is about the simplest thing that works, not a real congestion-controlled protocol (like
+/// uTP): packets carry a sequence number, the receiver acknowledges the next one it expects, and
+/// anything not acknowledged after a fixed timeout is re-sent. A thread per stream handles
+/// incoming packets and re-sends.
+///
+/// All packets from addresses other than `remote` are dropped, so the socket shouldn't be shared
+/// with anything else.
+#[derive(Clone)]
+pub struct UdpStream {
+    shared: Arc<Shared>,
+}
+
+impl UdpStream {
+
+    pub fn new(sock: Arc<dyn Datagram + Send + Sync>, remote: SocketAddr) -> UdpStream {
+        let shared = Arc::new(Shared {
+            sock,
+            remote,
+            state: Mutex::new(State {
+                rx_buf: VecDeque::new(),
+                rx_next: 0,
+                rx_ahead: BTreeMap::new(),
+                tx_next: 0,
+                unacked: BTreeMap::new(),
+                closed: false,
+                remote_closed: false,
+                error: None,
+                write_timeout: None,
+            }),
+            cond: Condvar::new(),
+        });
+        let bg = shared.clone();
+        thread::spawn(move || {
+            receive_loop(bg);
+        });
+        UdpStream { shared }
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.shared.remote
+    }
+
+    pub fn set_write_timeout(&self, dur: Option<Duration>) {
+        self.shared.lock().write_timeout = dur;
+    }
+
+    /// Closes the stream in both directions: the remote gets an end-of-stream after any data
+    /// already written, and reads here return end-of-stream right away.
+    pub fn shutdown(&self) {
+        let mut state = self.shared.lock();
+        if !state.closed {
+            state.closed = true;
+            self.shared.send_segment(&mut state, KIND_FIN, &[]);
+        }
+        self.shared.cond.notify_all();
+    }
+}
+
+impl Shared {
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    /// Sends a new (data or FIN) packet, and keeps it for re-sending until it's acknowledged
+    fn send_segment(&self, state: &mut State, kind: u8, payload: &[u8]) {
+        let seq = state.tx_next;
+        state.tx_next += 1;
+        let packet = stream_packet(kind, seq, payload);
+        // Losses are dealt with by re-sending
+        let _ = self.sock.send_to(&packet, self.remote);
+        state.unacked.insert(seq, (packet, Instant::now(), 1));
+    }
+
+    fn handle_packet(&self, raw: &[u8]) {
+        if raw.len() < STREAM_MAGIC.len() + 9 || &raw[0..STREAM_MAGIC.len()] != STREAM_MAGIC {
+            return;
+        }
+        let kind = raw[STREAM_MAGIC.len()];
+        let mut seq_buf = [0; 8];
+        seq_buf.copy_from_slice(&raw[(STREAM_MAGIC.len() + 1)..(STREAM_MAGIC.len() + 9)]);
+        let seq = u64::from_be_bytes(seq_buf);
+        let payload = &raw[(STREAM_MAGIC.len() + 9)..];
+
+        let mut state = self.lock();
+        match kind {
+            KIND_ACK => {
+                // Cumulative: everything before `seq` has arrived
+                state.unacked = state.unacked.split_off(&seq);
+            },
+            KIND_DATA | KIND_FIN => {
+                if seq >= state.rx_next && seq < state.rx_next + 2 * WINDOW {
+                    let segment = if kind == KIND_DATA { Some(payload.to_vec()) } else { None };
+                    state.rx_ahead.insert(seq, segment);
+                }
+                loop {
+                    let next = state.rx_next;
+                    match state.rx_ahead.remove(&next) {
+                        Some(Some(data)) => state.rx_buf.extend(data),
+                        Some(None) => state.remote_closed = true,
+                        None => break,
+                    }
+                    state.rx_next += 1;
+                }
+                let _ = self.sock.send_to(&stream_packet(KIND_ACK, state.rx_next, &[]), self.remote);
+            },
+            _ => return,
+        }
+        self.cond.notify_all();
+    }
+
+    /// Re-sends anything that's been waiting too long for an acknowledgement
+    fn retransmit(&self) {
+        let mut state = self.lock();
+        let now = Instant::now();
+        let mut dead = false;
+        for (packet, sent, tries) in state.unacked.values_mut() {
+            if now.duration_since(*sent) < Duration::from_millis(RETRANSMIT_MS) {
+                continue;
+            }
+            if *tries >= MAX_TRIES {
+                dead = true;
+                break;
+            }
+            let _ = self.sock.send_to(packet, self.remote);
+            *sent = now;
+            *tries += 1;
+        }
+        if dead {
+            state.error = Some(io::ErrorKind::TimedOut);
+            self.cond.notify_all();
+        }
+    }
+
+    /// The background thread can stop once nothing more can happen: every handle is gone, or we
+    /// closed and everything we sent got through, or the connection died.
+    fn finished(self: &Arc<Self>) -> bool {
+        let state = self.lock();
+        state.error.is_some()
+            || (state.closed && state.unacked.is_empty())
+            || Arc::strong_count(self) == 1
+    }
+}
+
+fn stream_packet(kind: u8, seq: u64, payload: &[u8]) -> Vec<u8> {
+    let mut buf = STREAM_MAGIC.to_vec();
+    buf.push(kind);
+    buf.extend_from_slice(&seq.to_be_bytes());
+    buf.extend_from_slice(payload);
+    buf
+}
+
+fn receive_loop(shared: Arc<Shared>) {
+    let mut buf = [0; MAX_SEGMENT + 64];
+    while !shared.finished() {
+        if let Err(e) = shared.sock.set_read_timeout(Some(Duration::from_millis(POLL_MS))) {
+            shared.lock().error = Some(e.kind());
+            break;
+        }
+        match shared.sock.recv_from(&mut buf) {
+            Ok((len, from)) => {
+                if from == shared.remote {
+                    shared.handle_packet(&buf[0..len]);
+                }
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {},
+            Err(e) => {
+                shared.lock().error = Some(e.kind());
+                break;
+            },
+        }
+        shared.retransmit();
+    }
+    shared.cond.notify_all();
+}
+
+impl Read for UdpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.shared.lock();
+        loop {
+            if !state.rx_buf.is_empty() {
+                let len = cmp::min(buf.len(), state.rx_buf.len());
+                for (i, b) in state.rx_buf.drain(0..len).enumerate() {
+                    buf[i] = b;
+                }
+                return Ok(len);
+            }
+            if state.remote_closed || state.closed {
+                return Ok(0);
+            }
+            if let Some(kind) = state.error {
+                return Err(io::Error::new(kind, "UDP stream failed"));
+            }
+            state = self.shared.cond.wait(state).unwrap();
+        }
+    }
+}
+
+impl Write for UdpStream {
+    /// Blocks (up to the write timeout) while too many packets are waiting to be acknowledged.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let started = Instant::now();
+        let mut state = self.shared.lock();
+        loop {
+            if state.closed {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "UDP stream closed"));
+            }
+            if let Some(kind) = state.error {
+                return Err(io::Error::new(kind, "UDP stream failed"));
+            }
+            if (state.unacked.len() as u64) < WINDOW {
+                break;
+            }
+            let wait = Duration::from_millis(RETRANSMIT_MS);
+            if let Some(timeout) = state.write_timeout {
+                if started.elapsed() >= timeout {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "UDP stream write timed out"));
+                }
+            }
+            state = self.shared.cond.wait_timeout(state, wait).unwrap().0;
+        }
+        let len = cmp::min(buf.len(), MAX_SEGMENT);
+        self.shared.send_segment(&mut state, KIND_DATA, &buf[0..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_udp_stream() {
+    use std::net::UdpSocket;
+
+    let sock_a = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let sock_b = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr_a = sock_a.local_addr().unwrap();
+    let addr_b = sock_b.local_addr().unwrap();
+    let mut a = UdpStream::new(Arc::new(sock_a), addr_b);
+    let mut b = UdpStream::new(Arc::new(sock_b), addr_a);
+    assert_eq!(a.peer_addr(), addr_b);
+
+    // More than a window's worth, in both directions at once
+    let data: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+    let expected = data.clone();
+    let mut b_reader = b.clone();
+    let reader = thread::spawn(move || {
+        let mut got = vec![];
+        b_reader.read_to_end(&mut got).unwrap();
+        got
+    });
+    a.write_all(&data).unwrap();
+    b.write_all(b"pong").unwrap();
+    let mut pong = [0; 4];
+    a.read_exact(&mut pong).unwrap();
+    assert_eq!(&pong, b"pong");
+    a.shutdown();
+    assert_eq!(reader.join().unwrap(), expected);
+    assert!(a.write(b"more").is_err());
+}